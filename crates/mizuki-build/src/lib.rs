@@ -1,4 +1,10 @@
-const COMMANDS: &[&str] = &["graphql", "subscriptions"];
+mod permissions;
+mod trusted_documents;
+
+pub use permissions::OperationPermissions;
+pub use trusted_documents::{Error, TrustedDocuments};
+
+const COMMANDS: &[&str] = &["graphql", "subscriptions", "transport"];
 
 pub fn build() {
   tauri_plugin::Builder::new(COMMANDS).build();
@@ -0,0 +1,396 @@
+use std::{collections::BTreeSet, path::PathBuf};
+
+use async_graphql_parser::{
+  parse_schema,
+  types::{OperationType, TypeKind, TypeSystemDefinition},
+};
+
+use toml::{Table, Value};
+
+use crate::{Error, COMMANDS};
+
+/// Where the permissions are written, next to the ones tauri generates for the commands.
+const PERMISSIONS_FILE: &str = "permissions/autogenerated/operations.toml";
+
+/// Generates a permission per root field of the schema, so capabilities can grant
+/// the operations one by one.
+///
+/// For a `sendMessage` mutation of the `chat` plugin, a capability gets the
+/// `chat:allow-mutation-send-message` and `chat:deny-mutation-send-message` permissions,
+/// along with `chat:allow-queries`, `chat:allow-mutations` and `chat:allow-subscriptions`
+/// granting all the root fields of an operation type.
+/// They are only enforced by the plugins built with `mizuki::Builder::field_permissions`.
+///
+/// The capabilities checked by the `mizuki::guard` are granted the same way,
+/// `chat:grant-admin` granting the `admin` capability.
+///
+/// The root fields are read from the SDL of the schema, or declared one by one,
+/// then the permissions are written in `permissions/autogenerated/operations.toml`,
+/// before [`crate::build`] picks them up:
+///
+/// ```rust,no_run
+/// // build.rs
+/// mizuki_build::OperationPermissions::new()
+///   .schema("schema.graphql")
+///   .generate()
+///   .expect("Failed to generate the operation permissions");
+/// mizuki_build::build();
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct OperationPermissions {
+  schemas: Vec<PathBuf>,
+  fields: BTreeSet<(Operation, String)>,
+  capabilities: BTreeSet<String>,
+}
+
+/// [`OperationType`] isn't ordered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Operation {
+  Query,
+  Mutation,
+  Subscription,
+}
+
+impl From<OperationType> for Operation {
+  fn from(ty: OperationType) -> Self {
+    match ty {
+      OperationType::Query => Self::Query,
+      OperationType::Mutation => Self::Mutation,
+      OperationType::Subscription => Self::Subscription,
+    }
+  }
+}
+
+impl Operation {
+  const ALL: [Self; 3] = [Self::Query, Self::Mutation, Self::Subscription];
+
+  fn name(self) -> &'static str {
+    match self {
+      Self::Query => "query",
+      Self::Mutation => "mutation",
+      Self::Subscription => "subscription",
+    }
+  }
+
+  fn plural(self) -> &'static str {
+    match self {
+      Self::Query => "queries",
+      Self::Mutation => "mutations",
+      Self::Subscription => "subscriptions",
+    }
+  }
+}
+
+impl OperationPermissions {
+  pub fn new() -> Self {
+    Self::default()
+  }
+  /// Add the root fields of a schema, in SDL like the one of `Schema::sdl`.
+  ///
+  /// Relative paths are relative to the plugin crate.
+  #[must_use]
+  pub fn schema(mut self, path: impl Into<PathBuf>) -> Self {
+    self.schemas.push(path.into());
+    self
+  }
+  /// Add a root field of an operation type.
+  #[must_use]
+  pub fn root_field(mut self, ty: OperationType, field: impl Into<String>) -> Self {
+    self.fields.insert((ty.into(), field.into()));
+    self
+  }
+  /// Add a capability checked by the `mizuki::guard::RequiresCapability` guards.
+  #[must_use]
+  pub fn capability(mut self, name: impl Into<String>) -> Self {
+    self.capabilities.insert(name.into());
+    self
+  }
+  /// The permissions, as a tauri permission file.
+  pub fn permissions(&self) -> Result<String, Error> {
+    let mut fields = self.fields.clone();
+    for path in &self.schemas {
+      let source = std::fs::read_to_string(path).map_err(|source| Error::Io {
+        path: path.clone(),
+        source,
+      })?;
+      fields.extend(root_fields(&source).map_err(|source| Error::Parse {
+        path: path.clone(),
+        source,
+      })?);
+    }
+
+    let mut permissions = Vec::new();
+    for operation in Operation::ALL {
+      permissions.push(permission(
+        &format!("allow-{}", operation.plural()),
+        &format!("Enables all the {}.", operation.plural()),
+        "allow",
+        &[("operation", operation.name()), ("field", "*")],
+      ));
+    }
+    for (operation, field) in &fields {
+      for action in ["allow", "deny"] {
+        let verb = if action == "allow" {
+          "Enables"
+        } else {
+          "Denies"
+        };
+        permissions.push(permission(
+          &format!("{}-{}-{}", action, operation.name(), kebab_case(field)),
+          &format!(
+            "{} the {} root field of the {} type.",
+            verb,
+            field,
+            operation.name()
+          ),
+          action,
+          &[("operation", operation.name()), ("field", field)],
+        ));
+      }
+    }
+    for capability in &self.capabilities {
+      permissions.push(permission(
+        &format!("grant-{}", kebab_case(capability)),
+        &format!("Grants the {} capability.", capability),
+        "allow",
+        &[("capability", capability)],
+      ));
+    }
+    let mut file = Table::new();
+    file.insert("permission".into(), Value::Array(permissions));
+    Ok(format!(
+      "# Automatically generated - DO NOT EDIT!\n\n{}",
+      toml::to_string(&file).expect("a table is valid TOML")
+    ))
+  }
+  /// Write the permissions in the `permissions` directory of the plugin crate.
+  ///
+  /// Cargo is told to run the build script again when the schemas change.
+  pub fn generate(&self) -> Result<PathBuf, Error> {
+    for path in &self.schemas {
+      println!("cargo:rerun-if-changed={}", path.display());
+    }
+    let toml = self.permissions()?;
+    let path = PathBuf::from(PERMISSIONS_FILE);
+    let io_error = |source| Error::Io {
+      path: path.clone(),
+      source,
+    };
+    if let Some(parent) = path.parent() {
+      std::fs::create_dir_all(parent).map_err(io_error)?;
+    }
+    // Rewriting an unchanged file would have cargo run the build script again.
+    if std::fs::read_to_string(&path).ok().as_deref() != Some(toml.as_str()) {
+      std::fs::write(&path, toml).map_err(io_error)?;
+    }
+    Ok(path)
+  }
+}
+
+/// A permission with a single scope, on the commands of the plugin.
+///
+/// The deny permissions enable the commands too: the scope of a permission enabling
+/// no command would be global, and deny the field to the windows of every capability.
+/// A capability holding nothing but deny permissions is still allowed no root field.
+fn permission(identifier: &str, description: &str, action: &str, scope: &[(&str, &str)]) -> Value {
+  let mut permission = Table::new();
+  permission.insert("identifier".into(), identifier.into());
+  permission.insert("description".into(), description.into());
+  let mut commands = Table::new();
+  commands.insert("allow".into(), COMMANDS.to_vec().into());
+  permission.insert("commands".into(), commands.into());
+  let scope: Table = scope
+    .iter()
+    .map(|(key, value)| (key.to_string(), Value::from(*value)))
+    .collect();
+  let mut scopes = Table::new();
+  scopes.insert(action.into(), Value::Array(vec![scope.into()]));
+  permission.insert("scope".into(), scopes.into());
+  permission.into()
+}
+
+/// The root fields of the operation types of a schema.
+fn root_fields(source: &str) -> async_graphql_parser::Result<Vec<(Operation, String)>> {
+  let document = parse_schema(source)?;
+  let mut roots = [
+    (Operation::Query, "Query".to_string()),
+    (Operation::Mutation, "Mutation".to_string()),
+    (Operation::Subscription, "Subscription".to_string()),
+  ];
+  for definition in &document.definitions {
+    if let TypeSystemDefinition::Schema(schema) = definition {
+      let schema = &schema.node;
+      for (root, name) in
+        roots
+          .iter_mut()
+          .zip([&schema.query, &schema.mutation, &schema.subscription])
+      {
+        if let Some(name) = name {
+          root.1 = name.node.to_string();
+        }
+      }
+    }
+  }
+
+  let mut fields = Vec::new();
+  for definition in &document.definitions {
+    let TypeSystemDefinition::Type(ty) = definition else {
+      continue;
+    };
+    let TypeKind::Object(object) = &ty.node.kind else {
+      continue;
+    };
+    let Some((operation, _)) = roots.iter().find(|(_, name)| *name == ty.node.name.node) else {
+      continue;
+    };
+    fields.extend(
+      object
+        .fields
+        .iter()
+        .map(|field| (*operation, field.node.name.node.to_string())),
+    );
+  }
+  Ok(fields)
+}
+
+/// `sendMessage` and `send_message` both become `send-message`, and `sendHTTPRequest`, `send-http-request`.
+fn kebab_case(name: &str) -> String {
+  let chars: Vec<char> = name.chars().collect();
+  let mut kebab = String::new();
+  for (i, &c) in chars.iter().enumerate() {
+    let previous = i.checked_sub(1).map(|i| chars[i]);
+    let next = chars.get(i + 1);
+    // A word starts after a lowercase letter, or at the last capital of an acronym.
+    let starts_word = c.is_ascii_uppercase()
+      && previous.is_some_and(|previous| {
+        previous.is_ascii_lowercase()
+          || previous.is_ascii_digit()
+          || (previous.is_ascii_uppercase() && next.is_some_and(char::is_ascii_lowercase))
+      });
+    if (starts_word || c == '_') && !kebab.is_empty() && !kebab.ends_with('-') {
+      kebab.push('-');
+    }
+    if c != '_' {
+      kebab.push(c.to_ascii_lowercase());
+    }
+  }
+  kebab
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::collections::BTreeMap;
+  use tauri_utils::{
+    acl::{
+      capability::Capability,
+      manifest::{Manifest, PermissionFile},
+      resolved::Resolved,
+    },
+    platform::Target,
+  };
+
+  fn permissions(builder: OperationPermissions) -> Vec<Value> {
+    let file: Table = builder.permissions().unwrap().parse().unwrap();
+    file["permission"].as_array().unwrap().clone()
+  }
+
+  fn find<'a>(permissions: &'a [Value], identifier: &str) -> &'a Value {
+    permissions
+      .iter()
+      .find(|permission| permission["identifier"].as_str() == Some(identifier))
+      .unwrap()
+  }
+
+  #[test]
+  fn kebab_case_splits_words() {
+    assert_eq!(kebab_case("sendMessage"), "send-message");
+    assert_eq!(kebab_case("send_message"), "send-message");
+    assert_eq!(kebab_case("sendHTTPRequest"), "send-http-request");
+    assert_eq!(kebab_case("HTTPRequest"), "http-request");
+    assert_eq!(kebab_case("user2Name"), "user2-name");
+    assert_eq!(kebab_case("_private__field"), "private-field");
+    assert_eq!(kebab_case("list"), "list");
+  }
+
+  #[test]
+  fn allow_permissions_enable_the_commands() {
+    let permissions =
+      permissions(OperationPermissions::new().root_field(OperationType::Mutation, "sendMessage"));
+    let allow = find(&permissions, "allow-mutation-send-message");
+    assert_eq!(
+      allow["commands"]["allow"],
+      Value::from(vec!["graphql", "subscriptions", "transport"])
+    );
+    let scope = &allow["scope"]["allow"][0];
+    assert_eq!(scope["operation"].as_str(), Some("mutation"));
+    assert_eq!(scope["field"].as_str(), Some("sendMessage"));
+    assert_eq!(
+      find(&permissions, "allow-mutations")["scope"]["allow"][0]["field"].as_str(),
+      Some("*")
+    );
+  }
+
+  #[test]
+  fn deny_permissions_are_bound_to_their_capability() {
+    let file = OperationPermissions::new()
+      .root_field(OperationType::Query, "secret")
+      .permissions()
+      .unwrap();
+    let manifest = Manifest::new(vec![toml::from_str::<PermissionFile>(&file).unwrap()], None);
+    let acl = BTreeMap::from([("chat".to_string(), manifest)]);
+    let capability = |window: &str, permissions: &[&str]| {
+      let capability: Capability = serde_json::from_value(serde_json::json!({
+        "identifier": window,
+        "windows": [window],
+        "permissions": permissions,
+      }))
+      .unwrap();
+      (window.to_string(), capability)
+    };
+    let capabilities = BTreeMap::from([
+      capability("main", &["chat:allow-queries", "chat:deny-query-secret"]),
+      capability("settings", &["chat:allow-queries"]),
+    ]);
+    let resolved = Resolved::resolve(&acl, capabilities, Target::current()).unwrap();
+
+    assert!(!resolved.global_scope.contains_key("chat"));
+    for command in COMMANDS {
+      let denied_to = resolved.allowed_commands[&format!("plugin:chat|{command}")]
+        .iter()
+        .filter(|command| {
+          let scope = &resolved.command_scope[&command.scope_id.unwrap()];
+          !scope.deny.is_empty()
+        })
+        .flat_map(|command| command.windows.iter().map(|window| window.as_str()))
+        .collect::<Vec<_>>();
+      assert_eq!(denied_to, ["main"]);
+    }
+  }
+
+  #[test]
+  fn strings_are_escaped() {
+    let permissions = permissions(OperationPermissions::new().capability("a\"b\\c"));
+    let grant = find(&permissions, "grant-a\"b\\c");
+    assert_eq!(
+      grant["scope"]["allow"][0]["capability"].as_str(),
+      Some("a\"b\\c")
+    );
+  }
+
+  #[test]
+  fn root_fields_follow_the_schema_definition() {
+    let fields = root_fields(
+      "schema { query: Root } type Root { a: Int b: Int } type Query { c: Int } type Mutation { d: Int }",
+    )
+    .unwrap();
+    assert_eq!(
+      fields,
+      vec![
+        (Operation::Query, "a".to_string()),
+        (Operation::Query, "b".to_string()),
+        (Operation::Mutation, "d".to_string()),
+      ]
+    );
+  }
+}
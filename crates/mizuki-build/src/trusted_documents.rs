@@ -0,0 +1,423 @@
+use std::{
+  collections::HashMap,
+  io,
+  path::{Path, PathBuf},
+};
+
+use async_graphql_parser::{
+  parse_query,
+  types::{DocumentOperations, FragmentDefinition, OperationDefinition},
+  Positioned,
+};
+use async_graphql_value::Name;
+
+use mizuki_documents::{hash_operation, Manifest, MANIFEST_FILE_NAME};
+
+/// The extensions of the files holding nothing but GraphQL.
+const GRAPHQL_EXTENSIONS: &[&str] = &["graphql", "gql"];
+/// The extensions of the files whose `gql` and `graphql` tagged templates are scanned.
+const SCRIPT_EXTENSIONS: &[&str] = &[
+  "ts", "tsx", "mts", "cts", "js", "jsx", "mjs", "cjs", "svelte", "vue",
+];
+/// The directories that are never scanned.
+const IGNORED_DIRECTORIES: &[&str] = &["node_modules", "target", "dist", "build"];
+/// Stands in for the operation of the documents that only define fragments.
+const PLACEHOLDER_OPERATION: &str = "__MizukiFragmentsOnly";
+
+/// Errors that can happen while generating the trusted documents manifest
+/// or the operation permissions.
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum Error {
+  #[error("{}: {source}", path.display())]
+  Io { path: PathBuf, source: io::Error },
+  #[error("{}: {source}", path.display())]
+  Parse {
+    path: PathBuf,
+    source: async_graphql_parser::Error,
+  },
+  /// An operation uses a fragment that none of the documents defines.
+  #[error("{}: operation {operation} uses an unknown fragment", path.display())]
+  UnknownFragment { path: PathBuf, operation: String },
+  #[error("OUT_DIR is not set, the manifest must be generated from a build script")]
+  MissingOutDir,
+}
+
+/// Generates the manifest of the operations the frontend is allowed to send.
+///
+/// The given paths are scanned for `.graphql` files and for the `gql` and
+/// `graphql` tagged templates of scripts and components.
+/// The manifest is written in `OUT_DIR`, to be loaded by the plugin with
+/// `mizuki::include_trusted_documents!`:
+///
+/// ```rust,no_run
+/// // build.rs
+/// mizuki_build::TrustedDocuments::new()
+///   .path("../src")
+///   .generate()
+///   .expect("Failed to generate the trusted documents");
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct TrustedDocuments {
+  paths: Vec<PathBuf>,
+}
+
+impl TrustedDocuments {
+  pub fn new() -> Self {
+    Self::default()
+  }
+  /// Add a file, or a directory to scan recursively.
+  ///
+  /// Relative paths are relative to the plugin crate.
+  #[must_use]
+  pub fn path(mut self, path: impl Into<PathBuf>) -> Self {
+    self.paths.push(path.into());
+    self
+  }
+  /// Scan the paths.
+  pub fn manifest(&self) -> Result<Manifest, Error> {
+    let mut scan = Scan::default();
+    for path in &self.paths {
+      scan.path(path)?;
+    }
+    scan.manifest()
+  }
+  /// Scan the paths and write the manifest in `OUT_DIR`.
+  ///
+  /// Cargo is told to run the build script again when the paths change.
+  pub fn generate(&self) -> Result<PathBuf, Error> {
+    let out_dir = std::env::var_os("OUT_DIR").ok_or(Error::MissingOutDir)?;
+    for path in &self.paths {
+      println!("cargo:rerun-if-changed={}", path.display());
+    }
+    let manifest = self.manifest()?;
+    let path = Path::new(&out_dir).join(MANIFEST_FILE_NAME);
+    let json = serde_json::to_string_pretty(&manifest).expect("the manifest is serializable");
+    std::fs::write(&path, json).map_err(|source| Error::Io {
+      path: path.clone(),
+      source,
+    })?;
+    Ok(path)
+  }
+}
+
+#[derive(Default)]
+struct Scan {
+  operations: Vec<(PathBuf, Option<Name>, OperationDefinition)>,
+  fragments: HashMap<Name, Positioned<FragmentDefinition>>,
+}
+
+impl Scan {
+  fn path(&mut self, path: &Path) -> Result<(), Error> {
+    let io_error = |source| Error::Io {
+      path: path.into(),
+      source,
+    };
+    if path.is_dir() {
+      let mut entries = std::fs::read_dir(path)
+        .and_then(|entries| {
+          entries
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<Vec<_>, _>>()
+        })
+        .map_err(io_error)?;
+      entries.sort();
+      for entry in entries {
+        let name = entry.file_name().unwrap_or_default().to_string_lossy();
+        if name.starts_with('.') || (entry.is_dir() && IGNORED_DIRECTORIES.contains(&&*name)) {
+          continue;
+        }
+        self.path(&entry)?;
+      }
+      return Ok(());
+    }
+    let extension = path
+      .extension()
+      .unwrap_or_default()
+      .to_string_lossy()
+      .to_ascii_lowercase();
+    if GRAPHQL_EXTENSIONS.contains(&extension.as_str()) {
+      let source = std::fs::read_to_string(path).map_err(io_error)?;
+      self.document(path, &source)?;
+    } else if SCRIPT_EXTENSIONS.contains(&extension.as_str()) {
+      let source = std::fs::read_to_string(path).map_err(io_error)?;
+      for document in tagged_templates(&source) {
+        // The scan can be fooled, by a backtick in a regular expression for instance.
+        match self.document(path, &document) {
+          Err(error @ Error::Parse { .. }) => {
+            println!("cargo:warning=skipped a GraphQL template: {}", error)
+          }
+          res => res?,
+        }
+      }
+    }
+    Ok(())
+  }
+
+  fn document(&mut self, path: &Path, source: &str) -> Result<(), Error> {
+    let parse_error = |source| Error::Parse {
+      path: path.into(),
+      source,
+    };
+    let document = match parse_query(source) {
+      Err(async_graphql_parser::Error::MissingOperation) => parse_query(format!(
+        "{}\nquery {} {{ __typename }}",
+        source, PLACEHOLDER_OPERATION
+      ))
+      .map_err(parse_error)?,
+      document => document.map_err(parse_error)?,
+    };
+    match document.operations {
+      DocumentOperations::Single(operation) => {
+        self.operations.push((path.into(), None, operation.node))
+      }
+      DocumentOperations::Multiple(operations) => {
+        for (name, operation) in operations {
+          if name != PLACEHOLDER_OPERATION {
+            self
+              .operations
+              .push((path.into(), Some(name), operation.node));
+          }
+        }
+      }
+    }
+    for (name, fragment) in document.fragments {
+      self.fragments.entry(name).or_insert(fragment);
+    }
+    Ok(())
+  }
+
+  fn manifest(self) -> Result<Manifest, Error> {
+    let mut manifest = Manifest::default();
+    for (path, name, operation) in self.operations {
+      let hash = hash_operation(name.as_ref(), &operation, &self.fragments).ok_or_else(|| {
+        Error::UnknownFragment {
+          path,
+          operation: name
+            .as_ref()
+            .map(ToString::to_string)
+            .unwrap_or_else(|| "<anonymous>".into()),
+        }
+      })?;
+      manifest
+        .operations
+        .insert(hash, name.map(|name| name.to_string()));
+    }
+    Ok(manifest)
+  }
+}
+
+/// The contents of the `gql` and `graphql` tagged templates of a script.
+///
+/// Comments and string literals are skipped, so the backticks they hold don't
+/// start a template, but the regular expression literals aren't told apart.
+/// Interpolations are left out, the fragments they embed are expected to be
+/// defined somewhere in the scanned paths.
+fn tagged_templates(source: &str) -> Vec<String> {
+  let bytes = source.as_bytes();
+  let mut templates = Vec::new();
+  // The identifier right before, and whether a `(` follows it.
+  let mut tag: Option<(&str, bool)> = None;
+  let mut i = 0;
+  while i < bytes.len() {
+    match bytes[i] {
+      b'/' if bytes.get(i + 1) == Some(&b'/') => {
+        i = skip_line_comment(bytes, i);
+        continue;
+      }
+      b'/' if bytes.get(i + 1) == Some(&b'*') => {
+        i = skip_block_comment(bytes, i);
+        continue;
+      }
+      b'\'' | b'"' => {
+        i = skip_quoted(bytes, i);
+        tag = None;
+        continue;
+      }
+      b'`' => {
+        let (template, end) = template(source, i + 1);
+        if matches!(tag, Some(("gql" | "graphql", _))) {
+          templates.push(template);
+        }
+        i = end;
+        tag = None;
+        continue;
+      }
+      c if c == b'_' || c == b'$' || c.is_ascii_alphabetic() => {
+        let start = i;
+        while i < bytes.len() && is_identifier(bytes[i]) {
+          i += 1;
+        }
+        tag = Some((&source[start..i], false));
+        continue;
+      }
+      b'(' => {
+        tag = tag
+          .filter(|(_, paren)| !paren)
+          .map(|(name, _)| (name, true))
+      }
+      c if c.is_ascii_whitespace() => {}
+      _ => tag = None,
+    }
+    i += 1;
+  }
+  templates
+}
+
+/// The text of the template starting at `start`, without its interpolations,
+/// and the index after it.
+fn template(source: &str, start: usize) -> (String, usize) {
+  let bytes = source.as_bytes();
+  let mut template = String::new();
+  let mut i = start;
+  let mut text = start;
+  while i < bytes.len() {
+    match bytes[i] {
+      b'\\' => {
+        template.push_str(&source[text..i]);
+        text = (i + 1).min(bytes.len());
+        i += 2;
+      }
+      b'`' => {
+        template.push_str(&source[text..i]);
+        return (template, i + 1);
+      }
+      b'$' if bytes.get(i + 1) == Some(&b'{') => {
+        template.push_str(&source[text..i]);
+        i = skip_interpolation(source, i + 2);
+        text = i.min(bytes.len());
+      }
+      _ => i += 1,
+    }
+  }
+  template.push_str(&source[text.min(bytes.len())..]);
+  (template, bytes.len())
+}
+
+/// The index after the interpolation whose expression starts at `start`.
+fn skip_interpolation(source: &str, start: usize) -> usize {
+  let bytes = source.as_bytes();
+  let mut depth = 1;
+  let mut i = start;
+  while i < bytes.len() {
+    match bytes[i] {
+      b'/' if bytes.get(i + 1) == Some(&b'/') => i = skip_line_comment(bytes, i),
+      b'/' if bytes.get(i + 1) == Some(&b'*') => i = skip_block_comment(bytes, i),
+      b'\'' | b'"' => i = skip_quoted(bytes, i),
+      b'`' => i = template(source, i + 1).1,
+      b'{' => {
+        depth += 1;
+        i += 1;
+      }
+      b'}' => {
+        depth -= 1;
+        i += 1;
+        if depth == 0 {
+          return i;
+        }
+      }
+      _ => i += 1,
+    }
+  }
+  i
+}
+
+fn is_identifier(c: u8) -> bool {
+  c == b'_' || c == b'$' || c.is_ascii_alphanumeric()
+}
+
+fn skip_line_comment(bytes: &[u8], start: usize) -> usize {
+  bytes[start..]
+    .iter()
+    .position(|&c| c == b'\n')
+    .map_or(bytes.len(), |end| start + end)
+}
+
+fn skip_block_comment(bytes: &[u8], start: usize) -> usize {
+  bytes[start + 2..]
+    .windows(2)
+    .position(|end| end == b"*/")
+    .map_or(bytes.len(), |end| start + 2 + end + 2)
+}
+
+/// The index after the string literal starting at `start`.
+///
+/// A string literal can't span lines, so a quote that isn't one, like the
+/// apostrophe of the markup of a component, only hides the rest of its line.
+fn skip_quoted(bytes: &[u8], start: usize) -> usize {
+  let quote = bytes[start];
+  let mut i = start + 1;
+  while i < bytes.len() {
+    match bytes[i] {
+      b'\\' => i += 2,
+      b'\n' => return i,
+      c if c == quote => return i + 1,
+      _ => i += 1,
+    }
+  }
+  bytes.len()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn tagged_templates_are_found() {
+    let source = r#"
+      const a = gql`query A { a }`
+      const b = graphql(`query B { b }`)
+      const c = sql`select 1`
+      const d = mygql`query D { d }`
+    "#;
+    assert_eq!(tagged_templates(source), ["query A { a }", "query B { b }"]);
+  }
+
+  #[test]
+  fn comments_and_strings_are_skipped() {
+    let source = r#"
+      // a comment with a ` backtick
+      /* and another ` one */
+      const quote = "`"
+      const apostrophe = '`'
+      const a = gql`query A { a }`
+      <p>Don't</p>
+      const b = gql`query B { b }`
+    "#;
+    assert_eq!(tagged_templates(source), ["query A { a }", "query B { b }"]);
+  }
+
+  #[test]
+  fn interpolations_are_left_out() {
+    let source = r#"
+      const a = gql`
+        query A { ...F }
+        ${fragment}
+        ${format(`nested ${"}"}`)}
+      `
+      const b = gql`query B { b(text: "\`") }`
+    "#;
+    let templates = tagged_templates(source);
+    assert_eq!(templates.len(), 2);
+    assert_eq!(
+      templates[0].split_whitespace().collect::<Vec<_>>(),
+      ["query", "A", "{", "...F", "}"]
+    );
+    assert_eq!(templates[1], "query B { b(text: \"`\") }");
+  }
+
+  #[test]
+  fn unparsable_templates_are_skipped() {
+    let dir = std::env::temp_dir().join(format!("mizuki-build-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(
+      dir.join("app.ts"),
+      "const bad = gql`query {`\nconst good = gql`query Good { a }`\n",
+    )
+    .unwrap();
+    let manifest = TrustedDocuments::new().path(&dir).manifest();
+    std::fs::remove_dir_all(&dir).unwrap();
+    let operations: Vec<_> = manifest.unwrap().operations.into_values().collect();
+    assert_eq!(operations, [Some("Good".to_string())]);
+  }
+}
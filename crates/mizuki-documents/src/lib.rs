@@ -0,0 +1,309 @@
+//! Hashes of the GraphQL operations, shared by the build script and the plugin.
+//!
+//! An operation is hashed along with the fragments it uses, once printed in a
+//! canonical form: formatting, commas and the order of the fragments don't
+//! change its hash, and neither do the `__typename` fields added by the GraphQL
+//! clients.
+
+use std::{
+  collections::{BTreeMap, BTreeSet, HashMap},
+  fmt::{self, Write},
+};
+
+use async_graphql_parser::{
+  types::{
+    Directive, DocumentOperations, ExecutableDocument, FragmentDefinition, OperationDefinition,
+    Selection, SelectionSet, VariableDefinition,
+  },
+  Positioned,
+};
+use async_graphql_value::Name;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// The file name of the manifest written in `OUT_DIR`, as a literal for `concat!`.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! manifest_file_name {
+  () => {
+    "mizuki-trusted-documents.json"
+  };
+}
+
+/// The file name of the manifest written in `OUT_DIR`.
+pub const MANIFEST_FILE_NAME: &str = manifest_file_name!();
+
+/// The trusted operations of a plugin.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+  /// The operation names, keyed by operation hash.
+  ///
+  /// Anonymous operations have no name.
+  pub operations: BTreeMap<String, Option<String>>,
+}
+
+/// The hash of the operation `operation_name` of a document.
+///
+/// Returns `None` if the document doesn't have this operation,
+/// or uses a fragment it doesn't define.
+pub fn operation_hash(
+  document: &ExecutableDocument,
+  operation_name: Option<&str>,
+) -> Option<String> {
+  let (name, operation) = match (&document.operations, operation_name) {
+    (DocumentOperations::Single(operation), None) => (None, operation),
+    (DocumentOperations::Multiple(operations), Some(name)) => {
+      let (name, operation) = operations.get_key_value(name)?;
+      (Some(name), operation)
+    }
+    (DocumentOperations::Multiple(operations), None) if operations.len() == 1 => {
+      let (name, operation) = operations.iter().next()?;
+      (Some(name), operation)
+    }
+    _ => return None,
+  };
+  hash_operation(name, &operation.node, &document.fragments)
+}
+
+/// The hash of an operation, using the fragments it needs from `fragments`.
+pub fn hash_operation(
+  name: Option<&Name>,
+  operation: &OperationDefinition,
+  fragments: &HashMap<Name, Positioned<FragmentDefinition>>,
+) -> Option<String> {
+  let mut used = BTreeSet::new();
+  collect_fragments(&operation.selection_set.node, fragments, &mut used)?;
+
+  let mut printer = Printer(String::new());
+  printer.operation(name, operation).ok()?;
+  for name in used {
+    printer.fragment(&name, &fragments[&name].node).ok()?;
+  }
+  Some(format!("{:x}", Sha256::digest(printer.0.as_bytes())))
+}
+
+fn collect_fragments(
+  selection_set: &SelectionSet,
+  fragments: &HashMap<Name, Positioned<FragmentDefinition>>,
+  used: &mut BTreeSet<Name>,
+) -> Option<()> {
+  for item in &selection_set.items {
+    match &item.node {
+      Selection::Field(field) => {
+        collect_fragments(&field.node.selection_set.node, fragments, used)?
+      }
+      Selection::InlineFragment(fragment) => {
+        collect_fragments(&fragment.node.selection_set.node, fragments, used)?
+      }
+      Selection::FragmentSpread(spread) => {
+        let name = &spread.node.fragment_name.node;
+        let fragment = fragments.get(name)?;
+        if used.insert(name.clone()) {
+          collect_fragments(&fragment.node.selection_set.node, fragments, used)?;
+        }
+      }
+    }
+  }
+  Some(())
+}
+
+/// Prints a document on a single line, with single spaces between tokens.
+struct Printer(String);
+
+impl Printer {
+  fn operation(&mut self, name: Option<&Name>, operation: &OperationDefinition) -> fmt::Result {
+    write!(self.0, "{}", operation.ty)?;
+    if let Some(name) = name {
+      write!(self.0, " {}", name)?;
+    }
+    if !operation.variable_definitions.is_empty() {
+      self.0.push_str(" (");
+      for (i, definition) in operation.variable_definitions.iter().enumerate() {
+        if i > 0 {
+          self.0.push_str(", ");
+        }
+        self.variable_definition(&definition.node)?;
+      }
+      self.0.push(')');
+    }
+    self.directives(&operation.directives)?;
+    self.selection_set(&operation.selection_set.node)?;
+    self.0.push('\n');
+    Ok(())
+  }
+
+  fn fragment(&mut self, name: &Name, fragment: &FragmentDefinition) -> fmt::Result {
+    write!(
+      self.0,
+      "fragment {} on {}",
+      name, fragment.type_condition.node.on.node
+    )?;
+    self.directives(&fragment.directives)?;
+    self.selection_set(&fragment.selection_set.node)?;
+    self.0.push('\n');
+    Ok(())
+  }
+
+  fn variable_definition(&mut self, definition: &VariableDefinition) -> fmt::Result {
+    write!(
+      self.0,
+      "${}: {}",
+      definition.name.node, definition.var_type.node
+    )?;
+    if let Some(default) = &definition.default_value {
+      write!(self.0, " = {}", default.node)?;
+    }
+    self.directives(&definition.directives)
+  }
+
+  fn directives(&mut self, directives: &[Positioned<Directive>]) -> fmt::Result {
+    for directive in directives {
+      write!(self.0, " @{}", directive.node.name.node)?;
+      self.arguments(
+        directive
+          .node
+          .arguments
+          .iter()
+          .map(|(name, value)| (&name.node, value.node.to_string())),
+      )?;
+    }
+    Ok(())
+  }
+
+  fn arguments<'a>(&mut self, arguments: impl Iterator<Item = (&'a Name, String)>) -> fmt::Result {
+    let mut arguments = arguments.peekable();
+    if arguments.peek().is_none() {
+      return Ok(());
+    }
+    self.0.push('(');
+    for (i, (name, value)) in arguments.enumerate() {
+      if i > 0 {
+        self.0.push_str(", ");
+      }
+      write!(self.0, "{}: {}", name, value)?;
+    }
+    self.0.push(')');
+    Ok(())
+  }
+
+  fn selection_set(&mut self, selection_set: &SelectionSet) -> fmt::Result {
+    let items: Vec<_> = selection_set
+      .items
+      .iter()
+      .filter(|item| !is_added_typename(&item.node))
+      .collect();
+    if items.is_empty() {
+      return Ok(());
+    }
+    self.0.push_str(" {");
+    for item in items {
+      self.0.push(' ');
+      match &item.node {
+        Selection::Field(field) => {
+          let field = &field.node;
+          if let Some(alias) = &field.alias {
+            write!(self.0, "{}: ", alias.node)?;
+          }
+          write!(self.0, "{}", field.name.node)?;
+          self.arguments(
+            field
+              .arguments
+              .iter()
+              .map(|(name, value)| (&name.node, value.node.to_string())),
+          )?;
+          self.directives(&field.directives)?;
+          self.selection_set(&field.selection_set.node)?;
+        }
+        Selection::FragmentSpread(spread) => {
+          write!(self.0, "...{}", spread.node.fragment_name.node)?;
+          self.directives(&spread.node.directives)?;
+        }
+        Selection::InlineFragment(fragment) => {
+          self.0.push_str("...");
+          if let Some(condition) = &fragment.node.type_condition {
+            write!(self.0, " on {}", condition.node.on.node)?;
+          }
+          self.directives(&fragment.node.directives)?;
+          self.selection_set(&fragment.node.selection_set.node)?;
+        }
+      }
+    }
+    self.0.push_str(" }");
+    Ok(())
+  }
+}
+
+/// A plain `__typename` field, as the clients add them to every selection set.
+fn is_added_typename(selection: &Selection) -> bool {
+  matches!(
+    selection,
+    Selection::Field(field)
+      if field.node.name.node == "__typename"
+        && field.node.alias.is_none()
+        && field.node.directives.is_empty()
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use async_graphql_parser::parse_query;
+
+  use super::*;
+
+  fn hash(query: &str, operation_name: Option<&str>) -> Option<String> {
+    operation_hash(&parse_query(query).unwrap(), operation_name)
+  }
+
+  #[test]
+  fn formatting_doesnt_change_the_hash() {
+    let compact = hash("query Q($id: ID!) { user(id: $id) { name, email } }", None);
+    let spread = hash(
+      "query Q(\n  $id: ID!\n) {\n  user(id: $id) {\n    name\n    email\n    __typename\n  }\n}",
+      None,
+    );
+    assert!(compact.is_some());
+    assert_eq!(compact, spread);
+    assert_ne!(
+      compact,
+      hash("query Q($id: ID!) { user(id: $id) { name } }", None)
+    );
+  }
+
+  #[test]
+  fn fragments_are_hashed_with_the_operation() {
+    let query = "query Q { user { ...F } } fragment F on User { name }";
+    let reordered = "fragment F on User { name } query Q { user { ...F } }";
+    let changed = "query Q { user { ...F } } fragment F on User { email }";
+    let unused = "query Q { user { ...F } } fragment F on User { name } fragment G on User { id }";
+    assert_eq!(hash(query, None), hash(reordered, None));
+    assert_eq!(hash(query, None), hash(unused, None));
+    assert_ne!(hash(query, None), hash(changed, None));
+    assert_eq!(hash("query Q { user { ...F } }", None), None);
+  }
+
+  #[test]
+  fn operations_are_picked_by_name() {
+    let query = "query A { a } query B { b }";
+    assert_eq!(hash(query, Some("A")), hash("query A { a }", None));
+    assert_ne!(hash(query, Some("A")), hash(query, Some("B")));
+    assert_eq!(hash(query, None), None);
+    assert_eq!(hash(query, Some("C")), None);
+  }
+
+  #[test]
+  fn printer_is_canonical() {
+    let document =
+      parse_query("query Q($a: Int = 1) @d(x: 1) { f(a: $a) @d { ... on T { g } ...F @d } }")
+        .unwrap();
+    let DocumentOperations::Multiple(operations) = &document.operations else {
+      panic!("a named operation");
+    };
+    let (name, operation) = operations.iter().next().unwrap();
+    let mut printer = Printer(String::new());
+    printer.operation(Some(name), &operation.node).unwrap();
+    assert_eq!(
+      printer.0,
+      "query Q ($a: Int = 1) @d(x: 1) { f(a: $a) @d { ... on T { g } ...F @d } }\n"
+    );
+  }
+}
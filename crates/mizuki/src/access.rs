@@ -0,0 +1,278 @@
+use async_graphql::{parser::types::OperationType, Request, ServerError};
+use tauri::{Runtime, Url, Webview};
+
+use crate::{
+  operation::{operation, operation_type},
+  Error,
+};
+
+/// A pattern matching the URLs of the pages a webview shows.
+///
+/// Each part is a glob, where `*` matches any run of characters and `?` any single one.
+/// The parts left out match anything, a pattern without port matching every port of its host:
+///
+/// ```
+/// use mizuki::UrlPattern;
+///
+/// let app = UrlPattern::new().scheme("tauri").host("localhost");
+/// let dev_server = UrlPattern::new().host("localhost").port(1420);
+/// let admin = UrlPattern::new()
+///   .scheme("https")
+///   .host("*.example.com")
+///   .path("/admin/*");
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct UrlPattern {
+  scheme: Option<String>,
+  host: Option<String>,
+  port: Option<u16>,
+  path: Option<String>,
+}
+
+impl UrlPattern {
+  /// A pattern matching any URL.
+  pub fn new() -> Self {
+    Self::default()
+  }
+  /// Match the scheme of the URL, like `https` or `tauri`.
+  #[must_use]
+  pub fn scheme(mut self, glob: impl Into<String>) -> Self {
+    self.scheme = Some(glob.into());
+    self
+  }
+  /// Match the host of the URL, on any port unless [`UrlPattern::port`] is set.
+  ///
+  /// A URL without host, like `about:blank`, has an empty one.
+  #[must_use]
+  pub fn host(mut self, glob: impl Into<String>) -> Self {
+    self.host = Some(glob.into());
+    self
+  }
+  /// Match the port of the URL, the default one of its scheme when it has none.
+  ///
+  /// A URL without port, like `tauri://localhost`, never matches.
+  #[must_use]
+  pub fn port(mut self, port: u16) -> Self {
+    self.port = Some(port);
+    self
+  }
+  /// Match the path of the URL, without its query and fragment.
+  #[must_use]
+  pub fn path(mut self, glob: impl Into<String>) -> Self {
+    self.path = Some(glob.into());
+    self
+  }
+
+  fn matches(&self, url: &Url) -> bool {
+    let part = |glob: &Option<String>, value: &str| {
+      glob
+        .as_deref()
+        .map_or(true, |glob| glob_matches(glob.as_bytes(), value.as_bytes()))
+    };
+    part(&self.scheme, url.scheme())
+      && part(&self.host, url.host_str().unwrap_or_default())
+      && self
+        .port
+        .map_or(true, |port| url.port_or_known_default() == Some(port))
+      && part(&self.path, url.path())
+  }
+}
+
+/// Whether `value` matches `glob`, backtracking to the last `*` on a mismatch.
+fn glob_matches(glob: &[u8], value: &[u8]) -> bool {
+  let (mut g, mut v) = (0, 0);
+  let mut star = None;
+  while v < value.len() {
+    match glob.get(g) {
+      Some(b'*') => {
+        star = Some((g, v));
+        g += 1;
+      }
+      Some(&c) if c == b'?' || c == value[v] => {
+        g += 1;
+        v += 1;
+      }
+      _ => match star {
+        Some((star_g, star_v)) => {
+          star = Some((star_g, star_v + 1));
+          g = star_g + 1;
+          v = star_v + 1;
+        }
+        None => return false,
+      },
+    }
+  }
+  glob[g..].iter().all(|&c| c == b'*')
+}
+
+/// The URLs an operation type is allowed from.
+#[derive(Default)]
+pub(crate) struct OperationAccess {
+  pub(crate) allow: Vec<UrlPattern>,
+  pub(crate) deny: Vec<UrlPattern>,
+}
+
+impl OperationAccess {
+  /// Denied URLs never are, and without any allowed URL, all the others are.
+  fn allows(&self, url: &Url) -> bool {
+    !self.deny.iter().any(|pattern| pattern.matches(url))
+      && (self.allow.is_empty() || self.allow.iter().any(|pattern| pattern.matches(url)))
+  }
+}
+
+/// Which pages of the webviews are allowed to run the operations.
+#[derive(Default)]
+pub(crate) struct AccessControl {
+  pub(crate) query: OperationAccess,
+  pub(crate) mutation: OperationAccess,
+  pub(crate) subscription: OperationAccess,
+}
+
+impl AccessControl {
+  pub(crate) fn operation_mut(&mut self, ty: OperationType) -> &mut OperationAccess {
+    match ty {
+      OperationType::Query => &mut self.query,
+      OperationType::Mutation => &mut self.mutation,
+      OperationType::Subscription => &mut self.subscription,
+    }
+  }
+
+  /// Check that the current page of `webview` is allowed to run `request`.
+  ///
+  /// Invalid requests pass, they fail before running anything.
+  pub(crate) fn check<R: Runtime>(
+    &self,
+    request: &mut Request,
+    webview: &Webview<R>,
+  ) -> Result<(), ServerError> {
+    let Some((_, operation)) = operation(request) else {
+      return Ok(());
+    };
+    let access = match operation.ty {
+      OperationType::Query => &self.query,
+      OperationType::Mutation => &self.mutation,
+      OperationType::Subscription => &self.subscription,
+    };
+    let operation = operation_type(operation.ty);
+    let mut url = webview_url(webview);
+    if access.allows(&url) {
+      return Ok(());
+    }
+    url.set_query(None);
+    url.set_fragment(None);
+    log::warn!(
+      "denied a {} to the webview \"{}\" at {}",
+      operation,
+      webview.label(),
+      url
+    );
+    Err(
+      Error::AccessDenied {
+        operation,
+        url: url.into(),
+      }
+      .into(),
+    )
+  }
+}
+
+/// The URL of the current page of `webview`, `about:blank` if it has none.
+pub(crate) fn webview_url<R: Runtime>(webview: &Webview<R>) -> Url {
+  webview
+    .url()
+    .unwrap_or_else(|_| Url::parse("about:blank").expect("a valid url"))
+}
+
+#[cfg(test)]
+mod tests {
+  use tauri::{test::mock_app, WebviewUrl, WebviewWindowBuilder};
+
+  use super::*;
+
+  fn url(url: &str) -> Url {
+    Url::parse(url).unwrap()
+  }
+
+  #[test]
+  fn globs_match() {
+    let matches = |glob: &str, value: &str| glob_matches(glob.as_bytes(), value.as_bytes());
+    assert!(matches("example.com", "example.com"));
+    assert!(!matches("example.com", "example.co"));
+    assert!(matches("*.example.com", "api.example.com"));
+    assert!(!matches("*.example.com", "example.com"));
+    assert!(matches("/admin/*", "/admin/users/1"));
+    assert!(matches("a*b*c", "aXbYbZc"));
+    assert!(!matches("a*b*c", "aXbYbZ"));
+    assert!(matches("h?st", "host"));
+    assert!(!matches("h?st", "hst"));
+    assert!(matches("*", ""));
+    assert!(matches("**", "anything"));
+    assert!(!matches("", "a"));
+  }
+
+  #[test]
+  fn patterns_match_each_part() {
+    let admin = UrlPattern::new()
+      .scheme("https")
+      .host("*.example.com")
+      .path("/admin/*");
+    assert!(admin.matches(&url("https://app.example.com/admin/users?id=1#top")));
+    assert!(!admin.matches(&url("http://app.example.com/admin/users")));
+    assert!(!admin.matches(&url("https://example.org/admin/users")));
+    assert!(!admin.matches(&url("https://app.example.com/users")));
+    // The port isn't part of the host.
+    assert!(admin.matches(&url("https://app.example.com:8080/admin/")));
+    let dev_server = UrlPattern::new().host("localhost").port(1420);
+    assert!(dev_server.matches(&url("http://localhost:1420/")));
+    assert!(!dev_server.matches(&url("http://localhost:1421/")));
+    assert!(!dev_server.matches(&url("tauri://localhost/")));
+    assert!(UrlPattern::new()
+      .port(443)
+      .matches(&url("https://example.com/")));
+    assert!(UrlPattern::new().host("").matches(&url("about:blank")));
+    assert!(UrlPattern::new().matches(&url("tauri://localhost/")));
+  }
+
+  #[test]
+  fn deny_rules_win() {
+    let access = OperationAccess {
+      allow: vec![UrlPattern::new().scheme("tauri")],
+      deny: vec![UrlPattern::new().path("/untrusted/*")],
+    };
+    assert!(access.allows(&url("tauri://localhost/index.html")));
+    assert!(!access.allows(&url("tauri://localhost/untrusted/page")));
+    assert!(!access.allows(&url("https://example.com/")));
+
+    let deny_only = OperationAccess {
+      allow: Vec::new(),
+      deny: vec![UrlPattern::new().scheme("https")],
+    };
+    assert!(deny_only.allows(&url("tauri://localhost/")));
+    assert!(!deny_only.allows(&url("https://example.com/")));
+  }
+
+  #[test]
+  fn operations_are_checked_by_type() {
+    let app = mock_app();
+    let window = WebviewWindowBuilder::new(&app, "main", WebviewUrl::default())
+      .build()
+      .unwrap();
+    let webview = window.as_ref().clone();
+    let mut control = AccessControl::default();
+    control
+      .operation_mut(OperationType::Mutation)
+      .deny
+      .push(UrlPattern::new());
+
+    assert!(control.check(&mut Request::new("{ a }"), &webview).is_ok());
+    let error = control
+      .check(&mut Request::new("mutation { a }"), &webview)
+      .unwrap_err();
+    let code = error.extensions.unwrap().get("code").cloned();
+    assert_eq!(code, Some(async_graphql::Value::from("ACCESS_DENIED")));
+    // Invalid requests are left to the validation.
+    assert!(control
+      .check(&mut Request::new("mutation {"), &webview)
+      .is_ok());
+  }
+}
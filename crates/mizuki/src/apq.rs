@@ -0,0 +1,421 @@
+//! [Automatic persisted queries].
+//!
+//! A client can send the SHA-256 hash of its query in the `persistedQuery`
+//! extension instead of the query itself.
+//! If the plugin doesn't know the hash yet, the request fails with a
+//! `PERSISTED_QUERY_NOT_FOUND` error, and the client sends the query along
+//! with its hash to store it. The query is only stored once the request passed
+//! the other checks of the plugin, a rejected query is never persisted.
+//!
+//! The stores are set with [`crate::Builder::persisted_queries`]:
+//!
+//! ```rust,no_run
+//! use mizuki::apq::MemoryStore;
+//! # use async_graphql::{EmptyMutation, EmptySubscription, Object, Schema};
+//! # struct Query;
+//! # #[Object]
+//! # impl Query {
+//! #   async fn hello(&self) -> &str { "world" }
+//! # }
+//!
+//! let schema = Schema::new(Query, EmptyMutation, EmptySubscription);
+//! let plugin = mizuki::Builder::<tauri::Wry, _, _, _>::new("todo-plugin", schema)
+//!   .persisted_queries(MemoryStore::with_capacity(1024))
+//!   .build();
+//! ```
+//!
+//! Known queries are also kept parsed, so they aren't parsed again on each request.
+//!
+//! [Automatic persisted queries]: https://www.apollographql.com/docs/apollo-server/performance/apq
+
+use std::{
+  collections::{HashMap, VecDeque},
+  hash::Hash,
+  io,
+  path::{Path, PathBuf},
+  sync::Mutex,
+};
+
+use async_graphql::{parser::types::ExecutableDocument, Request, ServerError};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::Error;
+
+/// How many parsed documents are kept.
+const DOCUMENTS_CAPACITY: usize = 256;
+/// How many queries the stores keep, unless told otherwise.
+pub const DEFAULT_CAPACITY: usize = 1024;
+
+/// A storage of persisted queries, keyed by their SHA-256 hash.
+pub trait PersistedQueryStore: Send + Sync {
+  /// The query of a hash, if it is known.
+  fn get(&self, hash: &str) -> Option<String>;
+  /// Remember the query of a hash.
+  ///
+  /// The hash has already been checked against the query.
+  fn set(&self, hash: &str, query: &str);
+}
+
+/// A map that forgets its oldest entries past its capacity.
+struct Fifo<K, V> {
+  capacity: usize,
+  entries: HashMap<K, V>,
+  order: VecDeque<K>,
+}
+
+impl<K: Hash + Eq + Clone, V> Fifo<K, V> {
+  fn new(capacity: usize) -> Self {
+    Self {
+      capacity,
+      entries: HashMap::new(),
+      order: VecDeque::new(),
+    }
+  }
+
+  fn insert(&mut self, key: K, value: V) {
+    if self.entries.insert(key.clone(), value).is_some() {
+      return;
+    }
+    self.order.push_back(key);
+    if self.order.len() > self.capacity {
+      if let Some(oldest) = self.order.pop_front() {
+        self.entries.remove(&oldest);
+      }
+    }
+  }
+}
+
+/// Keeps the persisted queries in memory.
+pub struct MemoryStore(Mutex<Fifo<String, String>>);
+
+impl MemoryStore {
+  /// A store that forgets its oldest queries past [`DEFAULT_CAPACITY`] queries.
+  pub fn new() -> Self {
+    Self::with_capacity(DEFAULT_CAPACITY)
+  }
+  /// A store that forgets its oldest queries past `capacity` queries.
+  pub fn with_capacity(capacity: usize) -> Self {
+    Self(Mutex::new(Fifo::new(capacity)))
+  }
+}
+
+impl Default for MemoryStore {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl PersistedQueryStore for MemoryStore {
+  fn get(&self, hash: &str) -> Option<String> {
+    self.0.lock().unwrap().entries.get(hash).cloned()
+  }
+
+  fn set(&self, hash: &str, query: &str) {
+    self.0.lock().unwrap().insert(hash.into(), query.into());
+  }
+}
+
+/// Keeps the persisted queries on disk, one `{hash}.graphql` file each.
+///
+/// The queries then survive restarts of the app.
+/// Past its capacity, the store deletes the files of its oldest queries.
+pub struct FileStore {
+  dir: PathBuf,
+  capacity: usize,
+}
+
+impl FileStore {
+  /// A store of [`DEFAULT_CAPACITY`] queries in `dir`, which is created if needed.
+  pub fn new(dir: impl Into<PathBuf>) -> io::Result<Self> {
+    Self::with_capacity(dir, DEFAULT_CAPACITY)
+  }
+  /// A store of `capacity` queries in `dir`, which is created if needed.
+  pub fn with_capacity(dir: impl Into<PathBuf>, capacity: usize) -> io::Result<Self> {
+    let dir = dir.into();
+    std::fs::create_dir_all(&dir)?;
+    Ok(Self { dir, capacity })
+  }
+
+  fn path(&self, hash: &str) -> Option<PathBuf> {
+    // The hash comes from the webview, it must not escape the directory.
+    is_sha256(hash).then(|| self.dir.join(format!("{}.graphql", hash)))
+  }
+
+  /// Delete the oldest queries past the capacity, leaving the other files alone.
+  fn evict(&self) -> io::Result<()> {
+    let is_query = |path: &Path| {
+      path
+        .extension()
+        .is_some_and(|extension| extension == "graphql")
+        && path
+          .file_stem()
+          .and_then(|stem| stem.to_str())
+          .is_some_and(is_sha256)
+    };
+    let mut queries = Vec::new();
+    for entry in std::fs::read_dir(&self.dir)? {
+      let entry = entry?;
+      if is_query(&entry.path()) {
+        queries.push((entry.metadata()?.modified()?, entry.path()));
+      }
+    }
+    if queries.len() <= self.capacity {
+      return Ok(());
+    }
+    queries.sort();
+    let excess = queries.len() - self.capacity;
+    for (_, path) in queries.into_iter().take(excess) {
+      std::fs::remove_file(path)?;
+    }
+    Ok(())
+  }
+}
+
+impl PersistedQueryStore for FileStore {
+  fn get(&self, hash: &str) -> Option<String> {
+    std::fs::read_to_string(self.path(hash)?).ok()
+  }
+
+  fn set(&self, hash: &str, query: &str) {
+    let Some(path) = self.path(hash) else {
+      return;
+    };
+    if path.exists() {
+      return;
+    }
+    // Failing to persist a query only means it will be sent again.
+    if std::fs::write(path, query).is_ok() {
+      let _ = self.evict();
+    }
+  }
+}
+
+fn is_sha256(hash: &str) -> bool {
+  hash.len() == 64 && hash.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// The lowercase hex SHA-256 hash of a query.
+pub(crate) fn sha256(query: &str) -> String {
+  format!("{:x}", Sha256::digest(query.as_bytes()))
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PersistedQuery {
+  version: u32,
+  sha256_hash: String,
+}
+
+/// Resolves the persisted queries of the requests.
+pub(crate) struct PersistedQueries {
+  store: Option<Box<dyn PersistedQueryStore>>,
+  documents: Mutex<Fifo<String, ExecutableDocument>>,
+}
+
+impl PersistedQueries {
+  pub(crate) fn new(store: Option<Box<dyn PersistedQueryStore>>) -> Self {
+    Self {
+      store,
+      documents: Mutex::new(Fifo::new(DOCUMENTS_CAPACITY)),
+    }
+  }
+
+  /// Fill in the query of a request sent with its hash.
+  ///
+  /// A query sent along with its hash comes back with it, to [`Self::register`]
+  /// once the request passed its checks.
+  pub(crate) fn resolve(
+    &self,
+    mut request: Request,
+  ) -> Result<(Request, Option<Unregistered>), ServerError> {
+    let Some(extension) = request.extensions.0.get("persistedQuery") else {
+      return Ok((request, None));
+    };
+    let Some(store) = &self.store else {
+      if request.query.is_empty() {
+        return Err(Error::PersistedQueryNotSupported.into());
+      }
+      return Ok((request, None));
+    };
+    let persisted: PersistedQuery = async_graphql::from_value(extension.clone())
+      .map_err(|_| Error::InvalidPersistedQuery("malformed extension"))?;
+    if persisted.version != 1 {
+      return Err(Error::InvalidPersistedQuery("unsupported version").into());
+    }
+    let hash = persisted.sha256_hash.to_ascii_lowercase();
+
+    if !request.query.is_empty() {
+      if sha256(&request.query) != hash {
+        return Err(Error::InvalidPersistedQuery("the hash doesn't match the query").into());
+      }
+      return Ok((request, Some(Unregistered(hash))));
+    }
+    request.query = store.get(&hash).ok_or(Error::PersistedQueryNotFound)?;
+    self.parse(&hash, &mut request);
+    Ok((request, None))
+  }
+
+  /// Store the query of a request that passed its checks.
+  pub(crate) fn register(&self, unregistered: Unregistered, request: &mut Request) {
+    let Unregistered(hash) = unregistered;
+    if let Some(store) = &self.store {
+      store.set(&hash, &request.query);
+    }
+    self.parse(&hash, request);
+  }
+
+  /// Parse the query of a request once, for all the requests sending its hash.
+  fn parse(&self, hash: &str, request: &mut Request) {
+    let cached = self.documents.lock().unwrap().entries.get(hash).cloned();
+    match cached {
+      Some(document) => request.set_parsed_query(document),
+      None => {
+        if let Ok(document) = request.parsed_query() {
+          let document = document.clone();
+          self.documents.lock().unwrap().insert(hash.into(), document);
+        }
+      }
+    }
+  }
+}
+
+/// The hash of a query sent along with it, not stored yet.
+#[derive(Debug)]
+pub(crate) struct Unregistered(String);
+
+#[cfg(test)]
+mod tests {
+  use async_graphql::Value;
+
+  use super::*;
+
+  const QUERY: &str = "{ hello }";
+
+  /// A request sent with the hash of its query, and the query unless `hash_only`.
+  fn request(hash: &str, hash_only: bool) -> Request {
+    let mut request = Request::new(if hash_only { "" } else { QUERY });
+    let extension = Value::from_json(serde_json::json!({ "version": 1, "sha256Hash": hash }));
+    request
+      .extensions
+      .0
+      .insert("persistedQuery".into(), extension.unwrap());
+    request
+  }
+
+  fn code(error: ServerError) -> Option<Value> {
+    error.extensions?.get("code").cloned()
+  }
+
+  #[test]
+  fn the_memory_store_forgets_the_oldest_queries() {
+    let store = MemoryStore::with_capacity(2);
+    store.set("a", "1");
+    store.set("b", "2");
+    store.set("a", "1");
+    store.set("c", "3");
+    assert_eq!(store.get("a"), None);
+    assert_eq!(store.get("b").as_deref(), Some("2"));
+    assert_eq!(store.get("c").as_deref(), Some("3"));
+  }
+
+  #[test]
+  fn the_stores_are_bounded_by_default() {
+    let store = MemoryStore::new();
+    for i in 0..=DEFAULT_CAPACITY {
+      store.set(&i.to_string(), "{ hello }");
+    }
+    assert_eq!(store.get("0"), None);
+    assert!(store.get(&DEFAULT_CAPACITY.to_string()).is_some());
+
+    let dir = std::env::temp_dir().join(format!("mizuki-apq-capacity-{}", std::process::id()));
+    let store = FileStore::with_capacity(&dir, 2).unwrap();
+    let queries = ["{ a }", "{ b }", "{ c }"];
+    for query in queries {
+      store.set(&sha256(query), query);
+      // The modification times tell the oldest query apart.
+      std::thread::sleep(std::time::Duration::from_millis(20));
+    }
+    assert_eq!(store.get(&sha256("{ a }")), None);
+    assert_eq!(store.get(&sha256("{ c }")).as_deref(), Some("{ c }"));
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 2);
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn the_file_store_only_takes_hashes() {
+    let dir = std::env::temp_dir().join(format!("mizuki-apq-{}", std::process::id()));
+    let store = FileStore::new(&dir).unwrap();
+    let hash = sha256(QUERY);
+    store.set(&hash, QUERY);
+    assert_eq!(store.get(&hash).as_deref(), Some(QUERY));
+    assert_eq!(
+      FileStore::new(&dir).unwrap().get(&hash).as_deref(),
+      Some(QUERY)
+    );
+    store.set("../escape", QUERY);
+    assert_eq!(store.get("../escape"), None);
+    assert!(!dir.parent().unwrap().join("escape.graphql").exists());
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn queries_are_persisted_then_resolved_by_hash() {
+    let queries = PersistedQueries::new(Some(Box::new(MemoryStore::new())));
+    let hash = sha256(QUERY);
+    let not_found = queries.resolve(request(&hash, true)).unwrap_err();
+    assert_eq!(
+      code(not_found),
+      Some(Value::from("PERSISTED_QUERY_NOT_FOUND"))
+    );
+    let (mut sent, unregistered) = queries.resolve(request(&hash, false)).unwrap();
+    assert_eq!(sent.query, QUERY);
+    queries.register(unregistered.unwrap(), &mut sent);
+    let (mut resolved, unregistered) = queries
+      .resolve(request(&hash.to_uppercase(), true))
+      .unwrap();
+    assert!(unregistered.is_none());
+    assert_eq!(resolved.query, QUERY);
+    assert!(resolved.parsed_query().is_ok());
+  }
+
+  #[test]
+  fn queries_are_only_stored_once_registered() {
+    let queries = PersistedQueries::new(Some(Box::new(MemoryStore::new())));
+    let hash = sha256(QUERY);
+    // Rejected by a later check, the query is never registered.
+    let (_, unregistered) = queries.resolve(request(&hash, false)).unwrap();
+    assert!(unregistered.is_some());
+    let not_found = queries.resolve(request(&hash, true)).unwrap_err();
+    assert_eq!(
+      code(not_found),
+      Some(Value::from("PERSISTED_QUERY_NOT_FOUND"))
+    );
+  }
+
+  #[test]
+  fn mismatched_hashes_are_rejected() {
+    let queries = PersistedQueries::new(Some(Box::new(MemoryStore::new())));
+    let error = queries
+      .resolve(request(&sha256("{ other }"), false))
+      .unwrap_err();
+    assert_eq!(code(error), Some(Value::from("BAD_USER_INPUT")));
+  }
+
+  #[test]
+  fn without_a_store_the_hash_is_ignored() {
+    let queries = PersistedQueries::new(None);
+    let hash = sha256(QUERY);
+    let (sent, unregistered) = queries.resolve(request(&hash, false)).unwrap();
+    assert_eq!(sent.query, QUERY);
+    assert!(unregistered.is_none());
+    let error = queries.resolve(request(&hash, true)).unwrap_err();
+    assert_eq!(
+      code(error),
+      Some(Value::from("PERSISTED_QUERY_NOT_SUPPORTED"))
+    );
+    // Requests without the extension are left alone.
+    let plain = Request::new(QUERY);
+    assert_eq!(queries.resolve(plain).unwrap().0.query, QUERY);
+  }
+}
@@ -1,74 +1,332 @@
-use std::marker::PhantomData;
+use std::{
+  collections::HashMap,
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+  },
+};
 
-use tauri::{EventId, Listener, Runtime};
+use tauri::{EventId, Listener, Runtime, Webview};
 use tokio_util::sync::CancellationToken;
 
-#[derive(Debug)]
-pub struct CancellationTokenListener<L, R>
-where
-  L: Listener<R>,
-  R: Runtime,
-{
-  listener: L,
+/// The operations of a webview that can be cancelled.
+struct WebviewTokens<R: Runtime> {
+  webview: Webview<R>,
+  window: String,
   event_id: EventId,
-  cancel_token: CancellationToken,
-  runtime: PhantomData<R>,
+  /// The parent of the tokens of the page currently loaded.
+  page: CancellationToken,
+  tokens: HashMap<u64, CancellationToken>,
+  /// The keys of the tokens, by the id the webview cancels them with.
+  ids: HashMap<String, Vec<u64>>,
 }
 
-impl<L, R> Drop for CancellationTokenListener<L, R>
-where
-  L: Listener<R>,
-  R: Runtime,
-{
-  fn drop(&mut self) {
-    self.listener.unlisten(self.event_id);
-  }
+/// Routes the cancel events of the webviews to the tokens of their operations.
+///
+/// Each webview gets a single listener, set up with its first operation and
+/// removed when its window is destroyed.
+/// The operations of a page are also cancelled when the webview leaves it.
+pub(crate) struct CancelDispatcher<R: Runtime> {
+  event: String,
+  webviews: Mutex<HashMap<String, WebviewTokens<R>>>,
+  next_key: AtomicU64,
 }
 
-impl<L, R> CancellationTokenListener<L, R>
-where
-  L: Listener<R>,
-  R: Runtime,
-{
-  pub fn new(listener: L, event: String, payload_match: String) -> Self {
-    let token = CancellationToken::new();
-    let cloned_token = token.clone();
-    let event_id = listener.listen(event.clone(), move |e| {
-      // println!("{} - {}", event, e.payload());
-      let Ok(payload) = serde_json::from_str::<String>(e.payload()) else {
+impl<R: Runtime> CancelDispatcher<R> {
+  pub(crate) fn new(event: String) -> Self {
+    Self {
+      event,
+      webviews: Default::default(),
+      next_key: AtomicU64::new(0),
+    }
+  }
+
+  /// Make a token for an operation of `webview`, cancelled by the cancel events carrying `id`.
+  ///
+  /// The operation is forgotten when the returned guard is dropped.
+  pub(crate) fn register(
+    self: &Arc<Self>,
+    webview: &Webview<R>,
+    id: Option<&str>,
+  ) -> CancelGuard<R> {
+    let key = self.next_key.fetch_add(1, Ordering::Relaxed);
+    let mut webviews = self.webviews.lock().unwrap();
+    let tokens = webviews
+      .entry(webview.label().into())
+      .or_insert_with(|| self.listen(webview));
+    let page = tokens.page.clone();
+    let token = page.child_token();
+    tokens.tokens.insert(key, token.clone());
+    if let Some(id) = id {
+      tokens.ids.entry(id.into()).or_default().push(key);
+    }
+    CancelGuard {
+      dispatcher: self.clone(),
+      webview: webview.label().into(),
+      key,
+      id: id.map(Into::into),
+      token,
+      page,
+    }
+  }
+
+  fn listen(self: &Arc<Self>, webview: &Webview<R>) -> WebviewTokens<R> {
+    let dispatcher = Arc::downgrade(self);
+    let label = webview.label().to_string();
+    let event_id = webview.listen(self.event.clone(), move |event| {
+      let Ok(id) = serde_json::from_str::<String>(event.payload()) else {
         return;
       };
-      if payload == payload_match {
-        // println!("Cancel!");
-        cloned_token.cancel();
+      if let Some(dispatcher) = dispatcher.upgrade() {
+        dispatcher.cancel(&label, &id);
       }
     });
-    Self {
-      listener,
+    WebviewTokens {
+      webview: webview.clone(),
+      window: webview.window().label().into(),
       event_id,
-      cancel_token: token,
-      runtime: PhantomData::<R>,
+      page: CancellationToken::new(),
+      tokens: HashMap::new(),
+      ids: HashMap::new(),
     }
   }
-  #[allow(dead_code)]
-  pub fn listener(&self) -> &L {
-    &self.listener
+
+  fn cancel(&self, webview: &str, id: &str) {
+    let webviews = self.webviews.lock().unwrap();
+    let Some(tokens) = webviews.get(webview) else {
+      return;
+    };
+    for key in tokens.ids.get(id).into_iter().flatten() {
+      if let Some(token) = tokens.tokens.get(key) {
+        token.cancel();
+      }
+    }
+  }
+
+  /// Cancel the operations of the page a webview leaves, and forget them.
+  pub(crate) fn leave_page(&self, webview: &str) {
+    let mut webviews = self.webviews.lock().unwrap();
+    if let Some(tokens) = webviews.get_mut(webview) {
+      std::mem::take(&mut tokens.page).cancel();
+      tokens.tokens.clear();
+      tokens.ids.clear();
+    }
+  }
+
+  /// Cancel the operations of the webviews of a window, and stop listening to them.
+  pub(crate) fn close_window(&self, window: &str) {
+    let closed: Vec<_> = {
+      let mut webviews = self.webviews.lock().unwrap();
+      let labels: Vec<_> = webviews
+        .iter()
+        .filter(|(_, tokens)| tokens.window == window)
+        .map(|(label, _)| label.clone())
+        .collect();
+      labels
+        .iter()
+        .filter_map(|label| webviews.remove(label))
+        .collect()
+    };
+    for tokens in closed {
+      tokens.webview.unlisten(tokens.event_id);
+      tokens.page.cancel();
+    }
+  }
+
+  fn remove(&self, webview: &str, key: u64, id: Option<&str>) {
+    let mut webviews = self.webviews.lock().unwrap();
+    let Some(tokens) = webviews.get_mut(webview) else {
+      return;
+    };
+    tokens.tokens.remove(&key);
+    if let Some(id) = id {
+      if let Some(keys) = tokens.ids.get_mut(id) {
+        keys.retain(|k| *k != key);
+        if keys.is_empty() {
+          tokens.ids.remove(id);
+        }
+      }
+    }
+  }
+}
+
+/// Keeps the token of an operation in the [`CancelDispatcher`].
+pub(crate) struct CancelGuard<R: Runtime> {
+  dispatcher: Arc<CancelDispatcher<R>>,
+  webview: String,
+  key: u64,
+  id: Option<String>,
+  token: CancellationToken,
+  page: CancellationToken,
+}
+
+impl<R: Runtime> CancelGuard<R> {
+  /// Cancelled by the webview, or when the page is left.
+  pub(crate) fn token(&self) -> CancellationToken {
+    self.token.clone()
   }
-  pub fn token(&self) -> CancellationToken {
-    self.cancel_token.clone()
+  /// Only cancelled when the page is left.
+  pub(crate) fn page_token(&self) -> CancellationToken {
+    self.page.clone()
   }
 }
 
-unsafe impl<L, R> Send for CancellationTokenListener<L, R>
-where
-  L: Listener<R> + Send,
-  R: Runtime,
-{
+impl<R: Runtime> Drop for CancelGuard<R> {
+  fn drop(&mut self) {
+    self
+      .dispatcher
+      .remove(&self.webview, self.key, self.id.as_deref());
+  }
 }
 
-unsafe impl<L, R> Sync for CancellationTokenListener<L, R>
-where
-  L: Listener<R> + Sync,
-  R: Runtime,
-{
+#[cfg(test)]
+mod tests {
+  use std::{sync::mpsc, time::Duration};
+
+  use async_graphql::{
+    futures_util::future, Context, EmptyMutation, EmptySubscription, Object, Schema,
+  };
+  use tauri::{
+    http::{HeaderMap, HeaderValue},
+    ipc::{CallbackFn, InvokeBody, InvokeResponse, InvokeResponseBody},
+    test::{mock_app, mock_builder, mock_context, noop_assets, MockRuntime, INVOKE_KEY},
+    utils::acl::ExecutionContext,
+    webview::InvokeRequest,
+    App, Emitter, EventTarget, WebviewUrl, WebviewWindowBuilder,
+  };
+
+  use super::*;
+  use crate::REQUEST_ID_HEADER;
+
+  fn webview(app: &App<MockRuntime>, label: &str) -> Webview<MockRuntime> {
+    let window = WebviewWindowBuilder::new(app, label, WebviewUrl::default())
+      .build()
+      .unwrap();
+    window.as_ref().clone()
+  }
+
+  fn dispatcher() -> Arc<CancelDispatcher<MockRuntime>> {
+    Arc::new(CancelDispatcher::new("cancel".into()))
+  }
+
+  #[test]
+  fn cancel_events_cancel_the_operations_with_their_id() {
+    let app = mock_app();
+    let webview = webview(&app, "main");
+    let dispatcher = dispatcher();
+    let first = dispatcher.register(&webview, Some("a"));
+    let second = dispatcher.register(&webview, Some("a"));
+    let other = dispatcher.register(&webview, Some("b"));
+    dispatcher.cancel("main", "a");
+    assert!(first.token().is_cancelled());
+    assert!(second.token().is_cancelled());
+    assert!(!other.token().is_cancelled());
+    assert!(!first.page_token().is_cancelled());
+  }
+
+  #[test]
+  fn leaving_the_page_cancels_its_operations() {
+    let app = mock_app();
+    let main = webview(&app, "main");
+    let other = webview(&app, "other");
+    let dispatcher = dispatcher();
+    let guard = dispatcher.register(&main, Some("a"));
+    let untouched = dispatcher.register(&other, Some("a"));
+    dispatcher.leave_page("main");
+    assert!(guard.token().is_cancelled());
+    assert!(guard.page_token().is_cancelled());
+    assert!(!untouched.token().is_cancelled());
+
+    // The next page starts afresh.
+    let next = dispatcher.register(&main, Some("a"));
+    assert!(!next.token().is_cancelled());
+    assert!(!next.page_token().is_cancelled());
+    let webviews = dispatcher.webviews.lock().unwrap();
+    assert_eq!(webviews["main"].tokens.len(), 1);
+    assert_eq!(webviews["main"].ids["a"].len(), 1);
+  }
+
+  #[test]
+  fn dropped_guards_are_forgotten() {
+    let app = mock_app();
+    let webview = webview(&app, "main");
+    let dispatcher = dispatcher();
+    let guard = dispatcher.register(&webview, Some("a"));
+    drop(guard);
+    let webviews = dispatcher.webviews.lock().unwrap();
+    assert!(webviews["main"].tokens.is_empty());
+    assert!(webviews["main"].ids.is_empty());
+  }
+
+  #[test]
+  fn closing_the_window_forgets_its_webviews() {
+    let app = mock_app();
+    let webview = webview(&app, "main");
+    let dispatcher = dispatcher();
+    let guard = dispatcher.register(&webview, None);
+    dispatcher.close_window("main");
+    assert!(guard.token().is_cancelled());
+    assert!(dispatcher.webviews.lock().unwrap().is_empty());
+  }
+
+  struct Query;
+
+  #[Object]
+  impl Query {
+    /// Tells the test it started, then never ends.
+    async fn stuck(&self, ctx: &Context<'_>) -> i32 {
+      let started = ctx.data_unchecked::<Mutex<mpsc::Sender<()>>>();
+      started.lock().unwrap().send(()).unwrap();
+      future::pending().await
+    }
+  }
+
+  #[test]
+  fn the_end_event_cancels_a_graphql_request_by_its_id() {
+    let (started, stuck) = mpsc::channel::<()>();
+    let schema = Schema::build(Query, EmptyMutation, EmptySubscription)
+      .data(Mutex::new(started))
+      .finish();
+    let mut context = mock_context(noop_assets());
+    context
+      .runtime_authority_mut()
+      .__allow_command("plugin:test|graphql".into(), ExecutionContext::Local);
+    let app = mock_builder()
+      .plugin(crate::Builder::new("test", schema).build())
+      .build(context)
+      .unwrap();
+    let webview = WebviewWindowBuilder::new(&app, "main", WebviewUrl::default())
+      .build()
+      .unwrap();
+
+    let mut headers = HeaderMap::new();
+    headers.insert(REQUEST_ID_HEADER, HeaderValue::from_static("list-1"));
+    let (respond, response) = mpsc::channel();
+    webview.as_ref().clone().on_message(
+      InvokeRequest {
+        cmd: "plugin:test|graphql".into(),
+        callback: CallbackFn(0),
+        error: CallbackFn(1),
+        url: "tauri://localhost".parse().unwrap(),
+        body: InvokeBody::Json(serde_json::json!({ "query": "{ stuck }" })),
+        headers,
+        invoke_key: INVOKE_KEY.into(),
+      },
+      Box::new(move |_, _, response, _, _| respond.send(response).unwrap()),
+    );
+    stuck.recv_timeout(Duration::from_secs(5)).unwrap();
+    app
+      .emit_to(EventTarget::webview("main"), "sub_end", "list-1")
+      .unwrap();
+
+    let InvokeResponse::Ok(InvokeResponseBody::Json(tuple)) =
+      response.recv_timeout(Duration::from_secs(5)).unwrap()
+    else {
+      panic!("a json response");
+    };
+    let (body, is_ok): (String, bool) = serde_json::from_str(&tuple).unwrap();
+    assert!(!is_ok);
+    let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(body["errors"][0]["extensions"]["code"], "CANCELLED");
+  }
 }
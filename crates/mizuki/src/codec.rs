@@ -0,0 +1,479 @@
+//! Encoding of the invoke payloads.
+//!
+//! A webview can send its request as raw bytes instead of a JavaScript object,
+//! by passing an [`ArrayBuffer`] or [`Uint8Array`] to `invoke`.
+//! The bytes are then decoded with the codec named by the `Mizuki-Content-Type`
+//! invoke header, JSON if there is none.
+//! Tauri owns the `Content-Type` header of raw invokes, hence the prefix.
+//!
+//! ```js
+//! import { invoke } from '@tauri-apps/api/core'
+//! import { encode, decode } from '@msgpack/msgpack'
+//!
+//! const response = await invoke('plugin:todo-plugin|graphql', encode({ query: '{ list { id } }' }), {
+//!   headers: { 'Mizuki-Content-Type': 'application/msgpack' }
+//! })
+//! const result = decode(new Uint8Array(response))
+//! ```
+//!
+//! The response of a raw request is encoded with the same codec, unless the
+//! `Mizuki-Accept` header names another one.
+//! Requests sent as JavaScript objects keep the `[body, isOk]` response,
+//! unless they set the `Mizuki-Accept` header too, or the plugin uses
+//! [`ResponseMode::Body`].
+//! The same goes for the results of the subscriptions streamed over a channel.
+//!
+//! MessagePack and CBOR are respectively behind the `msgpack` and `cbor` features.
+//!
+//! The `graphql` command also accepts the [GraphQL multipart request spec],
+//! to hand files to the resolvers as [`async_graphql::Upload`] values.
+//! The browser can build the body and its boundary from a [`FormData`]:
+//!
+//! ```js
+//! const form = new FormData()
+//! form.append('operations', JSON.stringify({
+//!   query: 'mutation ($file: Upload!) { upload(file: $file) }',
+//!   variables: { file: null }
+//! }))
+//! form.append('map', JSON.stringify({ 0: ['variables.file'] }))
+//! form.append('0', file)
+//!
+//! const body = new Response(form)
+//! const [response, isOk] = await invoke('plugin:todo-plugin|graphql', new Uint8Array(await body.arrayBuffer()), {
+//!   headers: { 'Mizuki-Content-Type': body.headers.get('Content-Type') }
+//! })
+//! ```
+//!
+//! The response of a multipart request is the JSON `[body, isOk]` tuple,
+//! unless the `Mizuki-Accept` header names a codec.
+//!
+//! [GraphQL multipart request spec]: https://github.com/jaydenseric/graphql-multipart-request-spec
+//! [`FormData`]: https://developer.mozilla.org/en-US/docs/Web/API/FormData
+//! [`ArrayBuffer`]: https://developer.mozilla.org/en-US/docs/Web/JavaScript/Reference/Global_Objects/ArrayBuffer
+//! [`Uint8Array`]: https://developer.mozilla.org/en-US/docs/Web/JavaScript/Reference/Global_Objects/Uint8Array
+
+use std::borrow::Cow;
+
+use async_graphql::{
+  http::{receive_batch_body, MultipartOptions},
+  BatchRequest, ParseRequestError,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use tauri::{
+  http::HeaderMap,
+  ipc::{InvokeBody, InvokeResponseBody},
+};
+
+/// The invoke header naming the codec of a raw payload.
+pub const CONTENT_TYPE_HEADER: &str = "mizuki-content-type";
+/// The invoke header naming the codec the response should be encoded with.
+pub const ACCEPT_HEADER: &str = "mizuki-accept";
+
+/// A payload encoding.
+///
+/// ```
+/// use mizuki::codec::Codec;
+///
+/// let codec = Codec::from_mime("application/json; charset=utf-8").unwrap();
+/// assert_eq!(codec, Codec::Json);
+///
+/// let bytes = codec.encode(&vec![1, 2, 3]).unwrap();
+/// assert_eq!(codec.decode::<Vec<u8>>(&bytes).unwrap(), vec![1, 2, 3]);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Codec {
+  /// `application/json`
+  Json,
+  /// `application/msgpack`
+  #[cfg(feature = "msgpack")]
+  MessagePack,
+  /// `application/cbor`
+  #[cfg(feature = "cbor")]
+  Cbor,
+}
+
+impl Codec {
+  /// Find the codec of a MIME type.
+  ///
+  /// The MIME type parameters are ignored.
+  pub fn from_mime(mime: &str) -> Option<Self> {
+    let essence = mime.split(';').next().unwrap_or_default().trim();
+    match essence.to_ascii_lowercase().as_str() {
+      "application/json" => Some(Self::Json),
+      #[cfg(feature = "msgpack")]
+      "application/msgpack" | "application/x-msgpack" | "application/vnd.msgpack" => {
+        Some(Self::MessagePack)
+      }
+      #[cfg(feature = "cbor")]
+      "application/cbor" => Some(Self::Cbor),
+      _ => None,
+    }
+  }
+
+  /// The MIME type of the codec.
+  pub fn mime(&self) -> &'static str {
+    match self {
+      Self::Json => "application/json",
+      #[cfg(feature = "msgpack")]
+      Self::MessagePack => "application/msgpack",
+      #[cfg(feature = "cbor")]
+      Self::Cbor => "application/cbor",
+    }
+  }
+
+  /// Decode a value from bytes.
+  pub fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, CodecError> {
+    match self {
+      Self::Json => Ok(serde_json::from_slice(bytes)?),
+      #[cfg(feature = "msgpack")]
+      Self::MessagePack => Ok(rmp_serde::from_slice(bytes)?),
+      #[cfg(feature = "cbor")]
+      Self::Cbor => ciborium::from_reader(bytes).map_err(|e| CodecError::Cbor(e.to_string())),
+    }
+  }
+
+  /// Encode a value to bytes.
+  pub fn encode<T: Serialize + ?Sized>(&self, value: &T) -> Result<Vec<u8>, CodecError> {
+    match self {
+      Self::Json => Ok(serde_json::to_vec(value)?),
+      #[cfg(feature = "msgpack")]
+      Self::MessagePack => Ok(rmp_serde::to_vec_named(value)?),
+      #[cfg(feature = "cbor")]
+      Self::Cbor => {
+        let mut bytes = Vec::new();
+        ciborium::into_writer(value, &mut bytes).map_err(|e| CodecError::Cbor(e.to_string()))?;
+        Ok(bytes)
+      }
+    }
+  }
+
+  /// Encode a value as an IPC response body.
+  ///
+  /// JSON is sent as is, so the webview receives it as an object.
+  pub(crate) fn encode_body<T: Serialize + ?Sized>(
+    &self,
+    value: &T,
+  ) -> Result<InvokeResponseBody, CodecError> {
+    match self {
+      Self::Json => Ok(InvokeResponseBody::Json(serde_json::to_string(value)?)),
+      #[allow(unreachable_patterns)]
+      _ => Ok(InvokeResponseBody::Raw(self.encode(value)?)),
+    }
+  }
+
+  fn from_header(headers: &HeaderMap, name: &str) -> Result<Option<Self>, CodecError> {
+    let Some(value) = headers.get(name) else {
+      return Ok(None);
+    };
+    let value = String::from_utf8_lossy(value.as_bytes());
+    Self::from_mime(&value)
+      .map(Some)
+      .ok_or_else(|| CodecError::UnsupportedContentType(value.into_owned()))
+  }
+}
+
+/// How the `graphql` command answers the requests sent as JavaScript objects.
+///
+/// The [`ACCEPT_HEADER`] of a request takes precedence over it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum ResponseMode {
+  /// The `[body, isOk]` tuple, where `body` is the serialized response.
+  ///
+  /// The webview has to parse `body` again.
+  #[default]
+  Tuple,
+  /// The response itself, serialized once and received as an object.
+  Body,
+}
+
+impl ResponseMode {
+  pub(crate) fn codec(self) -> Option<Codec> {
+    match self {
+      Self::Tuple => None,
+      Self::Body => Some(Codec::Json),
+    }
+  }
+}
+
+/// Errors that can happen while decoding or encoding a payload.
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum CodecError {
+  /// The payload uses a content type that isn't supported, or whose feature isn't enabled.
+  #[error("unsupported content type: {0}")]
+  UnsupportedContentType(String),
+  #[error(transparent)]
+  Json(#[from] serde_json::Error),
+  #[cfg(feature = "msgpack")]
+  #[error(transparent)]
+  MessagePackDecode(#[from] rmp_serde::decode::Error),
+  #[cfg(feature = "msgpack")]
+  #[error(transparent)]
+  MessagePackEncode(#[from] rmp_serde::encode::Error),
+  #[cfg(feature = "cbor")]
+  #[error("{0}")]
+  Cbor(String),
+  /// The multipart payload is malformed or exceeds the [`MultipartOptions`].
+  #[error(transparent)]
+  Multipart(#[from] ParseRequestError),
+}
+
+/// Decode an invoke payload.
+///
+/// Raw payloads are decoded with the codec of their [`CONTENT_TYPE_HEADER`], JSON by default.
+pub(crate) fn decode<T: DeserializeOwned>(
+  body: &InvokeBody,
+  headers: &HeaderMap,
+) -> Result<T, CodecError> {
+  match body {
+    InvokeBody::Json(value) => Ok(T::deserialize(value)?),
+    InvokeBody::Raw(bytes) => Codec::from_header(headers, CONTENT_TYPE_HEADER)?
+      .unwrap_or(Codec::Json)
+      .decode(bytes),
+  }
+}
+
+/// Decode the payload of the `graphql` command.
+///
+/// Unlike [`decode`], raw payloads can also be multipart requests.
+pub(crate) async fn decode_batch_request(
+  body: &InvokeBody,
+  headers: &HeaderMap,
+  multipart_options: MultipartOptions,
+) -> Result<BatchRequest, CodecError> {
+  match (body, multipart_content_type(headers)) {
+    (InvokeBody::Raw(bytes), Some(content_type)) => {
+      Ok(receive_batch_body(Some(content_type), bytes.as_slice(), multipart_options).await?)
+    }
+    _ => decode(body, headers),
+  }
+}
+
+/// The [`CONTENT_TYPE_HEADER`] of the payload, if it is a multipart one.
+fn multipart_content_type(headers: &HeaderMap) -> Option<Cow<'_, str>> {
+  let value = String::from_utf8_lossy(headers.get(CONTENT_TYPE_HEADER)?.as_bytes());
+  value
+    .trim_start()
+    .get(..10)
+    .is_some_and(|prefix| prefix.eq_ignore_ascii_case("multipart/"))
+    .then_some(value)
+}
+
+/// The codec the response of an invoke should be encoded with.
+///
+/// `None` means that the webview expects the JSON `[body, isOk]` tuple.
+pub(crate) fn response_codec(
+  body: &InvokeBody,
+  headers: &HeaderMap,
+) -> Result<Option<Codec>, CodecError> {
+  if let Some(codec) = Codec::from_header(headers, ACCEPT_HEADER)? {
+    return Ok(Some(codec));
+  }
+  match body {
+    InvokeBody::Json(_) => Ok(None),
+    InvokeBody::Raw(_) if multipart_content_type(headers).is_some() => Ok(None),
+    InvokeBody::Raw(_) => Ok(Some(
+      Codec::from_header(headers, CONTENT_TYPE_HEADER)?.unwrap_or(Codec::Json),
+    )),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use async_graphql::{EmptyMutation, EmptySubscription, Object, Request, Schema};
+  use serde_json::json;
+  use tauri::{
+    http::HeaderValue,
+    ipc::CallbackFn,
+    test::{get_ipc_response, mock_builder, mock_context, noop_assets, INVOKE_KEY},
+    utils::acl::ExecutionContext,
+    webview::InvokeRequest,
+    WebviewUrl, WebviewWindowBuilder,
+  };
+
+  use super::*;
+
+  struct Query;
+
+  #[Object]
+  impl Query {
+    async fn hello(&self) -> &str {
+      "world"
+    }
+  }
+
+  /// The body the graphql command of a plugin answers `{ hello }` with.
+  fn invoke_graphql(mode: ResponseMode) -> InvokeResponseBody {
+    let mut context = mock_context(noop_assets());
+    context
+      .runtime_authority_mut()
+      .__allow_command("plugin:test|graphql".into(), ExecutionContext::Local);
+    let plugin = crate::Builder::new("test", Schema::new(Query, EmptyMutation, EmptySubscription))
+      .response_mode(mode)
+      .build();
+    let app = mock_builder().plugin(plugin).build(context).unwrap();
+    let webview = WebviewWindowBuilder::new(&app, "main", WebviewUrl::default())
+      .build()
+      .unwrap();
+    get_ipc_response(
+      &webview,
+      InvokeRequest {
+        cmd: "plugin:test|graphql".into(),
+        callback: CallbackFn(0),
+        error: CallbackFn(1),
+        url: "tauri://localhost".parse().unwrap(),
+        body: InvokeBody::Json(json!({ "query": "{ hello }" })),
+        headers: HeaderMap::new(),
+        invoke_key: INVOKE_KEY.into(),
+      },
+    )
+    .unwrap()
+  }
+
+  fn headers(entries: &[(&'static str, &str)]) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    for (name, value) in entries {
+      headers.insert(*name, HeaderValue::from_str(value).unwrap());
+    }
+    headers
+  }
+
+  fn codecs() -> Vec<Codec> {
+    vec![
+      Codec::Json,
+      #[cfg(feature = "msgpack")]
+      Codec::MessagePack,
+      #[cfg(feature = "cbor")]
+      Codec::Cbor,
+    ]
+  }
+
+  #[test]
+  fn codecs_round_trip_requests() {
+    let value = json!({
+      "query": "query Q($id: ID!) { user(id: $id) { name } }",
+      "operationName": "Q",
+      "variables": { "id": "1", "nested": [1, 2.5, null, true] },
+    });
+    for codec in codecs() {
+      let bytes = codec.encode(&value).unwrap();
+      let request: Request = codec.decode(&bytes).unwrap();
+      assert_eq!(request.operation_name.as_deref(), Some("Q"), "{:?}", codec);
+      assert_eq!(
+        codec.decode::<serde_json::Value>(&bytes).unwrap(),
+        value,
+        "{:?}",
+        codec
+      );
+      assert_eq!(Codec::from_mime(codec.mime()), Some(codec));
+    }
+  }
+
+  #[test]
+  fn mime_parameters_and_case_are_ignored() {
+    assert_eq!(
+      Codec::from_mime(" Application/JSON ; charset=utf-8"),
+      Some(Codec::Json)
+    );
+    assert_eq!(Codec::from_mime("text/plain"), None);
+  }
+
+  #[test]
+  fn raw_payloads_are_decoded_with_their_content_type() {
+    let body = InvokeBody::Raw(br#"{ "query": "{ a }" }"#.to_vec());
+    let request: Request = decode(&body, &HeaderMap::new()).unwrap();
+    assert_eq!(request.query, "{ a }");
+    let json = InvokeBody::Json(json!({ "query": "{ b }" }));
+    let request: Request = decode(&json, &HeaderMap::new()).unwrap();
+    assert_eq!(request.query, "{ b }");
+
+    let unsupported = headers(&[(CONTENT_TYPE_HEADER, "text/plain")]);
+    assert!(matches!(
+      decode::<Request>(&body, &unsupported),
+      Err(CodecError::UnsupportedContentType(mime)) if mime == "text/plain"
+    ));
+  }
+
+  #[test]
+  fn responses_follow_the_request() {
+    let json = InvokeBody::Json(json!({}));
+    let raw = InvokeBody::Raw(Vec::new());
+    let none = HeaderMap::new();
+    assert_eq!(response_codec(&json, &none).unwrap(), None);
+    assert_eq!(response_codec(&raw, &none).unwrap(), Some(Codec::Json));
+    let accept = headers(&[(ACCEPT_HEADER, "application/json")]);
+    assert_eq!(response_codec(&json, &accept).unwrap(), Some(Codec::Json));
+    let multipart = headers(&[(CONTENT_TYPE_HEADER, "multipart/form-data; boundary=x")]);
+    assert_eq!(response_codec(&raw, &multipart).unwrap(), None);
+    assert_eq!(ResponseMode::Body.codec(), Some(Codec::Json));
+    assert_eq!(ResponseMode::Tuple.codec(), None);
+  }
+
+  #[test]
+  fn the_graphql_command_answers_in_the_response_mode() {
+    // The tuple is the default.
+    let InvokeResponseBody::Json(tuple) = invoke_graphql(ResponseMode::default()) else {
+      panic!("a json body");
+    };
+    let (body, is_ok): (String, bool) = serde_json::from_str(&tuple).unwrap();
+    assert!(is_ok);
+    assert_eq!(
+      serde_json::from_str::<serde_json::Value>(&body).unwrap(),
+      json!({ "data": { "hello": "world" } })
+    );
+
+    let InvokeResponseBody::Json(body) = invoke_graphql(ResponseMode::Body) else {
+      panic!("a json body");
+    };
+    assert_eq!(
+      serde_json::from_str::<serde_json::Value>(&body).unwrap(),
+      json!({ "data": { "hello": "world" } })
+    );
+  }
+
+  #[test]
+  fn json_bodies_are_sent_as_is() {
+    let InvokeResponseBody::Json(body) = Codec::Json.encode_body(&json!({ "a": 1 })).unwrap()
+    else {
+      panic!("a json body");
+    };
+    assert_eq!(body, r#"{"a":1}"#);
+  }
+
+  #[tokio::test]
+  async fn multipart_requests_carry_their_files() {
+    let body = [
+      "--x",
+      "Content-Disposition: form-data; name=\"operations\"",
+      "",
+      r#"{ "query": "mutation ($file: Upload!) { upload(file: $file) }", "variables": { "file": null } }"#,
+      "--x",
+      "Content-Disposition: form-data; name=\"map\"",
+      "",
+      r#"{ "0": ["variables.file"] }"#,
+      "--x",
+      "Content-Disposition: form-data; name=\"0\"; filename=\"a.txt\"",
+      "Content-Type: text/plain",
+      "",
+      "hello",
+      "--x--",
+      "",
+    ]
+    .join("\r\n");
+    let headers = headers(&[(CONTENT_TYPE_HEADER, "multipart/form-data; boundary=x")]);
+    let batch = decode_batch_request(
+      &InvokeBody::Raw(body.into_bytes()),
+      &headers,
+      MultipartOptions::default(),
+    )
+    .await
+    .unwrap();
+    let BatchRequest::Single(request) = batch else {
+      panic!("a single request");
+    };
+    assert_eq!(request.uploads.len(), 1);
+    assert_eq!(request.uploads[0].filename, "a.txt");
+  }
+}
@@ -0,0 +1,265 @@
+use std::{collections::HashMap, collections::VecDeque, time::Duration};
+
+use async_graphql::{
+  futures_util::{future, stream::BoxStream, StreamExt},
+  parser::types::OperationType,
+  Request,
+};
+use tokio::time::Instant;
+
+use crate::operation::{operation, root_fields};
+
+/// How many results the policies without a buffer keep waiting.
+const DEFAULT_CAPACITY: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Overflow {
+  DropOldest,
+  DropNewest,
+}
+
+/// How the results of a subscription are paced on their way to the webview.
+///
+/// Results wait in a buffer until they are sent. It fills up when the
+/// subscription produces results faster than they are delivered, which the
+/// minimum interval between two results bounds.
+///
+/// The results are sent to the webview as soon as they leave the buffer, so
+/// without a minimum interval they hardly ever wait: the overflow of
+/// [`DeliveryPolicy::drop_oldest`], [`DeliveryPolicy::drop_newest`] and
+/// [`DeliveryPolicy::latest`] only drops results when combined with
+/// [`DeliveryPolicy::with_min_interval`].
+///
+/// ```
+/// use std::time::Duration;
+/// use mizuki::DeliveryPolicy;
+///
+/// // At most 20 results a second, only the last one of each window.
+/// let policy = DeliveryPolicy::latest().with_min_interval(Duration::from_millis(50));
+/// ```
+///
+/// The default policy sends every result as soon as it is produced.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct DeliveryPolicy {
+  buffer: Option<(usize, Overflow)>,
+  min_interval: Duration,
+}
+
+impl DeliveryPolicy {
+  /// Keep at most `capacity` results waiting, dropping the oldest ones.
+  ///
+  /// Results only wait with a [`Self::with_min_interval`].
+  pub fn drop_oldest(capacity: usize) -> Self {
+    Self {
+      buffer: Some((capacity.max(1), Overflow::DropOldest)),
+      ..Self::default()
+    }
+  }
+  /// Keep at most `capacity` results waiting, dropping the new ones.
+  ///
+  /// Results only wait with a [`Self::with_min_interval`].
+  pub fn drop_newest(capacity: usize) -> Self {
+    Self {
+      buffer: Some((capacity.max(1), Overflow::DropNewest)),
+      ..Self::default()
+    }
+  }
+  /// Only keep the latest result waiting.
+  ///
+  /// Results only wait with a [`Self::with_min_interval`].
+  pub fn latest() -> Self {
+    Self::drop_oldest(1)
+  }
+  /// Send the results at least `interval` apart.
+  ///
+  /// At most 64 results are kept waiting, the oldest ones being dropped.
+  pub fn min_interval(interval: Duration) -> Self {
+    Self::default().with_min_interval(interval)
+  }
+  /// Send the results at least `interval` apart.
+  #[must_use]
+  pub fn with_min_interval(mut self, interval: Duration) -> Self {
+    self.min_interval = interval;
+    self
+  }
+}
+
+/// The delivery policies of the subscriptions.
+#[derive(Default)]
+pub(crate) struct DeliveryPolicies {
+  pub(crate) default: DeliveryPolicy,
+  /// Keyed by subscription root field name.
+  pub(crate) fields: HashMap<String, DeliveryPolicy>,
+}
+
+impl DeliveryPolicies {
+  /// The policy of a request.
+  ///
+  /// Only subscriptions are paced, the results of the other operations all matter.
+  pub(crate) fn of(&self, request: &mut Request) -> DeliveryPolicy {
+    match operation(request) {
+      Some((document, operation)) if operation.ty == OperationType::Subscription => {
+        root_fields(document, operation)
+          .into_iter()
+          .find_map(|field| self.fields.get(field).copied())
+          .unwrap_or(self.default)
+      }
+      _ => DeliveryPolicy::default(),
+    }
+  }
+}
+
+struct Pace<T> {
+  stream: Option<BoxStream<'static, T>>,
+  buffer: VecDeque<T>,
+  next_send: Instant,
+}
+
+/// Buffer the results of a stream, and release them according to `policy`.
+pub(crate) fn pace<T: Send + 'static>(
+  stream: BoxStream<'static, T>,
+  policy: DeliveryPolicy,
+) -> BoxStream<'static, T> {
+  if policy == DeliveryPolicy::default() {
+    return stream;
+  }
+  let state = Pace {
+    stream: Some(stream),
+    buffer: VecDeque::new(),
+    next_send: Instant::now(),
+  };
+  async_graphql::futures_util::stream::unfold(state, move |mut state| async move {
+    loop {
+      if state.stream.is_none() && state.buffer.is_empty() {
+        return None;
+      }
+      let ready = !state.buffer.is_empty();
+      tokio::select! {
+        item = next(&mut state.stream) => match item {
+          Some(item) => push(&mut state.buffer, item, policy),
+          None => state.stream = None,
+        },
+        _ = tokio::time::sleep_until(state.next_send), if ready => {
+          let item = state.buffer.pop_front()?;
+          state.next_send = Instant::now() + policy.min_interval;
+          return Some((item, state));
+        }
+      }
+    }
+  })
+  .boxed()
+}
+
+/// The next item of a stream, never if it ended.
+async fn next<T>(stream: &mut Option<BoxStream<'static, T>>) -> Option<T> {
+  match stream {
+    Some(stream) => stream.next().await,
+    None => future::pending().await,
+  }
+}
+
+fn push<T>(buffer: &mut VecDeque<T>, item: T, policy: DeliveryPolicy) {
+  match policy
+    .buffer
+    .unwrap_or((DEFAULT_CAPACITY, Overflow::DropOldest))
+  {
+    (capacity, Overflow::DropOldest) if buffer.len() >= capacity => {
+      buffer.pop_front();
+      buffer.push_back(item);
+    }
+    (capacity, Overflow::DropNewest) if buffer.len() >= capacity => {}
+    _ => buffer.push_back(item),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use async_graphql::futures_util::stream;
+
+  use super::*;
+
+  fn buffered(policy: DeliveryPolicy, items: std::ops::Range<usize>) -> Vec<usize> {
+    let mut buffer = VecDeque::new();
+    for item in items {
+      push(&mut buffer, item, policy);
+    }
+    buffer.into()
+  }
+
+  #[test]
+  fn overflows() {
+    assert_eq!(buffered(DeliveryPolicy::drop_oldest(3), 0..10), [7, 8, 9]);
+    assert_eq!(buffered(DeliveryPolicy::drop_newest(3), 0..10), [0, 1, 2]);
+    assert_eq!(buffered(DeliveryPolicy::latest(), 0..10), [9]);
+    assert_eq!(buffered(DeliveryPolicy::drop_oldest(0), 0..10), [9]);
+  }
+
+  #[test]
+  fn min_interval_is_bounded() {
+    let policy = DeliveryPolicy::min_interval(Duration::from_secs(1));
+    let buffer = buffered(policy, 0..100);
+    assert_eq!(buffer.len(), DEFAULT_CAPACITY);
+    assert_eq!(buffer[0], 100 - DEFAULT_CAPACITY);
+  }
+
+  /// The items `0..count`, one every 10ms.
+  fn ticks(count: usize) -> BoxStream<'static, usize> {
+    stream::iter(0..count)
+      .then(|item| async move {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        item
+      })
+      .boxed()
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn default_policy_sends_everything() {
+    let items: Vec<_> = pace(ticks(5), DeliveryPolicy::default()).collect().await;
+    assert_eq!(items, [0, 1, 2, 3, 4]);
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn overflows_need_a_min_interval() {
+    // Nothing waits without an interval, so nothing is dropped.
+    let items: Vec<_> = pace(ticks(5), DeliveryPolicy::latest()).collect().await;
+    assert_eq!(items, [0, 1, 2, 3, 4]);
+
+    // A burst may wait for its turn, but the buffer stays bounded.
+    for policy in [
+      DeliveryPolicy::drop_oldest(4),
+      DeliveryPolicy::drop_newest(4),
+    ] {
+      let items: Vec<_> = pace(stream::iter(0..1000).boxed(), policy).collect().await;
+      assert!(
+        items.windows(2).all(|pair| pair[0] < pair[1]),
+        "{:?}",
+        policy
+      );
+      assert!(!items.is_empty() && items.len() <= 1000);
+    }
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn latest_with_min_interval() {
+    let policy = DeliveryPolicy::latest().with_min_interval(Duration::from_millis(45));
+    let start = Instant::now();
+    let items: Vec<_> = pace(ticks(8), policy)
+      .map(|item| (item, (Instant::now() - start).as_millis()))
+      .collect()
+      .await;
+    assert_eq!(items, [(0, 10), (4, 55), (7, 100)]);
+  }
+
+  #[test]
+  fn policies_by_root_field() {
+    let latest = DeliveryPolicy::latest();
+    let policies = DeliveryPolicies {
+      default: DeliveryPolicy::drop_newest(2),
+      fields: HashMap::from([("prices".to_string(), latest)]),
+    };
+    let of = |query: &str| policies.of(&mut Request::new(query));
+    assert_eq!(of("subscription { prices }"), latest);
+    assert_eq!(of("subscription { news }"), DeliveryPolicy::drop_newest(2));
+    assert_eq!(of("query { prices }"), DeliveryPolicy::default());
+  }
+}
@@ -0,0 +1,292 @@
+use std::time::Duration;
+
+use async_graphql::{ErrorExtensionValues, ServerError};
+use serde::{ser::SerializeMap, Serialize, Serializer};
+
+use crate::codec::CodecError;
+
+/// The failures the plugin reports to the webview.
+///
+/// The commands reject with it (through [`tauri::ipc::InvokeError`]) serialized
+/// as an object holding a stable `code`,
+/// the `message`, and the details of the variant, like the `cause` of a failure:
+///
+/// ```js
+/// try {
+///   await invoke('plugin:todo-plugin|graphql', { query: '{ list { id } }' })
+/// } catch (error) {
+///   if (error.code === 'DECODE_FAILED') {
+///     // ...
+///   }
+/// }
+/// ```
+///
+/// The failures of an operation, like [`Error::Timeout`], are rather GraphQL
+/// errors of its response, with the code in their `extensions`.
+///
+/// The messages are lowercase, but for the persisted query ones the clients
+/// look for as they are.
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum Error {
+  /// A command was invoked before the plugin was initialized.
+  #[error("the plugin isn't initialized")]
+  NotInitialized,
+  /// The payload of a command couldn't be decoded.
+  #[error("failed to decode the payload")]
+  DecodeFailed(#[source] CodecError),
+  /// A response couldn't be encoded.
+  #[error("failed to encode the response")]
+  EncodeFailed(#[source] CodecError),
+  /// The plugin has no such command.
+  #[error(
+    "unknown endpoint \"{0}\", the endpoints are \"graphql\", \"subscriptions\" and \"transport\""
+  )]
+  UnknownEndpoint(String),
+  /// The plugin delivers subscriptions through channels, but none was given.
+  #[error("the plugin requires a subscription channel")]
+  MissingChannel,
+  /// The subscription was delivered through events, but without their id.
+  #[error("missing the subscription event id")]
+  MissingEventId,
+  /// A subscription result couldn't be sent to the webview.
+  #[error("failed to emit to the webview")]
+  EmitFailed(#[source] tauri::Error),
+  /// The operation was cancelled by the webview.
+  #[error("request cancelled")]
+  Cancelled,
+  /// The operation ran out of time.
+  #[error("operation timed out after {}ms", .0.as_millis())]
+  Timeout(Duration),
+  /// The request goes over one of the limits of the plugin.
+  #[error("{limit} limit of {max} exceeded")]
+  LimitExceeded {
+    /// The name of the limit.
+    limit: &'static str,
+    max: usize,
+  },
+  /// The current page of the webview isn't allowed to run the operation.
+  #[error("a {operation} isn't allowed from {url}")]
+  AccessDenied {
+    /// `query`, `mutation` or `subscription`.
+    operation: &'static str,
+    /// The URL of the page, without its query and fragment.
+    url: String,
+  },
+  /// The capabilities of the webview don't grant a root field of the operation.
+  #[error("the {operation} root field {field} isn't granted to the webview")]
+  PermissionDenied {
+    /// `query`, `mutation` or `subscription`.
+    operation: &'static str,
+    field: String,
+  },
+  /// The webview doesn't hold the capability a field requires,
+  /// see [`crate::guard::RequiresCapability`].
+  #[error("the {capability} capability is required")]
+  MissingCapability { capability: String },
+  /// A subscriber of a shared subscription fell behind, and missed some of its results.
+  #[error("missed {missed} results of the subscription")]
+  Lagged { missed: u64 },
+  /// The request uses a directive the plugin can't honour, like `@stream`.
+  #[error("the @{directive} directive is not supported")]
+  UnsupportedDirective { directive: &'static str },
+  /// The plugin doesn't know the hash of a persisted query yet, the client has
+  /// to send the query along with it.
+  #[error("PersistedQueryNotFound")]
+  PersistedQueryNotFound,
+  /// The plugin doesn't store persisted queries, the client has to send the query.
+  #[error("PersistedQueryNotSupported")]
+  PersistedQueryNotSupported,
+  /// The `persistedQuery` extension of a request is malformed, or its hash
+  /// doesn't match the query.
+  #[error("invalid persisted query: {0}")]
+  InvalidPersistedQuery(&'static str),
+  /// The operation isn't one of the trusted documents of the plugin.
+  #[error("untrusted document")]
+  UntrustedDocument,
+}
+
+impl Error {
+  /// The stable code of the error.
+  pub fn code(&self) -> &'static str {
+    match self {
+      Self::NotInitialized => "NOT_INITIALIZED",
+      Self::DecodeFailed(_) => "DECODE_FAILED",
+      Self::EncodeFailed(_) => "ENCODE_FAILED",
+      Self::UnknownEndpoint(_) => "UNKNOWN_ENDPOINT",
+      Self::MissingChannel => "MISSING_CHANNEL",
+      Self::MissingEventId => "MISSING_EVENT_ID",
+      Self::EmitFailed(_) => "EMIT_FAILED",
+      Self::Cancelled => "CANCELLED",
+      Self::Timeout(_) => "TIMEOUT",
+      Self::LimitExceeded { .. } => "LIMIT_EXCEEDED",
+      Self::AccessDenied { .. } => "ACCESS_DENIED",
+      Self::PermissionDenied { .. } => "PERMISSION_DENIED",
+      Self::MissingCapability { .. } => "MISSING_CAPABILITY",
+      Self::Lagged { .. } => "LAGGED",
+      Self::UnsupportedDirective { .. } => "UNSUPPORTED_DIRECTIVE",
+      Self::PersistedQueryNotFound => "PERSISTED_QUERY_NOT_FOUND",
+      Self::PersistedQueryNotSupported => "PERSISTED_QUERY_NOT_SUPPORTED",
+      Self::InvalidPersistedQuery(_) => "BAD_USER_INPUT",
+      Self::UntrustedDocument => "UNTRUSTED_DOCUMENT",
+    }
+  }
+
+  /// The details of the error, next to its code and message.
+  fn details(&self) -> Vec<(&'static str, serde_json::Value)> {
+    match self {
+      Self::DecodeFailed(cause) | Self::EncodeFailed(cause) => {
+        vec![("cause", cause.to_string().into())]
+      }
+      Self::EmitFailed(cause) => vec![("cause", cause.to_string().into())],
+      Self::UnknownEndpoint(endpoint) => vec![("endpoint", endpoint.as_str().into())],
+      Self::Timeout(timeout) => vec![("timeout", (timeout.as_millis() as u64).into())],
+      Self::LimitExceeded { limit, max } => {
+        vec![("limit", (*limit).into()), ("max", (*max).into())]
+      }
+      Self::AccessDenied { operation, url } => {
+        vec![
+          ("operation", (*operation).into()),
+          ("url", url.as_str().into()),
+        ]
+      }
+      Self::PermissionDenied { operation, field } => vec![
+        ("operation", (*operation).into()),
+        ("field", field.as_str().into()),
+      ],
+      Self::MissingCapability { capability } => vec![("capability", capability.as_str().into())],
+      Self::Lagged { missed } => vec![("missed", (*missed).into())],
+      Self::UnsupportedDirective { directive } => vec![("directive", (*directive).into())],
+      _ => Vec::new(),
+    }
+  }
+}
+
+impl Serialize for Error {
+  fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    let details = self.details();
+    let mut map = serializer.serialize_map(Some(2 + details.len()))?;
+    map.serialize_entry("code", self.code())?;
+    map.serialize_entry("message", &self.to_string())?;
+    for (key, value) in &details {
+      map.serialize_entry(key, value)?;
+    }
+    map.end()
+  }
+}
+
+impl From<Error> for ServerError {
+  fn from(error: Error) -> Self {
+    let mut server_error = ServerError::new(error.to_string(), None);
+    let mut extensions = ErrorExtensionValues::default();
+    extensions.set("code", error.code());
+    for (key, value) in error.details() {
+      if let Ok(value) = async_graphql::Value::from_json(value) {
+        extensions.set(key, value);
+      }
+    }
+    server_error.extensions = Some(extensions);
+    server_error
+  }
+}
+
+impl From<tauri::Error> for Error {
+  fn from(error: tauri::Error) -> Self {
+    Self::EmitFailed(error)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::error::Error as _;
+
+  use serde_json::json;
+
+  use super::*;
+
+  #[test]
+  fn errors_serialize_with_their_code_and_details() {
+    let error = Error::LimitExceeded {
+      limit: "depth",
+      max: 5,
+    };
+    assert_eq!(
+      serde_json::to_value(&error).unwrap(),
+      json!({ "code": "LIMIT_EXCEEDED", "message": "depth limit of 5 exceeded", "limit": "depth", "max": 5 })
+    );
+    assert_eq!(
+      serde_json::to_value(Error::Timeout(Duration::from_millis(1500))).unwrap(),
+      json!({ "code": "TIMEOUT", "message": "operation timed out after 1500ms", "timeout": 1500 })
+    );
+    assert_eq!(
+      serde_json::to_value(Error::Cancelled).unwrap(),
+      json!({ "code": "CANCELLED", "message": "request cancelled" })
+    );
+    assert_eq!(
+      serde_json::to_value(Error::NotInitialized).unwrap(),
+      json!({ "code": "NOT_INITIALIZED", "message": "the plugin isn't initialized" })
+    );
+  }
+
+  #[test]
+  fn the_cause_is_the_source_and_a_detail() {
+    let cause = CodecError::UnsupportedContentType("text/plain".into());
+    let error = Error::DecodeFailed(cause);
+    assert_eq!(error.to_string(), "failed to decode the payload");
+    assert_eq!(
+      error.source().unwrap().to_string(),
+      "unsupported content type: text/plain"
+    );
+    assert_eq!(
+      serde_json::to_value(&error).unwrap(),
+      json!({
+        "code": "DECODE_FAILED",
+        "message": "failed to decode the payload",
+        "cause": "unsupported content type: text/plain",
+      })
+    );
+  }
+
+  #[test]
+  fn graphql_errors_carry_the_code_in_their_extensions() {
+    let error = ServerError::from(Error::AccessDenied {
+      operation: "mutation",
+      url: "https://example.com/".into(),
+    });
+    assert_eq!(
+      error.message,
+      "a mutation isn't allowed from https://example.com/"
+    );
+    let extensions = error.extensions.unwrap();
+    assert_eq!(
+      extensions.get("code"),
+      Some(&async_graphql::Value::from("ACCESS_DENIED"))
+    );
+    assert_eq!(
+      extensions.get("operation"),
+      Some(&async_graphql::Value::from("mutation"))
+    );
+    assert_eq!(
+      extensions.get("url"),
+      Some(&async_graphql::Value::from("https://example.com/"))
+    );
+  }
+
+  #[test]
+  fn persisted_query_errors_keep_the_messages_clients_look_for() {
+    let error = ServerError::from(Error::PersistedQueryNotFound);
+    assert_eq!(error.message, "PersistedQueryNotFound");
+    assert_eq!(
+      error.extensions.unwrap().get("code"),
+      Some(&async_graphql::Value::from("PERSISTED_QUERY_NOT_FOUND"))
+    );
+    assert_eq!(
+      Error::PersistedQueryNotSupported.to_string(),
+      "PersistedQueryNotSupported"
+    );
+    assert_eq!(
+      Error::InvalidPersistedQuery("unsupported version").code(),
+      "BAD_USER_INPUT"
+    );
+  }
+}
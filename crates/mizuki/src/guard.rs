@@ -0,0 +1,156 @@
+//! Field-level authorization, by the capabilities of the webview sending the operation.
+//!
+//! A webview holds a capability when one of its tauri capabilities includes the
+//! `<plugin>:grant-<name>` permission generated by `mizuki_build::OperationPermissions::capability`,
+//! or when the [`crate::Builder::roles`] callback gives it one.
+//!
+//! The fields requiring one are guarded by [`RequiresCapability`], and marked in the SDL
+//! with the `@requiresCapability` directive:
+//!
+//! ```rust,ignore
+//! use mizuki::guard::{requiresCapability, RequiresCapability};
+//!
+//! #[Object]
+//! impl User {
+//!   async fn name(&self) -> &str {
+//!     &self.name
+//!   }
+//!   #[graphql(
+//!     guard = "RequiresCapability::new(\"admin\")",
+//!     directive = requiresCapability::apply("admin".into())
+//!   )]
+//!   async fn email(&self) -> &str {
+//!     &self.email
+//!   }
+//! }
+//! ```
+//!
+//! A guarded field the webview can't access is left out of the response,
+//! with a `MISSING_CAPABILITY` error.
+
+use std::collections::BTreeSet;
+
+use async_graphql::{Context, Guard, TypeDirective};
+
+use crate::{AsyncGQLContextExt, Error};
+
+/// The capabilities held by the webview sending an operation.
+///
+/// They are in the data of its requests, see [`AsyncGQLContextExt::capabilities`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Capabilities(BTreeSet<String>);
+
+impl Capabilities {
+  pub fn contains(&self, capability: &str) -> bool {
+    self.0.contains(capability)
+  }
+
+  pub fn iter(&self) -> impl Iterator<Item = &str> {
+    self.0.iter().map(String::as_str)
+  }
+}
+
+impl<S: Into<String>> FromIterator<S> for Capabilities {
+  fn from_iter<I: IntoIterator<Item = S>>(iter: I) -> Self {
+    Self(iter.into_iter().map(Into::into).collect())
+  }
+}
+
+/// Only resolves a field for the webviews holding a capability.
+#[derive(Debug, Clone)]
+pub struct RequiresCapability {
+  capability: String,
+}
+
+impl RequiresCapability {
+  pub fn new(capability: impl Into<String>) -> Self {
+    Self {
+      capability: capability.into(),
+    }
+  }
+}
+
+impl Guard for RequiresCapability {
+  async fn check(&self, ctx: &Context<'_>) -> async_graphql::Result<()> {
+    let held = ctx
+      .capabilities()
+      .is_some_and(|capabilities| capabilities.contains(&self.capability));
+    if held {
+      return Ok(());
+    }
+    let error = async_graphql::ServerError::from(Error::MissingCapability {
+      capability: self.capability.clone(),
+    });
+    Err(async_graphql::Error {
+      message: error.message,
+      source: None,
+      extensions: error.extensions,
+    })
+  }
+}
+
+/// Marks in the SDL the fields and types guarded by [`RequiresCapability`].
+#[TypeDirective(location = "FieldDefinition", location = "Object")]
+pub fn requiresCapability(name: String) {}
+
+#[cfg(test)]
+mod tests {
+  use async_graphql::{EmptyMutation, EmptySubscription, Object, Request, Schema, Value};
+
+  use super::*;
+
+  struct Query;
+
+  #[Object]
+  impl Query {
+    async fn name(&self) -> &str {
+      "name"
+    }
+    #[graphql(
+      guard = "RequiresCapability::new(\"admin\")",
+      directive = requiresCapability::apply("admin".into())
+    )]
+    async fn email(&self) -> &str {
+      "email"
+    }
+  }
+
+  fn schema() -> Schema<Query, EmptyMutation, EmptySubscription> {
+    Schema::new(Query, EmptyMutation, EmptySubscription)
+  }
+
+  #[tokio::test]
+  async fn guarded_fields_require_the_capability() {
+    let request = || Request::new("{ name email }");
+    let granted = schema()
+      .execute(request().data(Capabilities::from_iter(["admin"])))
+      .await;
+    assert!(granted.errors.is_empty());
+
+    for request in [request(), request().data(Capabilities::from_iter(["user"]))] {
+      let response = schema().execute(request).await;
+      assert_eq!(response.errors.len(), 1);
+      let extensions = response.errors[0].extensions.as_ref().unwrap();
+      assert_eq!(
+        extensions.get("code"),
+        Some(&Value::from("MISSING_CAPABILITY"))
+      );
+      assert_eq!(extensions.get("capability"), Some(&Value::from("admin")));
+    }
+  }
+
+  #[test]
+  fn the_directive_marks_the_sdl() {
+    assert!(schema()
+      .sdl()
+      .contains("email: String! @requiresCapability(name: \"admin\")"));
+  }
+
+  #[test]
+  fn capabilities_are_sorted_and_unique() {
+    let capabilities = Capabilities::from_iter(["b", "a", "b"]);
+    assert_eq!(capabilities.iter().collect::<Vec<_>>(), ["a", "b"]);
+    assert!(capabilities.contains("a"));
+    assert!(!capabilities.contains("c"));
+  }
+}
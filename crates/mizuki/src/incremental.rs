@@ -0,0 +1,794 @@
+//! Incremental delivery of the `@defer` directive.
+//!
+//! async-graphql doesn't know this directive, so a query using it is split
+//! before its execution, and only by the `subscriptions` command.
+//! The initial request doesn't select the deferred fragments anymore, and each
+//! of them becomes a request of its own, selecting the fields that lead to it.
+//! All the requests run concurrently, and the results of the deferred ones are
+//! sent as patches once the initial payload is out.
+//!
+//! This is not a single execution: the fields on the path of a deferred fragment
+//! are resolved once more for each of its requests, side effects included, and
+//! the patches may see a different state than the initial payload.
+//! The other commands reject `@defer`, rather than silently returning all the fields at once.
+//!
+//! `@stream` can't be emulated this way, a list being resolved in one go,
+//! so the requests using it are rejected by every command.
+
+use std::collections::{HashMap, HashSet};
+
+use async_graphql::{
+  futures_util::{
+    future::BoxFuture,
+    stream::{self, BoxStream, FuturesUnordered},
+    FutureExt, StreamExt,
+  },
+  parser::{
+    types::{
+      Directive, DocumentOperations, ExecutableDocument, Field, FragmentDefinition, InlineFragment,
+      OperationDefinition, OperationType, Selection, SelectionSet,
+    },
+    Pos, Positioned,
+  },
+  Name, ObjectType, PathSegment, Request, Response, Schema, ServerError, SubscriptionType, Value,
+};
+use serde::Serialize;
+
+use crate::Error;
+
+const DEFER: &str = "defer";
+const STREAM: &str = "stream";
+
+/// A result of an incrementally delivered query.
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub(crate) enum IncrementalResponse {
+  Initial {
+    #[serde(flatten)]
+    response: Response,
+    #[serde(rename = "hasNext")]
+    has_next: bool,
+  },
+  Subsequent {
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    incremental: Vec<IncrementalPayload>,
+    #[serde(rename = "hasNext")]
+    has_next: bool,
+  },
+}
+
+/// The data of a deferred fragment, to merge at `path`.
+#[derive(Debug, Serialize)]
+pub(crate) struct IncrementalPayload {
+  data: Value,
+  path: Vec<PathSegment>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  label: Option<String>,
+  #[serde(skip_serializing_if = "Vec::is_empty")]
+  errors: Vec<ServerError>,
+}
+
+impl IncrementalResponse {
+  pub(crate) fn has_errors(&self) -> bool {
+    match self {
+      Self::Initial { response, .. } => response.is_err(),
+      Self::Subsequent { incremental, .. } => {
+        incremental.iter().any(|payload| !payload.errors.is_empty())
+      }
+    }
+  }
+}
+
+impl IncrementalPayload {
+  pub(crate) fn errors_mut(&mut self) -> &mut Vec<ServerError> {
+    &mut self.errors
+  }
+}
+
+/// A deferred fragment.
+struct Deferred {
+  /// The fields and fragments leading to the fragment, without their selections.
+  ancestors: Vec<Positioned<Selection>>,
+  fragment: Positioned<Selection>,
+  label: Option<String>,
+}
+
+impl Deferred {
+  /// The response keys of the fields leading to the fragment.
+  fn keys(&self) -> Vec<Name> {
+    self
+      .ancestors
+      .iter()
+      .filter_map(|ancestor| match &ancestor.node {
+        Selection::Field(field) => Some(field.node.response_key().node.clone()),
+        _ => None,
+      })
+      .collect()
+  }
+
+  /// Turn the result of its request into patches, one for each object the fragment applies to.
+  fn patches(&self, response: Response) -> Vec<IncrementalPayload> {
+    let keys = self.keys();
+    let mut located = Vec::new();
+    locate(response.data, &keys, Vec::new(), &mut located);
+    let mut patches: Vec<_> = located
+      .into_iter()
+      .filter(|(_, data)| !matches!(data, Value::Object(object) if object.is_empty()))
+      .map(|(path, data)| IncrementalPayload {
+        data,
+        path,
+        label: self.label.clone(),
+        errors: Vec::new(),
+      })
+      .collect();
+    if !response.errors.is_empty() {
+      match patches.first_mut() {
+        Some(patch) => patch.errors = response.errors,
+        None => patches.push(IncrementalPayload {
+          data: Value::Null,
+          path: keys
+            .into_iter()
+            .map(|key| PathSegment::Field(key.to_string()))
+            .collect(),
+          label: self.label.clone(),
+          errors: response.errors,
+        }),
+      }
+    }
+    patches
+  }
+}
+
+/// A query split along its `@defer` directives.
+pub(crate) struct Plan {
+  initial: Request,
+  deferred: Vec<(Deferred, Request)>,
+}
+
+impl Plan {
+  /// Split the request if it is a query using `@defer`.
+  ///
+  /// Any other request is left without the directive, to run in one go.
+  pub(crate) fn new(request: &mut Request) -> Option<Self> {
+    if !uses_directives(request) {
+      return None;
+    }
+    let document = request.parsed_query().ok()?.clone();
+    let plan = select_operation(&document, request.operation_name.as_deref())
+      .filter(|(_, operation)| operation.node.ty == OperationType::Query)
+      .and_then(|(name, mut operation)| {
+        inline_fragments(
+          &mut operation.node.selection_set.node,
+          &document.fragments,
+          &mut Vec::new(),
+        )?;
+        Self::split(request, name, operation)
+      });
+    if plan.is_none() {
+      strip(request);
+    }
+    plan
+  }
+
+  fn split(
+    request: &Request,
+    name: Option<Name>,
+    mut operation: Positioned<OperationDefinition>,
+  ) -> Option<Self> {
+    let variables = variable_values(request, &operation.node);
+    let mut deferred = Vec::new();
+    split(
+      &mut operation.node.selection_set.node,
+      &mut Vec::new(),
+      &variables,
+      &mut deferred,
+    );
+    if deferred.is_empty() {
+      return None;
+    }
+    if operation.node.selection_set.node.items.is_empty() {
+      operation
+        .node
+        .selection_set
+        .node
+        .items
+        .push(typename(operation.pos));
+    }
+
+    let deferred = deferred
+      .into_iter()
+      .map(|deferred| {
+        let mut selection = deferred.fragment.clone();
+        for ancestor in deferred.ancestors.iter().rev() {
+          let mut ancestor = ancestor.clone();
+          selection_set_mut(&mut ancestor.node)
+            .expect("ancestors have a selection set")
+            .items = vec![selection];
+          selection = ancestor;
+        }
+        let mut operation = operation.clone();
+        operation.node.selection_set.node.items = vec![selection];
+        let request = derive_request(request, document_of(name.clone(), operation));
+        (deferred, request)
+      })
+      .collect();
+    let initial = derive_request(request, document_of(name, operation));
+    Some(Self { initial, deferred })
+  }
+
+  /// Execute the requests, with `prepare` applied to each of them.
+  pub(crate) fn execute<Q, M, S>(
+    self,
+    schema: Schema<Q, M, S>,
+    prepare: impl Fn(Request) -> Request,
+  ) -> BoxStream<'static, IncrementalResponse>
+  where
+    Q: ObjectType + 'static,
+    M: ObjectType + 'static,
+    S: SubscriptionType + 'static,
+  {
+    let pending: FuturesUnordered<BoxFuture<'static, (Option<usize>, Response)>> =
+      FuturesUnordered::new();
+    let (deferred, requests): (Vec<_>, Vec<_>) = self.deferred.into_iter().unzip();
+    let initial = prepare(self.initial);
+    pending.push({
+      let schema = schema.clone();
+      async move { (None, schema.execute(initial).await) }.boxed()
+    });
+    for (index, request) in requests.into_iter().enumerate() {
+      let request = prepare(request);
+      let schema = schema.clone();
+      pending.push(async move { (Some(index), schema.execute(request).await) }.boxed());
+    }
+
+    struct State {
+      pending: FuturesUnordered<BoxFuture<'static, (Option<usize>, Response)>>,
+      deferred: Vec<Deferred>,
+      initial_sent: bool,
+      buffer: Vec<IncrementalPayload>,
+    }
+
+    stream::unfold(
+      State {
+        pending,
+        deferred,
+        initial_sent: false,
+        buffer: Vec::new(),
+      },
+      |mut state| async move {
+        loop {
+          if state.initial_sent && !state.buffer.is_empty() {
+            let has_next = !state.pending.is_empty();
+            let incremental = std::mem::take(&mut state.buffer);
+            return Some((
+              IncrementalResponse::Subsequent {
+                incremental,
+                has_next,
+              },
+              state,
+            ));
+          }
+          match state.pending.next().await? {
+            (None, response) => {
+              state.initial_sent = true;
+              if response.data == Value::Null {
+                // The query failed as a whole, the deferred requests will fail the same way.
+                state.pending = FuturesUnordered::new();
+                state.buffer.clear();
+              }
+              let has_next = !state.pending.is_empty() || !state.buffer.is_empty();
+              return Some((IncrementalResponse::Initial { response, has_next }, state));
+            }
+            (Some(index), response) => {
+              let patches = state.deferred[index].patches(response);
+              state.buffer.extend(patches);
+              if state.initial_sent && state.buffer.is_empty() && state.pending.is_empty() {
+                return Some((
+                  IncrementalResponse::Subsequent {
+                    incremental: Vec::new(),
+                    has_next: false,
+                  },
+                  state,
+                ));
+              }
+            }
+          }
+        }
+      },
+    )
+    .boxed()
+  }
+}
+
+/// Reject the requests using `@stream`, which isn't supported, and the ones using
+/// `@defer` unless they are `deferrable`, sent to the command delivering them incrementally.
+pub(crate) fn check(request: &mut Request, deferrable: bool) -> Result<(), ServerError> {
+  let unsupported: &[&'static str] = if deferrable {
+    &[STREAM]
+  } else {
+    &[STREAM, DEFER]
+  };
+  for &directive in unsupported {
+    if !request.query.contains(&format!("@{}", directive)) {
+      continue;
+    }
+    let Ok(document) = request.parsed_query() else {
+      return Ok(());
+    };
+    let uses = |selection_set: &SelectionSet| uses_directive(selection_set, directive);
+    let used = match &document.operations {
+      DocumentOperations::Single(operation) => uses(&operation.node.selection_set.node),
+      DocumentOperations::Multiple(operations) => operations
+        .values()
+        .any(|operation| uses(&operation.node.selection_set.node)),
+    } || document
+      .fragments
+      .values()
+      .any(|fragment| uses(&fragment.node.selection_set.node));
+    if used {
+      return Err(Error::UnsupportedDirective { directive }.into());
+    }
+  }
+  Ok(())
+}
+
+fn uses_directive(selection_set: &SelectionSet, name: &str) -> bool {
+  selection_set.items.iter().any(|item| {
+    item
+      .node
+      .directives()
+      .iter()
+      .any(|directive| directive.node.name.node == name)
+      || match &item.node {
+        Selection::Field(field) => uses_directive(&field.node.selection_set.node, name),
+        Selection::InlineFragment(fragment) => {
+          uses_directive(&fragment.node.selection_set.node, name)
+        }
+        Selection::FragmentSpread(_) => false,
+      }
+  })
+}
+
+/// Remove the `@defer` directives of a request, so that it runs in one go.
+fn strip(request: &mut Request) {
+  if !uses_directives(request) {
+    return;
+  }
+  let Ok(document) = request.parsed_query() else {
+    return;
+  };
+  let mut document = document.clone();
+  match &mut document.operations {
+    DocumentOperations::Single(operation) => {
+      strip_selection_set(&mut operation.node.selection_set.node)
+    }
+    DocumentOperations::Multiple(operations) => {
+      for operation in operations.values_mut() {
+        strip_selection_set(&mut operation.node.selection_set.node);
+      }
+    }
+  }
+  for fragment in document.fragments.values_mut() {
+    strip_selection_set(&mut fragment.node.selection_set.node);
+  }
+  request.set_parsed_query(document);
+}
+
+fn uses_directives(request: &Request) -> bool {
+  request.query.contains("@defer")
+}
+
+fn strip_selection_set(selection_set: &mut SelectionSet) {
+  for item in &mut selection_set.items {
+    item
+      .node
+      .directives_mut()
+      .retain(|directive| directive.node.name.node != DEFER);
+    if let Some(selection_set) = selection_set_mut(&mut item.node) {
+      strip_selection_set(selection_set);
+    }
+  }
+}
+
+fn selection_set_mut(selection: &mut Selection) -> Option<&mut SelectionSet> {
+  match selection {
+    Selection::Field(field) => Some(&mut field.node.selection_set.node),
+    Selection::InlineFragment(fragment) => Some(&mut fragment.node.selection_set.node),
+    Selection::FragmentSpread(_) => None,
+  }
+}
+
+fn select_operation(
+  document: &ExecutableDocument,
+  operation_name: Option<&str>,
+) -> Option<(Option<Name>, Positioned<OperationDefinition>)> {
+  match (&document.operations, operation_name) {
+    (DocumentOperations::Single(operation), None) => Some((None, operation.clone())),
+    (DocumentOperations::Multiple(operations), Some(name)) => operations
+      .get_key_value(name)
+      .map(|(name, operation)| (Some(name.clone()), operation.clone())),
+    (DocumentOperations::Multiple(operations), None) if operations.len() == 1 => operations
+      .iter()
+      .next()
+      .map(|(name, operation)| (Some(name.clone()), operation.clone())),
+    _ => None,
+  }
+}
+
+/// Replace the fragment spreads with inline fragments.
+///
+/// Returns `None` on unknown or cyclic fragments, which are left to the validation.
+fn inline_fragments(
+  selection_set: &mut SelectionSet,
+  fragments: &HashMap<Name, Positioned<FragmentDefinition>>,
+  visiting: &mut Vec<Name>,
+) -> Option<()> {
+  for item in &mut selection_set.items {
+    if let Selection::FragmentSpread(spread) = &item.node {
+      let name = &spread.node.fragment_name.node;
+      if visiting.contains(name) {
+        return None;
+      }
+      let fragment = fragments.get(name)?;
+      let inline = InlineFragment {
+        type_condition: Some(fragment.node.type_condition.clone()),
+        directives: spread.node.directives.clone(),
+        selection_set: fragment.node.selection_set.clone(),
+      };
+      visiting.push(name.clone());
+      item.node = Selection::InlineFragment(spread.position_node(inline));
+      inline_fragments(selection_set_mut(&mut item.node)?, fragments, visiting)?;
+      visiting.pop();
+    } else if let Some(selection_set) = selection_set_mut(&mut item.node) {
+      inline_fragments(selection_set, fragments, visiting)?;
+    }
+  }
+  Some(())
+}
+
+/// The values of the variables, with the defaults of the operation.
+fn variable_values(request: &Request, operation: &OperationDefinition) -> HashMap<Name, Value> {
+  let mut values: HashMap<_, _> = operation
+    .variable_definitions
+    .iter()
+    .filter_map(|definition| {
+      let default = definition.node.default_value.as_ref()?;
+      Some((definition.node.name.node.clone(), default.node.clone()))
+    })
+    .collect();
+  values.extend(
+    request
+      .variables
+      .iter()
+      .map(|(name, value)| (name.clone(), value.clone())),
+  );
+  values
+}
+
+/// Move the deferred fragments out of the selection set.
+fn split(
+  selection_set: &mut SelectionSet,
+  ancestors: &mut Vec<Positioned<Selection>>,
+  variables: &HashMap<Name, Value>,
+  deferred: &mut Vec<Deferred>,
+) {
+  for mut item in std::mem::take(&mut selection_set.items) {
+    let directive = match &item.node {
+      // `@defer` only applies to fragments.
+      Selection::Field(_) => None,
+      _ => take_directive(item.node.directives_mut(), variables),
+    };
+
+    if let Some(children) = selection_set_mut(&mut item.node) {
+      let mut children = std::mem::take(children);
+      let was_empty = children.items.is_empty();
+      ancestors.push(item.clone());
+      split(&mut children, ancestors, variables, deferred);
+      ancestors.pop();
+      if children.items.is_empty() && !was_empty {
+        children.items.push(typename(item.pos));
+      }
+      *selection_set_mut(&mut item.node).expect("checked above") = children;
+    }
+
+    match directive {
+      Some(label) => deferred.push(Deferred {
+        ancestors: ancestors.clone(),
+        fragment: item,
+        label,
+      }),
+      None => selection_set.items.push(item),
+    }
+  }
+}
+
+/// Remove the `@defer` directive of a fragment.
+///
+/// Returns the label of the directive if it applies.
+fn take_directive(
+  directives: &mut Vec<Positioned<Directive>>,
+  variables: &HashMap<Name, Value>,
+) -> Option<Option<String>> {
+  let index = directives
+    .iter()
+    .position(|directive| directive.node.name.node == DEFER)?;
+  let directive = directives.remove(index).node;
+  let argument = |name: &str| {
+    directive
+      .arguments
+      .iter()
+      .find(|(argument, _)| argument.node == name)
+      .and_then(|(_, value)| {
+        value
+          .node
+          .clone()
+          .into_const_with(|variable| variables.get(&variable).cloned().ok_or(()))
+          .ok()
+      })
+  };
+  if let Some(Value::Boolean(false)) = argument("if") {
+    return None;
+  }
+  match argument("label") {
+    Some(Value::String(label)) => Some(Some(label)),
+    _ => Some(None),
+  }
+}
+
+/// A `__typename` selection, to keep a selection set from being empty.
+fn typename(pos: Pos) -> Positioned<Selection> {
+  let field = Field {
+    alias: None,
+    name: Positioned::new(Name::new("__typename"), pos),
+    arguments: Vec::new(),
+    directives: Vec::new(),
+    selection_set: Default::default(),
+  };
+  Positioned::new(Selection::Field(Positioned::new(field, pos)), pos)
+}
+
+/// A document made of the operation, without the variables it doesn't use anymore.
+fn document_of(
+  name: Option<Name>,
+  mut operation: Positioned<OperationDefinition>,
+) -> ExecutableDocument {
+  let mut used = HashSet::new();
+  for directive in &operation.node.directives {
+    collect_directive_variables(&directive.node, &mut used);
+  }
+  collect_variables(&operation.node.selection_set.node, &mut used);
+  operation
+    .node
+    .variable_definitions
+    .retain(|definition| used.contains(&definition.node.name.node));
+  ExecutableDocument {
+    operations: match name {
+      Some(name) => DocumentOperations::Multiple(HashMap::from([(name, operation)])),
+      None => DocumentOperations::Single(operation),
+    },
+    fragments: HashMap::new(),
+  }
+}
+
+fn collect_variables(selection_set: &SelectionSet, used: &mut HashSet<Name>) {
+  for item in &selection_set.items {
+    for directive in item.node.directives() {
+      collect_directive_variables(&directive.node, used);
+    }
+    match &item.node {
+      Selection::Field(field) => {
+        for (_, value) in &field.node.arguments {
+          let _ = value
+            .node
+            .clone()
+            .into_const_with(|name| collect_variable(name, used));
+        }
+        collect_variables(&field.node.selection_set.node, used);
+      }
+      Selection::InlineFragment(fragment) => {
+        collect_variables(&fragment.node.selection_set.node, used)
+      }
+      Selection::FragmentSpread(_) => {}
+    }
+  }
+}
+
+fn collect_directive_variables(directive: &Directive, used: &mut HashSet<Name>) {
+  for (_, value) in &directive.arguments {
+    let _ = value
+      .node
+      .clone()
+      .into_const_with(|name| collect_variable(name, used));
+  }
+}
+
+fn collect_variable(name: Name, used: &mut HashSet<Name>) -> Result<Value, ()> {
+  used.insert(name);
+  Ok(Value::Null)
+}
+
+fn derive_request(request: &Request, document: ExecutableDocument) -> Request {
+  let mut derived = Request::new(request.query.clone());
+  derived.operation_name = request.operation_name.clone();
+  derived.variables = request.variables.clone();
+  derived.extensions = request.extensions.clone();
+  derived.introspection_mode = request.introspection_mode;
+  derived.set_parsed_query(document);
+  derived
+}
+
+/// Find the values at the end of `keys`, going through the lists on the way.
+fn locate(
+  value: Value,
+  keys: &[Name],
+  path: Vec<PathSegment>,
+  located: &mut Vec<(Vec<PathSegment>, Value)>,
+) {
+  match (value, keys.split_first()) {
+    (Value::List(items), _) => {
+      for (index, item) in items.into_iter().enumerate() {
+        let mut path = path.clone();
+        path.push(PathSegment::Index(index));
+        locate(item, keys, path, located);
+      }
+    }
+    (value @ Value::Object(_), None) => located.push((path, value)),
+    (Value::Object(mut object), Some((key, keys))) => {
+      if let Some(value) = object.swap_remove(key) {
+        let mut path = path;
+        path.push(PathSegment::Field(key.to_string()));
+        locate(value, keys, path, located);
+      }
+    }
+    _ => {}
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use async_graphql::{EmptyMutation, EmptySubscription, Object, SimpleObject};
+  use serde_json::json;
+
+  use super::*;
+
+  #[derive(SimpleObject)]
+  struct User {
+    name: String,
+    email: String,
+  }
+
+  struct Query;
+
+  #[Object]
+  impl Query {
+    async fn id(&self) -> i32 {
+      1
+    }
+    async fn user(&self) -> User {
+      User {
+        name: "tony".into(),
+        email: "tony@example.com".into(),
+      }
+    }
+    async fn users(&self) -> Vec<User> {
+      Vec::new()
+    }
+  }
+
+  fn responses(request: Request) -> Vec<serde_json::Value> {
+    let schema = Schema::new(Query, EmptyMutation, EmptySubscription);
+    let mut request = request;
+    let plan = Plan::new(&mut request).expect("a deferred query");
+    tauri::async_runtime::block_on(
+      plan
+        .execute(schema, |request| request)
+        .map(|response| serde_json::to_value(response).unwrap())
+        .collect(),
+    )
+  }
+
+  #[test]
+  fn deferred_fragments_come_as_patches() {
+    let request = Request::new("{ id user { name ... @defer(label: \"contact\") { email } } }");
+    assert_eq!(
+      responses(request),
+      [
+        json!({ "data": { "id": 1, "user": { "name": "tony" } }, "hasNext": true }),
+        json!({
+          "incremental": [{
+            "data": { "email": "tony@example.com" },
+            "path": ["user"],
+            "label": "contact",
+          }],
+          "hasNext": false,
+        }),
+      ]
+    );
+  }
+
+  #[test]
+  fn deferred_fragment_spreads_are_inlined() {
+    let request =
+      Request::new("query Q { id ...Rest @defer } fragment Rest on Query { user { email } }");
+    assert_eq!(
+      responses(request),
+      [
+        json!({ "data": { "id": 1 }, "hasNext": true }),
+        json!({
+          "incremental": [{
+            "data": { "user": { "email": "tony@example.com" } },
+            "path": [],
+          }],
+          "hasNext": false,
+        }),
+      ]
+    );
+  }
+
+  #[test]
+  fn disabled_defer_runs_in_one_go() {
+    let mut request =
+      Request::new("query($d: Boolean) { id ... @defer(if: $d) { user { name } } }")
+        .variables(async_graphql::Variables::from_json(json!({ "d": false })));
+    assert!(Plan::new(&mut request).is_none());
+  }
+
+  #[test]
+  fn strip_removes_defer() {
+    let mut request = Request::new("{ id ... @defer { user { name } } }");
+    strip(&mut request);
+    let schema = Schema::new(Query, EmptyMutation, EmptySubscription);
+    let response = tauri::async_runtime::block_on(schema.execute(request));
+    assert_eq!(
+      response.data.into_json().unwrap(),
+      json!({ "id": 1, "user": { "name": "tony" } })
+    );
+  }
+
+  #[test]
+  fn stream_is_rejected() {
+    for query in [
+      "{ users @stream(initialCount: 1) { name } }",
+      "{ ...F } fragment F on Query { users @stream { name } }",
+    ] {
+      let error = check(&mut Request::new(query), true).unwrap_err();
+      let code = error.extensions.unwrap().get("code").cloned();
+      assert_eq!(
+        code,
+        Some(Value::from("UNSUPPORTED_DIRECTIVE")),
+        "{}",
+        query
+      );
+    }
+    assert!(check(
+      &mut Request::new("{ id ... @defer { user { name } } }"),
+      true
+    )
+    .is_ok());
+    assert!(check(&mut Request::new("{ id # @stream\n }"), true).is_ok());
+  }
+
+  #[test]
+  fn defer_is_rejected_unless_deferrable() {
+    for query in [
+      "{ id ... @defer { user { name } } }",
+      "{ ...F @defer } fragment F on Query { id }",
+      "{ ...F } fragment F on Query { user { ... @defer { name } } }",
+    ] {
+      let error = check(&mut Request::new(query), false).unwrap_err();
+      let extensions = error.extensions.unwrap();
+      assert_eq!(
+        extensions.get("code").cloned(),
+        Some(Value::from("UNSUPPORTED_DIRECTIVE")),
+        "{}",
+        query
+      );
+      assert_eq!(
+        extensions.get("directive").cloned(),
+        Some(Value::from("defer"))
+      );
+      assert!(check(&mut Request::new(query), true).is_ok());
+    }
+    assert!(check(&mut Request::new("{ id # @defer\n }"), false).is_ok());
+  }
+}
@@ -210,19 +210,77 @@
 //! [`Commands`]: https://tauri.studio/docs/guides/command
 //! [`Events`]: https://tauri.studio/docs/guides/events
 //! [`GraphQL`]: https://graphql.org
+pub(crate) mod access;
+pub mod apq;
 pub(crate) mod cancel_token;
+pub mod codec;
+pub(crate) mod delivery;
+pub(crate) mod error;
+pub mod guard;
+pub(crate) mod incremental;
+pub mod metrics;
+pub(crate) mod multicast;
+pub(crate) mod operation;
+pub(crate) mod permissions;
 pub(crate) mod plugin;
+pub(crate) mod preflight;
+pub mod protocol;
+pub(crate) mod redaction;
+pub(crate) mod registry;
+pub(crate) mod request_limits;
+pub(crate) mod schema;
 pub(crate) mod subscription;
+pub(crate) mod timeout;
+pub(crate) mod trace;
+pub(crate) mod transport;
+pub mod trusted_documents;
+pub(crate) mod webview_limits;
 
+pub use access::UrlPattern;
 use async_graphql::Context;
+pub use delivery::DeliveryPolicy;
+pub use error::Error;
+pub use metrics::Metrics;
 pub use plugin::{Builder, BuilderError, MizukiPlugin};
+pub use registry::{SubscriptionInfo, SubscriptionRegistry};
+pub use request_limits::RequestLimits;
+pub use subscription::SubscriptionDelivery;
 use tauri::{AppHandle, Runtime, Webview, Window};
 use tokio_util::sync::CancellationToken;
+pub use webview_limits::WebviewLimits;
+
+/// The invoke header holding the id a `graphql` request can be cancelled by.
+///
+/// The webview cancels the request by emitting the subscription end event
+/// (see [`Builder::subscription_end_label`]) with the id as payload,
+/// just like it ends a subscription:
+///
+/// ```js
+/// import { invoke } from '@tauri-apps/api/core'
+/// import { getCurrentWebview } from '@tauri-apps/api/webview'
+///
+/// const webview = getCurrentWebview()
+/// const response = invoke('plugin:todo-plugin|graphql', { query: '{ list { id } }' }, {
+///   headers: { 'Mizuki-Request-Id': 'list-1' }
+/// })
+/// webview.emitTo({ kind: 'Webview', label: webview.label }, 'sub_end', 'list-1')
+/// ```
+///
+/// A cancelled request is answered with a `CANCELLED` error.
+///
+/// Emitting the event requires the page to hold the `core:event:allow-emit-to`
+/// permission, part of `core:event:default`. Without it the emit fails,
+/// and the request runs to its end.
+pub const REQUEST_ID_HEADER: &str = "mizuki-request-id";
 
 /// A trait extension
 /// to extract [`tauri::AppHandle`], [`tauri::Window`], [`tauri::Webview`]
-/// and the subscription [`tokio_util::sync::CancellationToken`]
+/// the [`tokio_util::sync::CancellationToken`] of the operation
+/// and the [`guard::Capabilities`] of the webview
 /// from an [`async_graphql::Context`].
+///
+/// The token is cancelled when the webview cancels the operation,
+/// or when its window is destroyed.
 pub trait AsyncGQLContextExt {
   fn app_handle<R>(&self) -> Option<&AppHandle<R>>
   where
@@ -234,6 +292,7 @@ pub trait AsyncGQLContextExt {
   where
     R: Runtime;
   fn cancel_token(&self) -> Option<&CancellationToken>;
+  fn capabilities(&self) -> Option<&guard::Capabilities>;
 }
 
 impl AsyncGQLContextExt for Context<'_> {
@@ -261,4 +320,8 @@ impl AsyncGQLContextExt for Context<'_> {
   fn cancel_token(&self) -> Option<&CancellationToken> {
     self.data_opt()
   }
+
+  fn capabilities(&self) -> Option<&guard::Capabilities> {
+    self.data_opt()
+  }
 }
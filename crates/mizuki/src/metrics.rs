@@ -0,0 +1,471 @@
+//! Metrics of the operations, see [`Metrics`].
+
+use std::{
+  collections::{BTreeMap, BTreeSet},
+  io,
+  sync::{Arc, Mutex},
+  time::{Duration, Instant},
+};
+
+use async_graphql::Request;
+use serde::Serialize;
+
+use crate::operation::{operation, operation_name, operation_type};
+
+/// How many operation names of a plugin are told apart, see [`OperationMetrics::operation_name`].
+pub const MAX_OPERATION_NAMES: usize = 100;
+
+/// The name the operations are recorded under past [`MAX_OPERATION_NAMES`].
+///
+/// No GraphQL operation can have it.
+pub const OTHER_OPERATIONS: &str = "[other]";
+
+/// The upper bounds of the buckets of the [`LatencyHistogram`]s, in milliseconds.
+pub const LATENCY_BUCKETS_MS: &[u64] = &[1, 5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10000];
+
+/// How long the requests of an operation took to answer.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LatencyHistogram {
+  /// How many requests took at most the matching bound of [`LATENCY_BUCKETS_MS`],
+  /// and longer than the previous one.
+  ///
+  /// The last bucket counts the requests longer than all the bounds.
+  pub buckets: Vec<u64>,
+  pub count: u64,
+  pub sum: Duration,
+}
+
+impl Default for LatencyHistogram {
+  fn default() -> Self {
+    Self {
+      buckets: vec![0; LATENCY_BUCKETS_MS.len() + 1],
+      count: 0,
+      sum: Duration::ZERO,
+    }
+  }
+}
+
+impl LatencyHistogram {
+  fn record(&mut self, latency: Duration) {
+    let bucket = LATENCY_BUCKETS_MS
+      .iter()
+      .position(|&bound| latency <= Duration::from_millis(bound))
+      .unwrap_or(LATENCY_BUCKETS_MS.len());
+    self.buckets[bucket] += 1;
+    self.count += 1;
+    self.sum += latency;
+  }
+}
+
+/// The metrics of an operation, as listed by [`Metrics::snapshot`].
+///
+/// Operations are told apart by plugin, name and type.
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OperationMetrics {
+  pub plugin: &'static str,
+  /// The names come from the webviews, so once a plugin recorded [`MAX_OPERATION_NAMES`] of them,
+  /// the operations with another name are recorded together, as [`OTHER_OPERATIONS`].
+  pub operation_name: Option<String>,
+  /// `query`, `mutation` or `subscription`, if the request was valid enough to tell.
+  pub operation_type: Option<String>,
+  /// How many times it was requested.
+  pub requests: u64,
+  /// How many of the requests were answered with errors.
+  ///
+  /// A subscription counts once, however many of its results have errors.
+  pub errors: u64,
+  /// How many of the requests were rejected before running, by the access rules, the permissions,
+  /// the trusted documents or the limits. They count as errors too.
+  pub rejected: u64,
+  /// How many of the requests were cancelled, by the webview or because it left its page.
+  pub cancelled: u64,
+  /// Only the queries and mutations are measured, subscriptions last as long as they are needed.
+  pub latency: LatencyHistogram,
+  pub active_subscriptions: u64,
+  /// How many subscription results were sent to the webviews.
+  pub items_emitted: u64,
+  /// The size of the subscription results sent to the webviews.
+  pub bytes_emitted: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct Key {
+  plugin: &'static str,
+  name: Option<String>,
+  ty: Option<&'static str>,
+}
+
+impl Key {
+  fn new(plugin: &'static str, request: &mut Request) -> Self {
+    let ty = operation(request).map(|(_, operation)| operation_type(operation.ty));
+    Self {
+      plugin,
+      name: operation_name(request),
+      ty,
+    }
+  }
+
+  #[cfg(feature = "metrics")]
+  fn labels(&self) -> [(&'static str, String); 3] {
+    [
+      ("plugin", self.plugin.to_string()),
+      (
+        "operation",
+        self.name.clone().unwrap_or_else(|| "anonymous".into()),
+      ),
+      ("type", self.ty.unwrap_or("unknown").to_string()),
+    ]
+  }
+}
+
+/// The metrics of the operations of the mizuki plugins of an app.
+///
+/// It is managed by the app, so a diagnostics window can show them:
+///
+/// ```rust,no_run
+/// use mizuki::Metrics;
+/// use tauri::Manager;
+///
+/// fn diagnostics<R: tauri::Runtime>(app: &tauri::AppHandle<R>) {
+///   for operation in app.state::<Metrics>().snapshot() {
+///     println!("{:?}", operation);
+///   }
+/// }
+/// ```
+///
+/// With the `metrics` feature, they are also recorded through the facade of the
+/// [`metrics`](https://docs.rs/metrics) crate, as the `mizuki_requests_total`,
+/// `mizuki_errors_total`, `mizuki_rejected_total`, `mizuki_cancelled_total`,
+/// `mizuki_request_duration_seconds`, `mizuki_active_subscriptions`,
+/// `mizuki_subscription_items_total` and `mizuki_subscription_bytes_total` metrics,
+/// labelled by `plugin`, `operation` and `type`.
+#[derive(Clone, Default)]
+pub struct Metrics {
+  operations: Arc<Mutex<BTreeMap<Key, OperationMetrics>>>,
+}
+
+impl Metrics {
+  /// The metrics of the operations requested so far.
+  pub fn snapshot(&self) -> Vec<OperationMetrics> {
+    self.operations.lock().unwrap().values().cloned().collect()
+  }
+
+  /// The key of `request`, under [`OTHER_OPERATIONS`] if its plugin has too many names already.
+  fn key(&self, plugin: &'static str, request: &mut Request) -> Key {
+    let mut key = Key::new(plugin, request);
+    let Some(name) = &key.name else {
+      return key;
+    };
+    let operations = self.operations.lock().unwrap();
+    let names: BTreeSet<_> = operations
+      .keys()
+      .filter(|known| known.plugin == plugin && known.name.as_deref() != Some(OTHER_OPERATIONS))
+      .filter_map(|known| known.name.as_deref())
+      .collect();
+    if !names.contains(name.as_str()) && names.len() >= MAX_OPERATION_NAMES {
+      key.name = Some(OTHER_OPERATIONS.into());
+    }
+    key
+  }
+
+  fn update(&self, key: &Key, update: impl FnOnce(&mut OperationMetrics)) {
+    let mut operations = self.operations.lock().unwrap();
+    let metrics = operations
+      .entry(key.clone())
+      .or_insert_with(|| OperationMetrics {
+        plugin: key.plugin,
+        operation_name: key.name.clone(),
+        operation_type: key.ty.map(Into::into),
+        ..Default::default()
+      });
+    update(metrics);
+  }
+
+  /// Start measuring a query or a mutation.
+  pub(crate) fn request(&self, plugin: &'static str, request: &mut Request) -> RequestMetrics {
+    RequestMetrics {
+      metrics: self.clone(),
+      key: self.key(plugin, request),
+      start: Instant::now(),
+      finished: false,
+    }
+  }
+
+  /// Record a request rejected before running.
+  pub(crate) fn rejected(&self, plugin: &'static str, request: &mut Request) {
+    let key = self.key(plugin, request);
+    self.update(&key, |metrics| {
+      metrics.requests += 1;
+      metrics.errors += 1;
+      metrics.rejected += 1;
+    });
+    #[cfg(feature = "metrics")]
+    {
+      let labels = key.labels();
+      ::metrics::counter!("mizuki_requests_total", &labels).increment(1);
+      ::metrics::counter!("mizuki_errors_total", &labels).increment(1);
+      ::metrics::counter!("mizuki_rejected_total", &labels).increment(1);
+    }
+  }
+
+  /// Start measuring a subscription, until the returned [`SubscriptionMetrics`] is dropped.
+  pub(crate) fn subscription(
+    &self,
+    plugin: &'static str,
+    request: &mut Request,
+  ) -> SubscriptionMetrics {
+    let key = self.key(plugin, request);
+    self.update(&key, |metrics| {
+      metrics.requests += 1;
+      metrics.active_subscriptions += 1;
+    });
+    #[cfg(feature = "metrics")]
+    {
+      ::metrics::counter!("mizuki_requests_total", &key.labels()).increment(1);
+      ::metrics::gauge!("mizuki_active_subscriptions", &key.labels()).increment(1.0);
+    }
+    SubscriptionMetrics {
+      metrics: self.clone(),
+      key,
+      failed: false,
+    }
+  }
+}
+
+/// Measures a query or a mutation.
+///
+/// Dropped before it is finished, the request was cancelled.
+pub(crate) struct RequestMetrics {
+  metrics: Metrics,
+  key: Key,
+  start: Instant,
+  finished: bool,
+}
+
+impl RequestMetrics {
+  pub(crate) fn finish(mut self, failed: bool) {
+    self.finished = true;
+    let latency = self.start.elapsed();
+    self.metrics.update(&self.key, |metrics| {
+      metrics.requests += 1;
+      metrics.errors += u64::from(failed);
+      metrics.latency.record(latency);
+    });
+    #[cfg(feature = "metrics")]
+    {
+      let labels = self.key.labels();
+      ::metrics::counter!("mizuki_requests_total", &labels).increment(1);
+      if failed {
+        ::metrics::counter!("mizuki_errors_total", &labels).increment(1);
+      }
+      ::metrics::histogram!("mizuki_request_duration_seconds", &labels)
+        .record(latency.as_secs_f64());
+    }
+  }
+}
+
+impl Drop for RequestMetrics {
+  fn drop(&mut self) {
+    if self.finished {
+      return;
+    }
+    self.metrics.update(&self.key, |metrics| {
+      metrics.requests += 1;
+      metrics.cancelled += 1;
+    });
+    #[cfg(feature = "metrics")]
+    {
+      let labels = self.key.labels();
+      ::metrics::counter!("mizuki_requests_total", &labels).increment(1);
+      ::metrics::counter!("mizuki_cancelled_total", &labels).increment(1);
+    }
+  }
+}
+
+/// Measures a subscription.
+pub(crate) struct SubscriptionMetrics {
+  metrics: Metrics,
+  key: Key,
+  failed: bool,
+}
+
+impl SubscriptionMetrics {
+  /// Record a result sent to the webview.
+  pub(crate) fn emitted(&mut self, bytes: usize, failed: bool) {
+    let first_failure = failed && !self.failed;
+    self.failed |= failed;
+    self.metrics.update(&self.key, |metrics| {
+      metrics.items_emitted += 1;
+      metrics.bytes_emitted += bytes as u64;
+      metrics.errors += u64::from(first_failure);
+    });
+    #[cfg(feature = "metrics")]
+    {
+      let labels = self.key.labels();
+      ::metrics::counter!("mizuki_subscription_items_total", &labels).increment(1);
+      ::metrics::counter!("mizuki_subscription_bytes_total", &labels).increment(bytes as u64);
+      if first_failure {
+        ::metrics::counter!("mizuki_errors_total", &labels).increment(1);
+      }
+    }
+  }
+
+  /// Record that the subscription was cancelled, by the webview or because it left its page.
+  pub(crate) fn cancelled(&self) {
+    self.metrics.update(&self.key, |metrics| {
+      metrics.cancelled += 1;
+    });
+    #[cfg(feature = "metrics")]
+    ::metrics::counter!("mizuki_cancelled_total", &self.key.labels()).increment(1);
+  }
+}
+
+impl Drop for SubscriptionMetrics {
+  fn drop(&mut self) {
+    self.metrics.update(&self.key, |metrics| {
+      metrics.active_subscriptions -= 1;
+    });
+    #[cfg(feature = "metrics")]
+    ::metrics::gauge!("mizuki_active_subscriptions", &self.key.labels()).decrement(1.0);
+  }
+}
+
+/// The size of a value serialized as JSON.
+pub(crate) fn json_len(value: &impl Serialize) -> usize {
+  struct Counter(usize);
+  impl io::Write for Counter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+      self.0 += buf.len();
+      Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+      Ok(())
+    }
+  }
+  let mut counter = Counter(0);
+  let _ = serde_json::to_writer(&mut counter, value);
+  counter.0
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn request(query: &str) -> Request {
+    Request::new(query)
+  }
+
+  fn find<'a>(snapshot: &'a [OperationMetrics], name: &str) -> &'a OperationMetrics {
+    snapshot
+      .iter()
+      .find(|operation| operation.operation_name.as_deref() == Some(name))
+      .unwrap()
+  }
+
+  #[test]
+  fn latencies_are_bucketed() {
+    let mut histogram = LatencyHistogram::default();
+    histogram.record(Duration::from_millis(1));
+    histogram.record(Duration::from_millis(7));
+    histogram.record(Duration::from_secs(60));
+    assert_eq!(histogram.buckets[0], 1);
+    assert_eq!(histogram.buckets[2], 1);
+    assert_eq!(histogram.buckets[LATENCY_BUCKETS_MS.len()], 1);
+    assert_eq!(histogram.count, 3);
+  }
+
+  #[test]
+  fn requests_are_recorded_by_operation() {
+    let metrics = Metrics::default();
+    metrics
+      .request("test", &mut request("query A { a }"))
+      .finish(false);
+    metrics
+      .request("test", &mut request("query A { a }"))
+      .finish(true);
+    metrics.request("test", &mut request("{ a }")).finish(false);
+    let snapshot = metrics.snapshot();
+    let a = find(&snapshot, "A");
+    assert_eq!((a.requests, a.errors), (2, 1));
+    assert_eq!(a.operation_type.as_deref(), Some("query"));
+    assert_eq!(a.latency.count, 2);
+    assert_eq!(snapshot.len(), 2);
+  }
+
+  #[test]
+  fn rejections_and_cancellations_are_recorded() {
+    let metrics = Metrics::default();
+    metrics.rejected("test", &mut request("query A { a }"));
+    drop(metrics.request("test", &mut request("query A { a }")));
+    let subscription = metrics.subscription("test", &mut request("subscription A { a }"));
+    subscription.cancelled();
+    drop(subscription);
+
+    let snapshot = metrics.snapshot();
+    let query = snapshot
+      .iter()
+      .find(|operation| operation.operation_type.as_deref() == Some("query"))
+      .unwrap();
+    assert_eq!(
+      (
+        query.requests,
+        query.errors,
+        query.rejected,
+        query.cancelled
+      ),
+      (2, 1, 1, 1)
+    );
+    assert_eq!(query.latency.count, 0);
+    let subscription = snapshot
+      .iter()
+      .find(|operation| operation.operation_type.as_deref() == Some("subscription"))
+      .unwrap();
+    assert_eq!(
+      (subscription.cancelled, subscription.active_subscriptions),
+      (1, 0)
+    );
+  }
+
+  #[test]
+  fn subscriptions_count_their_errors_once() {
+    let metrics = Metrics::default();
+    let mut subscription = metrics.subscription("test", &mut request("subscription S { a }"));
+    subscription.emitted(10, true);
+    subscription.emitted(5, true);
+    assert_eq!(find(&metrics.snapshot(), "S").active_subscriptions, 1);
+    drop(subscription);
+    let snapshot = metrics.snapshot();
+    let s = find(&snapshot, "S");
+    assert_eq!((s.items_emitted, s.bytes_emitted, s.errors), (2, 15, 1));
+    assert_eq!(s.active_subscriptions, 0);
+  }
+
+  #[test]
+  fn operation_names_are_capped() {
+    let metrics = Metrics::default();
+    for i in 0..MAX_OPERATION_NAMES + 5 {
+      metrics
+        .request("test", &mut request(&format!("query Q{} {{ a }}", i)))
+        .finish(false);
+    }
+    // A known name is still told apart, and other plugins have their own names.
+    metrics
+      .request("test", &mut request("query Q0 { a }"))
+      .finish(false);
+    metrics
+      .request("other", &mut request("query Q200 { a }"))
+      .finish(false);
+    let snapshot = metrics.snapshot();
+    assert_eq!(find(&snapshot, "Q0").requests, 2);
+    assert_eq!(find(&snapshot, OTHER_OPERATIONS).requests, 5);
+    assert_eq!(find(&snapshot, "Q200").plugin, "other");
+    assert_eq!(snapshot.len(), MAX_OPERATION_NAMES + 2);
+  }
+
+  #[test]
+  fn json_len_measures_the_serialized_value() {
+    assert_eq!(json_len(&serde_json::json!({ "a": [1, 2] })), 11);
+  }
+}
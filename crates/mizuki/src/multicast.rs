@@ -0,0 +1,421 @@
+use std::{
+  collections::HashMap,
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+  },
+};
+
+use async_graphql::{
+  futures_util::{stream::BoxStream, StreamExt},
+  parser::types::OperationType,
+  Request, Response,
+};
+use tauri::{Runtime, Webview};
+use tokio::sync::broadcast;
+use tokio_util::sync::CancellationToken;
+
+use crate::{guard::Capabilities, operation::operation, Error};
+
+/// How many results a slow subscriber can lag behind before missing some.
+const CAPACITY: usize = 64;
+
+pub(crate) type MulticastKey<R> = dyn Fn(&Webview<R>) -> String + Send + Sync;
+
+struct Upstream {
+  generation: u64,
+  sender: broadcast::Sender<Arc<Response>>,
+  subscribers: usize,
+  cancel_token: CancellationToken,
+}
+
+/// Shares a single stream between the identical subscriptions.
+pub(crate) struct Multicast<R: Runtime> {
+  /// The context key of a webview, `None` if multicasting is disabled.
+  key: Option<Box<MulticastKey<R>>>,
+  upstreams: Mutex<HashMap<String, Upstream>>,
+  next_generation: AtomicU64,
+}
+
+impl<R: Runtime> Multicast<R> {
+  pub(crate) fn new(key: Option<Box<MulticastKey<R>>>) -> Self {
+    Self {
+      key,
+      upstreams: Default::default(),
+      next_generation: AtomicU64::new(0),
+    }
+  }
+
+  /// The key of the subscriptions a request can share its stream with.
+  ///
+  /// Only subscriptions are multicast, and only if it is enabled.
+  /// The guards of the shared stream check the capabilities of its first subscriber,
+  /// so only the webviews holding the same ones share it.
+  pub(crate) fn key(
+    &self,
+    request: &mut Request,
+    webview: &Webview<R>,
+    capabilities: &Capabilities,
+  ) -> Option<String> {
+    let context = self.key.as_ref()?(webview);
+    let (_, operation) = operation(request)?;
+    if operation.ty != OperationType::Subscription {
+      return None;
+    }
+    serde_json::to_string(&(
+      &request.query,
+      &request.operation_name,
+      &request.variables,
+      capabilities.iter().collect::<Vec<_>>(),
+      context,
+    ))
+    .ok()
+  }
+
+  /// Subscribe to the stream of `key`, started with `execute` if there is none.
+  ///
+  /// The results a subscriber misses are replaced with a `LAGGED` error.
+  ///
+  /// `execute` receives the token cancelled once the last subscriber is gone.
+  /// It runs the hooks of the user, so it is called without holding the lock;
+  /// if another subscriber starts the stream meanwhile, its stream is dropped.
+  pub(crate) fn subscribe(
+    self: &Arc<Self>,
+    key: String,
+    execute: impl FnOnce(CancellationToken) -> BoxStream<'static, Response>,
+  ) -> BoxStream<'static, Response> {
+    let (generation, receiver) = match self.join(&key) {
+      Some(joined) => joined,
+      None => {
+        let cancel_token = CancellationToken::new();
+        let stream = execute(cancel_token.clone());
+        let mut upstreams = self.upstreams.lock().unwrap();
+        match upstreams.get_mut(&key) {
+          Some(upstream) => {
+            cancel_token.cancel();
+            upstream.subscribers += 1;
+            (upstream.generation, upstream.sender.subscribe())
+          }
+          None => {
+            let generation = self.next_generation.fetch_add(1, Ordering::Relaxed);
+            let (sender, receiver) = broadcast::channel(CAPACITY);
+            upstreams.insert(
+              key.clone(),
+              Upstream {
+                generation,
+                sender: sender.clone(),
+                subscribers: 1,
+                cancel_token: cancel_token.clone(),
+              },
+            );
+            tauri::async_runtime::spawn(self.clone().run(
+              key.clone(),
+              generation,
+              stream,
+              sender,
+              cancel_token,
+            ));
+            (generation, receiver)
+          }
+        }
+      }
+    };
+    let subscriber = Subscriber {
+      multicast: self.clone(),
+      key,
+      generation,
+    };
+    async_graphql::futures_util::stream::unfold(
+      (receiver, subscriber),
+      |(mut receiver, subscriber)| async move {
+        let response = match receiver.recv().await {
+          Ok(response) => clone_response(&response),
+          // A lagging subscriber misses the results it couldn't keep up with.
+          Err(broadcast::error::RecvError::Lagged(missed)) => {
+            Response::from_errors(vec![Error::Lagged { missed }.into()])
+          }
+          Err(broadcast::error::RecvError::Closed) => return None,
+        };
+        Some((response, (receiver, subscriber)))
+      },
+    )
+    .boxed()
+  }
+
+  /// Join the stream of `key` if it is running.
+  fn join(&self, key: &str) -> Option<(u64, broadcast::Receiver<Arc<Response>>)> {
+    let mut upstreams = self.upstreams.lock().unwrap();
+    let upstream = upstreams.get_mut(key)?;
+    upstream.subscribers += 1;
+    Some((upstream.generation, upstream.sender.subscribe()))
+  }
+
+  /// Forward the results of a stream to its subscribers.
+  async fn run(
+    self: Arc<Self>,
+    key: String,
+    generation: u64,
+    mut stream: BoxStream<'static, Response>,
+    sender: broadcast::Sender<Arc<Response>>,
+    cancel_token: CancellationToken,
+  ) {
+    loop {
+      tokio::select! {
+        _ = cancel_token.cancelled() => break,
+        response = stream.next() => match response {
+          Some(response) => {
+            let _ = sender.send(Arc::new(response));
+          }
+          None => break,
+        },
+      }
+    }
+    // Dropping the last sender ends the streams of the subscribers.
+    self.remove(&key, generation);
+  }
+
+  fn remove(&self, key: &str, generation: u64) {
+    let mut upstreams = self.upstreams.lock().unwrap();
+    if upstreams.get(key).map(|upstream| upstream.generation) == Some(generation) {
+      upstreams.remove(key);
+    }
+  }
+}
+
+/// Stops the stream of its key once the last subscriber is dropped.
+struct Subscriber<R: Runtime> {
+  multicast: Arc<Multicast<R>>,
+  key: String,
+  generation: u64,
+}
+
+impl<R: Runtime> Drop for Subscriber<R> {
+  fn drop(&mut self) {
+    let mut upstreams = self.multicast.upstreams.lock().unwrap();
+    let Some(upstream) = upstreams.get_mut(&self.key) else {
+      return;
+    };
+    if upstream.generation != self.generation {
+      return;
+    }
+    upstream.subscribers -= 1;
+    if upstream.subscribers == 0 {
+      upstream.cancel_token.cancel();
+      upstreams.remove(&self.key);
+    }
+  }
+}
+
+fn clone_response(response: &Response) -> Response {
+  let mut clone = Response::new(response.data.clone());
+  clone.extensions = response.extensions.clone();
+  clone.cache_control = response.cache_control;
+  clone.errors = response.errors.clone();
+  clone.http_headers = response.http_headers.clone();
+  clone
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::atomic::AtomicUsize;
+
+  use async_graphql::Value;
+  use tauri::{
+    test::{mock_app, MockRuntime},
+    WebviewUrl, WebviewWindowBuilder,
+  };
+  use tokio::sync::mpsc;
+
+  use super::*;
+
+  type Sender = mpsc::UnboundedSender<Response>;
+
+  /// A multicast whose upstreams are fed by hand, the last one started first.
+  struct Fixture {
+    multicast: Arc<Multicast<MockRuntime>>,
+    executions: Arc<AtomicUsize>,
+    upstreams: Arc<Mutex<Vec<(Sender, CancellationToken)>>>,
+  }
+
+  impl Fixture {
+    fn new() -> Self {
+      Self {
+        multicast: Arc::new(Multicast::new(Some(Box::new(|_| String::new())))),
+        executions: Default::default(),
+        upstreams: Default::default(),
+      }
+    }
+
+    fn subscribe(&self, key: &str) -> BoxStream<'static, Response> {
+      self.multicast.subscribe(key.into(), |cancel_token| {
+        self.executions.fetch_add(1, Ordering::Relaxed);
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        self.upstreams.lock().unwrap().push((sender, cancel_token));
+        async_graphql::futures_util::stream::poll_fn(move |cx| receiver.poll_recv(cx)).boxed()
+      })
+    }
+
+    fn send(&self, value: i32) {
+      let upstreams = self.upstreams.lock().unwrap();
+      let (sender, _) = upstreams.last().unwrap();
+      sender.send(Response::new(Value::from(value))).unwrap();
+    }
+
+    /// End the last upstream.
+    fn end(&self) {
+      self.upstreams.lock().unwrap().pop();
+    }
+
+    fn cancelled(&self) -> bool {
+      let upstreams = self.upstreams.lock().unwrap();
+      upstreams.last().unwrap().1.is_cancelled()
+    }
+
+    fn upstreams(&self) -> usize {
+      self.multicast.upstreams.lock().unwrap().len()
+    }
+  }
+
+  fn value(response: Option<Response>) -> Value {
+    response.unwrap().data
+  }
+
+  #[tokio::test]
+  async fn subscribers_share_the_upstream() {
+    let fixture = Fixture::new();
+    let _first = fixture.subscribe("a");
+    let _second = fixture.subscribe("a");
+    let _other = fixture.subscribe("b");
+    let _third = fixture.subscribe("a");
+    assert_eq!(fixture.executions.load(Ordering::Relaxed), 2);
+    assert_eq!(fixture.upstreams(), 2);
+    assert_eq!(
+      fixture.multicast.upstreams.lock().unwrap()["a"].subscribers,
+      3
+    );
+  }
+
+  #[tokio::test]
+  async fn the_first_stream_inserted_wins() {
+    let fixture = Fixture::new();
+    let cancel_token = Mutex::new(None);
+    let second = Mutex::new(None);
+    let _first = fixture.multicast.subscribe("a".into(), |token| {
+      *cancel_token.lock().unwrap() = Some(token);
+      // Another subscriber starts the stream while this one is being built.
+      *second.lock().unwrap() = Some(fixture.subscribe("a"));
+      async_graphql::futures_util::stream::pending().boxed()
+    });
+    assert!(cancel_token
+      .lock()
+      .unwrap()
+      .as_ref()
+      .unwrap()
+      .is_cancelled());
+    assert!(!fixture.cancelled());
+    assert_eq!(fixture.upstreams(), 1);
+    assert_eq!(
+      fixture.multicast.upstreams.lock().unwrap()["a"].subscribers,
+      2
+    );
+  }
+
+  #[tokio::test]
+  async fn results_go_to_every_subscriber() {
+    let fixture = Fixture::new();
+    let mut first = fixture.subscribe("a");
+    let mut second = fixture.subscribe("a");
+    fixture.send(1);
+    fixture.send(2);
+    assert_eq!(value(first.next().await), Value::from(1));
+    assert_eq!(value(first.next().await), Value::from(2));
+    assert_eq!(value(second.next().await), Value::from(1));
+    assert_eq!(value(second.next().await), Value::from(2));
+  }
+
+  #[tokio::test]
+  async fn the_last_subscriber_stops_the_upstream() {
+    let fixture = Fixture::new();
+    let first = fixture.subscribe("a");
+    let second = fixture.subscribe("a");
+    drop(first);
+    assert!(!fixture.cancelled());
+    drop(second);
+    assert!(fixture.cancelled());
+    assert_eq!(fixture.upstreams(), 0);
+
+    let _third = fixture.subscribe("a");
+    assert_eq!(fixture.executions.load(Ordering::Relaxed), 2);
+    assert!(!fixture.cancelled());
+  }
+
+  #[tokio::test]
+  async fn the_end_of_the_upstream_ends_the_subscribers() {
+    let fixture = Fixture::new();
+    let mut subscriber = fixture.subscribe("a");
+    fixture.send(1);
+    fixture.end();
+    assert_eq!(value(subscriber.next().await), Value::from(1));
+    assert!(subscriber.next().await.is_none());
+    assert_eq!(fixture.upstreams(), 0);
+  }
+
+  #[tokio::test]
+  async fn lagging_subscribers_are_told() {
+    let fixture = Fixture::new();
+    let mut subscriber = fixture.subscribe("a");
+    let total = CAPACITY as i32 + 6;
+    for value in 0..total {
+      fixture.send(value);
+    }
+    // Let the upstream forward everything before the subscriber catches up.
+    while fixture.multicast.upstreams.lock().unwrap()["a"]
+      .sender
+      .len()
+      < CAPACITY
+    {
+      tokio::task::yield_now().await;
+    }
+    tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    let lagged = subscriber.next().await.unwrap();
+    let extensions = lagged.errors[0].extensions.as_ref().unwrap();
+    assert_eq!(extensions.get("code"), Some(&Value::from("LAGGED")));
+    assert_eq!(extensions.get("missed"), Some(&Value::from(6)));
+    assert_eq!(value(subscriber.next().await), Value::from(6));
+  }
+
+  #[test]
+  fn keys() {
+    let app = mock_app();
+    let webview = WebviewWindowBuilder::new(&app, "main", WebviewUrl::default())
+      .build()
+      .unwrap();
+    let webview = webview.as_ref();
+    let multicast = Multicast::<MockRuntime>::new(Some(Box::new(|webview| webview.label().into())));
+    let key = |query: &str, capabilities: &[&str]| {
+      let capabilities = capabilities.iter().copied().collect();
+      multicast.key(&mut Request::new(query), webview, &capabilities)
+    };
+    assert!(key("subscription { a }", &[]).is_some());
+    assert_eq!(
+      key("subscription { a }", &[]),
+      key("subscription { a }", &[])
+    );
+    assert_ne!(
+      key("subscription { a }", &[]),
+      key("subscription { b }", &[])
+    );
+    assert_ne!(
+      key("subscription { a }", &[]),
+      key("subscription { a }", &["admin"])
+    );
+    assert_eq!(key("query { a }", &[]), None);
+    let disabled = Multicast::<MockRuntime>::new(None);
+    let key = disabled.key(
+      &mut Request::new("subscription { a }"),
+      webview,
+      &Default::default(),
+    );
+    assert_eq!(key, None);
+  }
+}
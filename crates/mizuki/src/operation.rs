@@ -0,0 +1,93 @@
+use async_graphql::{
+  parser::types::{
+    DocumentOperations, ExecutableDocument, OperationDefinition, OperationType, Selection,
+    SelectionSet,
+  },
+  Request,
+};
+
+/// The operation a request runs, once parsed.
+///
+/// Requests that don't parse, or don't name one of their operations, have none.
+pub(crate) fn operation(
+  request: &mut Request,
+) -> Option<(&ExecutableDocument, &OperationDefinition)> {
+  let operation_name = request.operation_name.clone();
+  let document = request.parsed_query().ok()?;
+  let operation = match (&document.operations, operation_name.as_deref()) {
+    (DocumentOperations::Single(operation), _) => operation,
+    (DocumentOperations::Multiple(operations), Some(name)) => operations.get(name)?,
+    (DocumentOperations::Multiple(operations), None) if operations.len() == 1 => {
+      operations.values().next()?
+    }
+    (DocumentOperations::Multiple(_), None) => return None,
+  };
+  Some((document, &operation.node))
+}
+
+/// The name of an operation type, as the errors and metrics report it.
+pub(crate) fn operation_type(ty: OperationType) -> &'static str {
+  match ty {
+    OperationType::Query => "query",
+    OperationType::Mutation => "mutation",
+    OperationType::Subscription => "subscription",
+  }
+}
+
+/// The name of the operation a request runs, if it has one.
+pub(crate) fn operation_name(request: &mut Request) -> Option<String> {
+  if request.operation_name.is_some() {
+    return request.operation_name.clone();
+  }
+  match &request.parsed_query().ok()?.operations {
+    DocumentOperations::Single(_) => None,
+    DocumentOperations::Multiple(operations) if operations.len() == 1 => {
+      operations.keys().next().map(ToString::to_string)
+    }
+    DocumentOperations::Multiple(_) => None,
+  }
+}
+
+/// The names of the root fields of an operation, fragments included.
+pub(crate) fn root_fields<'a>(
+  document: &'a ExecutableDocument,
+  operation: &'a OperationDefinition,
+) -> Vec<&'a str> {
+  let mut fields = Vec::new();
+  collect_fields(document, &operation.selection_set.node, &mut fields, 0);
+  fields
+}
+
+fn collect_fields<'a>(
+  document: &'a ExecutableDocument,
+  selection_set: &'a SelectionSet,
+  fields: &mut Vec<&'a str>,
+  depth: usize,
+) {
+  // Fragment cycles fail validation, which runs after the request is analysed.
+  if depth > document.fragments.len() {
+    return;
+  }
+  for selection in &selection_set.items {
+    match &selection.node {
+      Selection::Field(field) if field.node.name.node == "__typename" => {}
+      Selection::Field(field) => fields.push(field.node.name.node.as_str()),
+      Selection::InlineFragment(fragment) => collect_fields(
+        document,
+        &fragment.node.selection_set.node,
+        fields,
+        depth + 1,
+      ),
+      Selection::FragmentSpread(spread) => {
+        if let Some(fragment) = document.fragments.get(&spread.node.fragment_name.node) {
+          collect_fields(
+            document,
+            &fragment.node.selection_set.node,
+            fields,
+            depth + 1,
+          )
+        }
+      }
+    }
+  }
+}
@@ -0,0 +1,130 @@
+use async_graphql::{Request, ServerError};
+use serde::{de::IgnoredAny, Deserialize};
+use tauri::{
+  ipc::{CommandArg, CommandItem, CommandScope, Invoke, InvokeError},
+  Runtime,
+};
+
+use crate::{
+  operation::{operation, operation_type, root_fields},
+  Error,
+};
+
+/// The scope of the permissions generated by `mizuki_build::OperationPermissions`.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub(crate) enum Scope {
+  Field {
+    operation: String,
+    /// A root field name, or `*` for all of them.
+    field: String,
+  },
+  /// A capability checked by the [`crate::guard::RequiresCapability`] guards.
+  Capability { capability: String },
+  /// The scopes the app gives the commands for other purposes.
+  Other(IgnoredAny),
+}
+
+impl Scope {
+  fn matches_field(&self, ty: &str, name: &str) -> bool {
+    matches!(self, Self::Field { operation, field } if operation == ty && (field == "*" || field == name))
+  }
+
+  fn capability(&self) -> Option<&str> {
+    match self {
+      Self::Capability { capability } => Some(capability),
+      _ => None,
+    }
+  }
+}
+
+/// What the capabilities of a webview grant to the command it invoked.
+///
+/// The default grants nothing, and enforces nothing.
+#[derive(Default)]
+pub(crate) struct FieldPermissions {
+  scope: Option<CommandScope<Scope>>,
+  /// Whether the root fields must be allowed.
+  enforced: bool,
+}
+
+impl FieldPermissions {
+  /// The scope resolved by tauri for the command invoked.
+  pub(crate) fn resolve<R: Runtime>(
+    invoke: &Invoke<R>,
+    plugin: &'static str,
+    enforced: bool,
+  ) -> Result<Self, InvokeError> {
+    let scope = CommandScope::from_command(CommandItem {
+      plugin: Some(plugin),
+      name: "",
+      key: "",
+      message: &invoke.message,
+      acl: &invoke.acl,
+    })?;
+    Ok(Self {
+      scope: Some(scope),
+      enforced,
+    })
+  }
+
+  fn allows(&self) -> impl Iterator<Item = &Scope> {
+    self
+      .scope
+      .iter()
+      .flat_map(|scope| scope.allows())
+      .map(|scope| &**scope)
+  }
+
+  fn denies(&self) -> impl Iterator<Item = &Scope> {
+    self
+      .scope
+      .iter()
+      .flat_map(|scope| scope.denies())
+      .map(|scope| &**scope)
+  }
+
+  /// The capabilities allowed by the scope, and not denied.
+  pub(crate) fn capabilities(&self) -> impl Iterator<Item = &str> {
+    let denied = |capability: &str| {
+      self
+        .denies()
+        .any(|scope| scope.capability() == Some(capability))
+    };
+    self
+      .allows()
+      .filter_map(|scope| scope.capability())
+      .filter(move |capability| !denied(capability))
+  }
+
+  /// Check that each root field of `request` is allowed, and none is denied.
+  ///
+  /// Invalid requests pass, they fail before running anything,
+  /// and so do the introspection fields.
+  pub(crate) fn check(&self, request: &mut Request) -> Result<(), ServerError> {
+    if !self.enforced {
+      return Ok(());
+    }
+    let Some((document, definition)) = operation(request) else {
+      return Ok(());
+    };
+    let operation = operation_type(definition.ty);
+    for field in root_fields(document, definition) {
+      if field.starts_with("__") {
+        continue;
+      }
+      let matches = |scope: &Scope| scope.matches_field(operation, field);
+      let (mut allows, mut denies) = (self.allows(), self.denies());
+      if denies.any(matches) || !allows.any(matches) {
+        return Err(
+          Error::PermissionDenied {
+            operation,
+            field: field.into(),
+          }
+          .into(),
+        );
+      }
+    }
+    Ok(())
+  }
+}
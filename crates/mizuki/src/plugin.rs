@@ -1,19 +1,41 @@
-use crate::{cancel_token::CancellationTokenListener, subscription::SubscriptionRequest};
+use crate::{
+  cancel_token::CancelDispatcher,
+  codec::{self, ResponseMode},
+  delivery::{self, DeliveryPolicies},
+  guard::Capabilities,
+  incremental::Plan,
+  metrics::Metrics,
+  multicast::Multicast,
+  permissions::FieldPermissions,
+  preflight::{Preflight, Rejections},
+  protocol::CloseReason,
+  redaction::Redaction,
+  registry::SubscriptionRegistry,
+  request_limits::RequestLimits,
+  schema::{Config, PluginSchema, SchemaLimits},
+  subscription::{SubscriptionDelivery, SubscriptionItem, SubscriptionRequest, SubscriptionSink},
+  timeout::{self, Timeouts},
+  trace::{self, Cancellation, Span},
+  transport::{Connections, Transport, TransportRequest},
+  Error, REQUEST_ID_HEADER,
+};
 mod builder;
 pub use builder::{Builder, BuilderError};
 
 use async_graphql::{
-  futures_util::StreamExt, BatchRequest, ObjectType, Request, Schema, SubscriptionType,
+  futures_util::{future::join_all, StreamExt},
+  http::MultipartOptions,
+  BatchRequest, BatchResponse, ObjectType, Request, Schema, SubscriptionType,
 };
-use serde::{de::IntoDeserializer, Deserialize};
 use serde_json::Value as JsonValue;
 use std::sync::Arc;
 use tauri::{
-  ipc::{Invoke, InvokeError},
+  ipc::{Invoke, InvokeError, InvokeResponseBody, Response},
   plugin::Plugin,
-  webview::PageLoadPayload,
-  AppHandle, Emitter, EventTarget, Manager, RunEvent, Runtime, Url, Webview, Window, WindowEvent,
+  webview::{PageLoadEvent, PageLoadPayload},
+  AppHandle, Manager, RunEvent, Runtime, Url, Webview, Window, WindowEvent,
 };
+use tokio_util::sync::CancellationToken;
 
 pub(crate) type SetupHook<R, Q, M, S> = dyn FnOnce(&AppHandle<R>, JsonValue, &Schema<Q, M, S>) -> Result<(), Box<dyn std::error::Error>>
   + Send;
@@ -26,6 +48,12 @@ pub(crate) type OnSubRequst = dyn Fn(Request) -> Request + Send + Sync;
 pub(crate) type OnWindowReady<R> = dyn FnMut(Window<R>) + Send;
 pub(crate) type OnNavigation<R> = dyn Fn(&Webview<R>, &Url) -> bool + Send;
 
+/// The commands of the plugin.
+const COMMANDS: [&str; 3] = ["graphql", "subscriptions", "transport"];
+pub(crate) type Roles<R> = dyn Fn(&Webview<R>) -> Vec<String> + Send + Sync;
+pub(crate) type OnConnectionInit<R> =
+  dyn Fn(&Webview<R>, Option<JsonValue>) -> Result<Option<JsonValue>, String> + Send + Sync;
+
 pub struct MizukiPlugin<R, Q, M, S>
 where
   R: Runtime,
@@ -35,7 +63,8 @@ where
 {
   name: &'static str,
   app: Option<AppHandle<R>>,
-  schema: Schema<Q, M, S>,
+  schema: PluginSchema<Q, M, S>,
+  schema_limits: SchemaLimits,
   setup: Option<Box<SetupHook<R, Q, M, S>>>,
   js_init_script: Option<String>,
   on_page_load: Box<OnPageLoad<R>>,
@@ -47,7 +76,22 @@ where
   on_window_ready: Box<OnWindowReady<R>>,
   on_navigation: Box<OnNavigation<R>>,
   auto_cancel: bool,
-  sub_end_event_label: String,
+  cancel_dispatcher: Arc<CancelDispatcher<R>>,
+  subscription_delivery: SubscriptionDelivery,
+  on_connection_init: Arc<Box<OnConnectionInit<R>>>,
+  connections: Arc<Connections>,
+  multipart_options: MultipartOptions,
+  response_mode: ResponseMode,
+  preflight: Arc<Preflight>,
+  timeouts: Arc<Timeouts>,
+  delivery_policies: Arc<DeliveryPolicies>,
+  registry: SubscriptionRegistry,
+  metrics: Metrics,
+  multicast: Arc<Multicast<R>>,
+  redaction: Arc<Redaction>,
+  request_limits: RequestLimits,
+  field_permissions: bool,
+  roles: Box<Roles<R>>,
 }
 
 impl<R, Q, M, S> Drop for MizukiPlugin<R, Q, M, S>
@@ -64,6 +108,20 @@ where
   }
 }
 
+impl<R, Q, M, S> MizukiPlugin<R, Q, M, S>
+where
+  R: Runtime,
+  Q: ObjectType + 'static,
+  M: ObjectType + 'static,
+  S: SubscriptionType + 'static,
+{
+  /// Close what belongs to the page a webview leaves, its budgets stay with its window.
+  fn leave_page(&self, webview: &str) {
+    self.connections.close(webview);
+    self.cancel_dispatcher.leave_page(webview);
+  }
+}
+
 impl<R, Q, M, S> Plugin<R> for MizukiPlugin<R, Q, M, S>
 where
   R: Runtime,
@@ -80,10 +138,30 @@ where
     app: &AppHandle<R>,
     config: JsonValue,
   ) -> Result<(), Box<dyn std::error::Error>> {
-    let _ = config;
     self.app.replace(app.clone());
+    let plugin_config: Config = if config.is_object() {
+      serde_json::from_value(config.clone())?
+    } else {
+      Config::default()
+    };
+    let schema = self
+      .schema
+      .finish(self.schema_limits.overridden_by(plugin_config.limits))?;
+    // The plugins of an app share the first registry and metrics.
+    match app.try_state::<SubscriptionRegistry>() {
+      Some(registry) => self.registry = registry.inner().clone(),
+      None => {
+        app.manage(self.registry.clone());
+      }
+    }
+    match app.try_state::<Metrics>() {
+      Some(metrics) => self.metrics = metrics.inner().clone(),
+      None => {
+        app.manage(self.metrics.clone());
+      }
+    }
     if let Some(s) = self.setup.take() {
-      (s)(app, config, &self.schema)?;
+      (s)(app, config, schema)?;
     }
     Ok(())
   }
@@ -93,130 +171,328 @@ where
   }
 
   fn on_page_load(&mut self, window: &Webview<R>, payload: &PageLoadPayload<'_>) {
+    // Only the page load tells the webview left its page: another plugin may still
+    // prevent a navigation after this one allows it.
+    if payload.event() == PageLoadEvent::Started {
+      self.leave_page(window.label());
+    }
     (self.on_page_load)(window, payload)
   }
 
   fn on_event(&mut self, app: &AppHandle<R>, event: &RunEvent) {
+    if let RunEvent::WindowEvent {
+      label,
+      event: WindowEvent::Destroyed,
+      ..
+    } = event
+    {
+      self.connections.close_window(label);
+      self.cancel_dispatcher.close_window(label);
+      self.preflight.webview_limiter.close_window(label);
+    }
     (self.on_event)(app, event)
   }
 
   fn extend_api(&mut self, invoke: Invoke<R>) -> bool {
     let on_batch_request = self.on_batch_request.clone();
     let on_sub_request = self.on_sub_request.clone();
-    let sub_end_event_label = self.sub_end_event_label.clone();
+    let cancel_dispatcher = self.cancel_dispatcher.clone();
     let auto_cancel = self.auto_cancel;
+    let subscription_delivery = self.subscription_delivery;
+    let multipart_options = self.multipart_options;
+    let response_mode = self.response_mode;
+    let preflight = self.preflight.clone();
+    let timeouts = self.timeouts.clone();
+    let delivery_policies = self.delivery_policies.clone();
+    let registry = self.registry.clone();
+    let metrics = self.metrics.clone();
+    let multicast = self.multicast.clone();
+    let redaction = self.redaction.clone();
+    let request_limits = self.request_limits;
+    let plugin_name = self.name;
 
-    let schema = self.schema.clone();
+    let Some(schema) = self.schema.get().cloned() else {
+      invoke.resolver.reject(Error::NotInitialized);
+      return true;
+    };
+    let command = invoke.message.command();
+    if !COMMANDS.contains(&command) {
+      invoke
+        .resolver
+        .reject(Error::UnknownEndpoint(command.into()));
+      return true;
+    }
+    let permissions = match FieldPermissions::resolve(&invoke, plugin_name, self.field_permissions)
+    {
+      Ok(permissions) => permissions,
+      Err(error) => {
+        invoke.resolver.invoke_error(error);
+        return true;
+      }
+    };
+    let capabilities: Capabilities = permissions
+      .capabilities()
+      .map(String::from)
+      .chain((self.roles)(&invoke.message.webview()))
+      .collect();
 
     match invoke.message.command() {
-      "graphql" => invoke.resolver.respond_async(async move {
-        let req: BatchRequest = match invoke.message.payload() {
-          tauri::ipc::InvokeBody::Json(value) => {
-            serde_json::from_value(value.clone()).map_err(InvokeError::from_error)?
-          }
-          tauri::ipc::InvokeBody::Raw(vec) => {
-            Deserialize::deserialize(vec.clone().into_deserializer())
-              .map_err(|e: serde_json::Error| InvokeError::from_error(e))?
-          }
-        };
+      "graphql" => {
+        let span = Span::graphql(plugin_name, invoke.message.webview().label());
+        invoke.resolver.respond_async(span.instrument(async move {
+          let (payload, headers) = (invoke.message.payload(), invoke.message.headers());
+          request_limits.check_body(payload)?;
+          let req: BatchRequest = codec::decode_batch_request(payload, headers, multipart_options)
+            .await
+            .map_err(Error::DecodeFailed)?;
+          request_limits.check_batch(&req)?;
+          let response_codec = codec::response_codec(payload, headers)
+            .map_err(Error::DecodeFailed)?
+            .or(response_mode.codec());
 
-        let resp = schema
-          .execute_batch((on_batch_request)(
-            req
-              .data(invoke.message.webview().app_handle().clone())
-              .data(invoke.message.webview())
-              .data(invoke.message.webview().window()),
-          ))
-          .await;
-
-        let str = serde_json::to_string(&resp).map_err(InvokeError::from_error)?;
-
-        Ok((str, resp.is_ok()))
-      }),
-      "subscriptions" => invoke.resolver.respond_async(async move {
-        let window = invoke.message.webview();
-        let req: SubscriptionRequest = match invoke.message.payload() {
-          tauri::ipc::InvokeBody::Json(value) => {
-            serde_json::from_value(value.clone()).map_err(InvokeError::from_error)?
-          }
-          tauri::ipc::InvokeBody::Raw(vec) => {
-            Deserialize::deserialize(vec.clone().into_deserializer())
-              .map_err(|e: serde_json::Error| InvokeError::from_error(e))?
-          }
-        };
+          let webview = invoke.message.webview();
+          let request_id = headers
+            .get(REQUEST_ID_HEADER)
+            .map(|id| String::from_utf8_lossy(id.as_bytes()));
+          let cancel_guard = cancel_dispatcher.register(&webview, request_id.as_deref());
+          let cancel_token = cancel_guard.token();
+          let _d = cancel_token.drop_guard_ref();
 
-        let subscription_webview = window.clone();
-        let webwiew_cancel_token = CancellationTokenListener::new(
-          subscription_webview.clone(),
-          sub_end_event_label,
-          req.sub_id.clone(),
-        );
-        let cancel_token = webwiew_cancel_token.token();
-        let mut stream = schema.execute_stream((on_sub_request)(
-          req
-            .inner
-            .data(invoke.message.webview().app_handle().clone())
-            .data(invoke.message.webview())
-            .data(invoke.message.webview().window())
-            .data(webwiew_cancel_token.token().clone()),
-        ));
-
-        let _ca = {
-          let cancel_token = Arc::new(cancel_token.clone());
-          // This will prevent the cancellation token for going out of scope
-          let weak_cancel_token = Arc::downgrade(&cancel_token);
-          subscription_webview.window().on_window_event(move |event| {
-            if let WindowEvent::Destroyed = event {
-              if let Some(token) = weak_cancel_token.upgrade() {
-                  token.cancel();
-              }
+          let (req, rejected) = preflight.check_batch(
+            req,
+            &webview,
+            &permissions,
+            Rejections {
+              metrics: &metrics,
+              plugin: plugin_name,
+            },
+          );
+          let resp = match req {
+            Some(req) => {
+              let cancelled = match &req {
+                BatchRequest::Single(_) => BatchResponse::Single(cancelled_response()),
+                BatchRequest::Batch(requests) => {
+                  BatchResponse::Batch(requests.iter().map(|_| cancelled_response()).collect())
+                }
+              };
+              let req = req
+                .data(webview.app_handle().clone())
+                .data(webview.clone())
+                .data(webview.window())
+                .data(cancel_token.clone())
+                .data(capabilities);
+              let req = (on_batch_request)(req);
+              let execute = |mut request: Request| {
+                let span = Span::operation(&mut request);
+                let request_metrics = metrics.request(plugin_name, &mut request);
+                let execution =
+                  timeout::execute(timeouts.of(&mut request), schema.execute(request));
+                span.instrument(async move {
+                  let response = execution.await;
+                  request_metrics.finish(response.is_err());
+                  response
+                })
+              };
+              let execution = async {
+                match req {
+                  BatchRequest::Single(request) => BatchResponse::Single(execute(request).await),
+                  BatchRequest::Batch(requests) => {
+                    BatchResponse::Batch(join_all(requests.into_iter().map(execute)).await)
+                  }
+                }
+              };
+              Some(tokio::select! {
+                resp = execution => resp,
+                _ = cancel_token.cancelled() => cancelled,
+              })
             }
-          });
-          cancel_token
-        };
+            None => None,
+          };
+          let mut resp = rejected.merge(resp);
+          redaction.batch_response(&mut resp);
 
-        let _d = cancel_token.drop_guard_ref();
+          let body = match response_codec {
+            Some(codec) => codec.encode_body(&resp).map_err(Error::EncodeFailed)?,
+            None => {
+              let str =
+                serde_json::to_string(&resp).map_err(|error| Error::EncodeFailed(error.into()))?;
+              InvokeResponseBody::Json(
+                serde_json::to_string(&(str, resp.is_ok()))
+                  .map_err(|error| Error::EncodeFailed(error.into()))?,
+              )
+            }
+          };
 
-        let event_id = &format!("graphql://{}", req.id);
-        if auto_cancel {
-          loop {
-            tokio::select! {
-              _ = cancel_token.cancelled() => {
-                // println!("end cancelled");
-                break;
-              },
-              res = stream.next() => {
-                if let Some(result) = res {
-                  let str = serde_json::to_string(&result).map_err(InvokeError::from_error)?;
-
-                  subscription_webview.emit_to(EventTarget::Webview{label: subscription_webview.label().into()},event_id, str)?;
-                }else {
-                  // println!("end stream");
-                  break;
-                }
-              },
-              else => {
-                // println!("end!");
-                break;
-              }
+          Ok(Response::new(body))
+        }))
+      }
+      "subscriptions" => {
+        let span = Span::subscription(plugin_name, invoke.message.webview().label());
+        invoke.resolver.respond_async(span.instrument(async move {
+          let window = invoke.message.webview();
+          let (payload, headers) = (invoke.message.payload(), invoke.message.headers());
+          request_limits.check_body(payload)?;
+          let req: SubscriptionRequest =
+            codec::decode(payload, headers).map_err(Error::DecodeFailed)?;
+          request_limits.check(&req.inner)?;
+          let response_codec =
+            codec::response_codec(payload, headers).map_err(Error::DecodeFailed)?;
+
+          let subscription_webview = window.clone();
+          let sink = SubscriptionSink::new(
+            subscription_delivery,
+            response_codec,
+            &subscription_webview,
+            &req,
+          )?;
+          let cancel_guard = cancel_dispatcher.register(&subscription_webview, Some(&req.sub_id));
+          let cancel_token = cancel_guard.token();
+          let page_token = cancel_guard.page_token();
+          let prepare = |request: Request, cancel_token: CancellationToken| {
+            (on_sub_request)(
+              request
+                .data(invoke.message.webview().app_handle().clone())
+                .data(invoke.message.webview())
+                .data(invoke.message.webview().window())
+                .data(cancel_token)
+                .data(capabilities.clone()),
+            )
+          };
+          let mut request = match preflight.check(
+            req.inner,
+            &subscription_webview,
+            &permissions,
+            Rejections {
+              metrics: &metrics,
+              plugin: plugin_name,
+            },
+            true,
+          ) {
+            Ok(request) => request,
+            Err(error) => {
+              sink.send(&async_graphql::Response::from_errors(vec![error]))?;
+              return Ok(sink.end()?);
             }
-          }
-        } else {
-          while let Some(result) = stream.next().await {
-            let str = serde_json::to_string(&result).map_err(InvokeError::from_error)?;
+          };
+          trace::record_subscription(&req.sub_id, &mut request);
+          let timeout = timeouts.of(&mut request);
+          let delivery_policy = delivery_policies.of(&mut request);
+          let mut subscription_metrics = metrics.subscription(plugin_name, &mut request);
+          let registration = registry.register(
+            plugin_name,
+            subscription_webview.label(),
+            &req.sub_id,
+            &mut request,
+            &cancel_token,
+          );
+          let multicast_key = multicast.key(&mut request, &subscription_webview, &capabilities);
+          let stream = match (Plan::new(&mut request), multicast_key) {
+            (Some(plan), _) => redaction.stream(
+              plan
+                .execute(schema, |request| prepare(request, cancel_token.clone()))
+                .map(SubscriptionItem::Incremental)
+                .boxed(),
+              Redaction::item,
+            ),
+            (None, Some(key)) => multicast
+              .subscribe(key, |upstream_token| {
+                redaction.stream(
+                  schema.execute_stream(prepare(request, upstream_token)),
+                  Redaction::response,
+                )
+              })
+              .map(SubscriptionItem::Response)
+              .boxed(),
+            (None, None) => redaction
+              .stream(
+                schema.execute_stream(prepare(request, cancel_token.clone())),
+                Redaction::response,
+              )
+              .map(SubscriptionItem::Response)
+              .boxed(),
+          };
+          let stream = timeout::stream(
+            delivery::pace(stream, delivery_policy),
+            timeout,
+            |timeout| {
+              SubscriptionItem::Response(async_graphql::Response::from_errors(vec![
+                timeout::timed_out(timeout),
+              ]))
+            },
+          );
+          let stream = registration.track(stream);
+          trace::subscription_started();
+
+          let _d = cancel_token.drop_guard_ref();
 
-            subscription_webview.emit_to(EventTarget::Webview{label: subscription_webview.label().into()},event_id, str)?;
+          sink
+            .forward(
+              stream,
+              if auto_cancel {
+                &cancel_token
+              } else {
+                &page_token
+              },
+              |item, len| {
+                registration.sent(len);
+                subscription_metrics.emitted(len, item.has_errors());
+              },
+            )
+            .await?;
+          if page_token.is_cancelled() {
+            subscription_metrics.cancelled();
+            trace::subscription_cancelled(Cancellation::PageLeft);
+          } else if cancel_token.is_cancelled() {
+            subscription_metrics.cancelled();
+            trace::subscription_cancelled(Cancellation::Cancelled);
+          } else {
+            trace::subscription_completed();
           }
-        }
 
-        subscription_webview.emit_to(EventTarget::Webview{label: subscription_webview.label().into()}, event_id, Option::<()>::None)?;
+          sink.end()?;
 
-        Ok(())
-      }),
-      cmd => invoke.resolver.reject(format!(
-        "Invalid endpoint \"{}\". Valid endpoints are: \"graphql\", \"subscriptions\".",
-        cmd
-      )),
+          Ok(())
+        }))
+      }
+      "transport" => {
+        let webview = invoke.message.webview();
+        let transport = Transport {
+          schema,
+          connections: self.connections.clone(),
+          on_connection_init: self.on_connection_init.clone(),
+          on_sub_request,
+          preflight,
+          timeouts,
+          delivery_policies,
+          registry,
+          metrics,
+          plugin_name,
+          multicast,
+          redaction,
+          request_limits,
+          permissions,
+          capabilities,
+        };
+        let span = Span::transport(plugin_name, webview.label());
+        let res = span.in_scope(|| {
+          let req = request_limits
+            .check_body(invoke.message.payload())
+            .map_err(|e| e.to_string())
+            .and_then(|()| {
+              codec::decode::<TransportRequest>(invoke.message.payload(), invoke.message.headers())
+                .map_err(|e| e.to_string())
+            });
+          match req {
+            Ok(req) => transport.handle(&webview, req),
+            Err(e) => {
+              self.connections.close(webview.label());
+              Err(CloseReason::bad_request(e))
+            }
+          }
+        });
+        invoke.resolver.respond(res.map_err(InvokeError::from));
+      }
+      _ => unreachable!("the command was checked"),
     }
     true
   }
@@ -233,3 +509,47 @@ where
     (self.on_navigation)(webview, url)
   }
 }
+
+/// The response of a request cancelled by the webview.
+fn cancelled_response() -> async_graphql::Response {
+  async_graphql::Response::from_errors(vec![Error::Cancelled.into()])
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::WebviewLimits;
+  use async_graphql::{EmptyMutation, EmptySubscription, Object};
+  use tauri::{
+    test::{mock_app, MockRuntime},
+    WebviewUrl, WebviewWindowBuilder,
+  };
+
+  struct Query;
+
+  #[Object]
+  impl Query {
+    async fn a(&self) -> i32 {
+      1
+    }
+  }
+
+  #[test]
+  fn a_reload_does_not_renew_the_budget() {
+    let app = mock_app();
+    let window = WebviewWindowBuilder::new(&app, "remote", WebviewUrl::default())
+      .build()
+      .unwrap();
+    let webview: Webview<MockRuntime> = window.as_ref().clone();
+    let plugin = Builder::<MockRuntime, _, _, _>::new(
+      "test",
+      Schema::new(Query, EmptyMutation, EmptySubscription),
+    )
+    .webview_limits(|_, _| WebviewLimits::new().complexity_per_minute(1))
+    .build();
+    let limiter = &plugin.preflight.webview_limiter;
+    assert!(limiter.check(&mut Request::new("{ a }"), &webview).is_ok());
+    plugin.leave_page("remote");
+    assert!(limiter.check(&mut Request::new("{ a }"), &webview).is_err());
+  }
+}
@@ -1,11 +1,31 @@
-use async_graphql::{BatchRequest, ObjectType, Request, Schema, SubscriptionType};
+use async_graphql::{
+  extensions::ExtensionFactory, http::MultipartOptions, parser::types::OperationType, BatchRequest,
+  ObjectType, Request, Schema, SchemaBuilder, ServerError, SubscriptionType,
+};
 use serde_json::Value as JsonValue;
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 use tauri::{webview::PageLoadPayload, AppHandle, RunEvent, Runtime, Url, Webview, Window};
 
+use crate::{
+  access::{AccessControl, UrlPattern},
+  apq::{PersistedQueries, PersistedQueryStore},
+  cancel_token::CancelDispatcher,
+  codec::ResponseMode,
+  delivery::{DeliveryPolicies, DeliveryPolicy},
+  multicast::{Multicast, MulticastKey},
+  preflight::Preflight,
+  redaction::Redaction,
+  request_limits::RequestLimits,
+  schema::{PluginSchema, SchemaLimits},
+  timeout::Timeouts,
+  trusted_documents::TrustedDocuments,
+  webview_limits::{WebviewLimiter, WebviewLimits, WebviewLimitsPolicy},
+  SubscriptionDelivery,
+};
+
 use super::{
-  MizukiPlugin, OnBatchRequest, OnDrop, OnEvent, OnNavigation, OnPageLoad, OnSubRequst,
-  OnWebviewReady, OnWindowReady, SetupHook,
+  MizukiPlugin, OnBatchRequest, OnConnectionInit, OnDrop, OnEvent, OnNavigation, OnPageLoad,
+  OnSubRequst, OnWebviewReady, OnWindowReady, Roles, SetupHook,
 };
 
 /// Errors that can happen during [`Builder`].
@@ -15,6 +35,12 @@ pub enum BuilderError {
   /// Plugin attempted to use a reserved name.
   #[error("plugin uses reserved name: {0}")]
   ReservedName(String),
+  /// Extensions or limits, from the builder or the plugin config, were given with a schema
+  /// that is already built.
+  #[error(
+    "plugin schema is already built, use Builder::with_schema_builder to extend or limit it"
+  )]
+  SchemaBuilt,
 }
 
 const RESERVED_PLUGIN_NAMES: &[&str] = &["core", "tauri"];
@@ -30,7 +56,10 @@ where
   S: SubscriptionType + 'static,
 {
   name: &'static str,
-  schema: Schema<Q, M, S>,
+  schema: PluginSchema<Q, M, S>,
+  schema_limits: SchemaLimits,
+  /// Whether a setting only a [`SchemaBuilder`] can take was used.
+  requires_schema_builder: bool,
   setup: Option<Box<SetupHook<R, Q, M, S>>>,
   js_init_script: Option<String>,
   on_page_load: Box<OnPageLoad<R>>,
@@ -43,6 +72,21 @@ where
   on_navigation: Box<OnNavigation<R>>,
   auto_cancel: bool,
   sub_event_label: String,
+  subscription_delivery: SubscriptionDelivery,
+  on_connection_init: Box<OnConnectionInit<R>>,
+  multipart_options: MultipartOptions,
+  response_mode: ResponseMode,
+  persisted_query_store: Option<Box<dyn PersistedQueryStore>>,
+  trusted_documents: Option<TrustedDocuments>,
+  access: AccessControl,
+  timeouts: Timeouts,
+  delivery_policies: DeliveryPolicies,
+  multicast_key: Option<Box<MulticastKey<R>>>,
+  redaction: Redaction,
+  webview_limits: Option<Box<WebviewLimitsPolicy>>,
+  request_limits: RequestLimits,
+  field_permissions: bool,
+  roles: Box<Roles<R>>,
 }
 
 impl<R, Q, M, S> Builder<R, Q, M, S>
@@ -54,9 +98,36 @@ where
 {
   /// Creates a new Plugin builder.
   pub fn new(name: &'static str, schema: Schema<Q, M, S>) -> Self {
+    Self::with_schema(name, PluginSchema::Built(schema))
+  }
+  /// Creates a new Plugin builder from a schema that isn't finished yet.
+  ///
+  /// The plugin finishes it when initialized, so it can take [`Self::extension`]s
+  /// and limits, from the builder or from the plugin config:
+  ///
+  /// ```json
+  /// {
+  ///   "plugins": {
+  ///     "todo-plugin": {
+  ///       "limitDepth": 10,
+  ///       "limitComplexity": 500
+  ///     }
+  ///   }
+  /// }
+  /// ```
+  ///
+  /// The config of a plugin made with [`Self::new`] can't set any limits: they fail its
+  /// initialization with [`BuilderError::SchemaBuilt`].
+  /// The extensions can't be set from the config.
+  pub fn with_schema_builder(name: &'static str, schema: SchemaBuilder<Q, M, S>) -> Self {
+    Self::with_schema(name, PluginSchema::Pending(Box::new(schema)))
+  }
+  fn with_schema(name: &'static str, schema: PluginSchema<Q, M, S>) -> Self {
     Self {
       name,
       schema,
+      schema_limits: SchemaLimits::default(),
+      requires_schema_builder: false,
       on_batch_request: Box::new(|r| r),
       on_sub_request: Box::new(|s| s),
       setup: None,
@@ -69,6 +140,21 @@ where
       on_navigation: Box::new(|_, _| true),
       auto_cancel: true,
       sub_event_label: "sub_end".into(),
+      subscription_delivery: SubscriptionDelivery::default(),
+      on_connection_init: Box::new(|_, _| Ok(None)),
+      multipart_options: MultipartOptions::default(),
+      response_mode: ResponseMode::default(),
+      persisted_query_store: None,
+      trusted_documents: None,
+      access: AccessControl::default(),
+      timeouts: Timeouts::default(),
+      delivery_policies: DeliveryPolicies::default(),
+      multicast_key: None,
+      redaction: Redaction::default(),
+      webview_limits: None,
+      request_limits: RequestLimits::default(),
+      field_permissions: false,
+      roles: Box::new(|_| Vec::new()),
     }
   }
   /// Same as [`tauri::plugin::Builder::js_init_script`]
@@ -181,23 +267,322 @@ where
     self
   }
   /// Prevent the plugin for canceling subscription internally.
-  /// But the subscription will still be cancelled if the window is reloaded or destroyed.
+  /// But the subscription will still be cancelled if the webview reloads or navigates away,
+  /// or if its window is destroyed.
   #[must_use]
   pub fn auto_cancel(mut self, auto_cancel: bool) -> Self {
     self.auto_cancel = auto_cancel;
     self
   }
 
-  /// Modify the subscription cancel event label
+  /// Modify the label of the event cancelling a subscription,
+  /// or a `graphql` request sent with a [`crate::REQUEST_ID_HEADER`].
   ///
-  /// Default: sub_event
+  /// Default: sub_end
   pub fn subscription_end_label(mut self, label: String) -> Self {
     self.sub_event_label = label;
     self
   }
+  /// Choose how the subscription results are delivered to the webview.
+  ///
+  /// Default: [`SubscriptionDelivery::Auto`]
+  #[must_use]
+  pub fn subscription_delivery(mut self, delivery: SubscriptionDelivery) -> Self {
+    self.subscription_delivery = delivery;
+    self
+  }
+  /// Pace the results of the subscriptions, see [`DeliveryPolicy`].
+  ///
+  /// Default: every result is sent as soon as it is produced
+  #[must_use]
+  pub fn delivery_policy(mut self, policy: DeliveryPolicy) -> Self {
+    self.delivery_policies.default = policy;
+    self
+  }
+  /// Pace the results of the subscriptions to the root field `field`,
+  /// overriding [`Self::delivery_policy`].
+  #[must_use]
+  pub fn field_delivery_policy(mut self, field: impl Into<String>, policy: DeliveryPolicy) -> Self {
+    self.delivery_policies.fields.insert(field.into(), policy);
+    self
+  }
+  /// Run the identical subscriptions once, sharing their results between their webviews.
+  ///
+  /// Subscriptions are identical if they have the same query, operation name and variables,
+  /// if their webviews hold the same capabilities, and if `context_key` gives the same key
+  /// for their webviews.
+  ///
+  /// Each subscription is checked on its own, but the shared stream is the one of the
+  /// first subscriber: its resolvers only see the data of the first subscriber, like its
+  /// webview. The key must tell apart the webviews they would treat differently.
+  /// The stream stops when the last subscriber is gone.
+  ///
+  /// A subscriber falling too far behind misses some results, and gets a `LAGGED` error instead.
+  ///
+  /// Default: every subscription runs its own stream
+  #[must_use]
+  pub fn multicast_subscriptions<F>(mut self, context_key: F) -> Self
+  where
+    F: Fn(&Webview<R>) -> String + Send + Sync + 'static,
+  {
+    self.multicast_key = Some(Box::new(context_key));
+    self
+  }
+  /// Limit the files uploaded with a multipart `graphql` request.
+  ///
+  /// Default: no limit
+  #[must_use]
+  pub fn multipart_options(mut self, options: MultipartOptions) -> Self {
+    self.multipart_options = options;
+    self
+  }
+  /// Set how the `graphql` command answers, see [`ResponseMode`].
+  ///
+  /// Default: [`ResponseMode::Tuple`]
+  #[must_use]
+  pub fn response_mode(mut self, mode: ResponseMode) -> Self {
+    self.response_mode = mode;
+    self
+  }
+  /// Enable [automatic persisted queries](crate::apq), kept in `store`.
+  ///
+  /// Default: disabled
+  #[must_use]
+  pub fn persisted_queries<P: PersistedQueryStore + 'static>(mut self, store: P) -> Self {
+    self.persisted_query_store = Some(Box::new(store));
+    self
+  }
+  /// Only accept the operations of a [trusted documents](crate::trusted_documents) manifest.
+  ///
+  /// Default: any operation is accepted
+  #[must_use]
+  pub fn trusted_documents(mut self, trusted_documents: TrustedDocuments) -> Self {
+    self.trusted_documents = Some(trusted_documents);
+    self
+  }
+  /// Allow an operation type only from the pages matching `pattern`,
+  /// or the ones of the other calls of this method for the same operation type.
+  ///
+  /// ```rust,ignore
+  /// builder
+  ///   .allow_url(OperationType::Mutation, UrlPattern::new().scheme("tauri"))
+  ///   .allow_url(OperationType::Subscription, UrlPattern::new().scheme("tauri"))
+  /// ```
+  ///
+  /// The other pages get an `ACCESS_DENIED` error, which is also logged.
+  ///
+  /// Default: any page is allowed
+  #[must_use]
+  pub fn allow_url(mut self, ty: OperationType, pattern: UrlPattern) -> Self {
+    self.access.operation_mut(ty).allow.push(pattern);
+    self
+  }
+  /// Deny an operation type to the pages matching `pattern`, even if they are allowed
+  /// by [`Self::allow_url`].
+  #[must_use]
+  pub fn deny_url(mut self, ty: OperationType, pattern: UrlPattern) -> Self {
+    self.access.operation_mut(ty).deny.push(pattern);
+    self
+  }
+  /// Only run the root fields granted by the capabilities of the webview,
+  /// through the permissions generated by `mizuki_build::OperationPermissions`:
+  ///
+  /// ```json
+  /// {
+  ///   "identifier": "settings",
+  ///   "windows": ["settings"],
+  ///   "permissions": ["chat:allow-queries", "chat:allow-mutation-send-message"]
+  /// }
+  /// ```
+  ///
+  /// A root field that isn't allowed, or is denied, gets a `PERMISSION_DENIED` error.
+  ///
+  /// Default: the capabilities only grant the commands
+  #[must_use]
+  pub fn field_permissions(mut self, enforce: bool) -> Self {
+    self.field_permissions = enforce;
+    self
+  }
+  /// Give the webviews capabilities of their own, like custom roles,
+  /// on top of the ones granted by their tauri capabilities.
+  ///
+  /// They are checked by the [`crate::guard::RequiresCapability`] guards:
+  ///
+  /// ```rust,ignore
+  /// builder.roles(|webview| match webview.label() {
+  ///   "settings" => vec!["admin".into()],
+  ///   _ => Vec::new(),
+  /// })
+  /// ```
+  #[must_use]
+  pub fn roles<F>(mut self, roles: F) -> Self
+  where
+    F: Fn(&Webview<R>) -> Vec<String> + Send + Sync + 'static,
+  {
+    self.roles = Box::new(roles);
+    self
+  }
+  /// Abort the queries and mutations running longer than `timeout`,
+  /// answering them with a `TIMEOUT` error.
+  ///
+  /// Subscriptions aren't concerned, see [`Self::operation_timeout`].
+  ///
+  /// Default: no timeout
+  #[must_use]
+  pub fn timeout(mut self, timeout: Duration) -> Self {
+    self.timeouts.default = Some(timeout);
+    self
+  }
+  /// Set the timeout of an operation type, overriding [`Self::timeout`].
+  ///
+  /// A timed out subscription gets a `TIMEOUT` error, then ends.
+  #[must_use]
+  pub fn operation_timeout(mut self, ty: OperationType, timeout: Duration) -> Self {
+    self.timeouts.operation_mut(ty).timeout = Some(timeout);
+    self
+  }
+  /// Set the timeout of the operations selecting the root field `field` of an operation type.
+  ///
+  /// An operation selecting several root fields is allowed the longest of their timeouts,
+  /// or their sum for a mutation, whose root fields run one after the other.
+  #[must_use]
+  pub fn field_timeout(
+    mut self,
+    ty: OperationType,
+    field: impl Into<String>,
+    timeout: Duration,
+  ) -> Self {
+    self
+      .timeouts
+      .operation_mut(ty)
+      .fields
+      .insert(field.into(), timeout);
+    self
+  }
+  /// Replace the messages of the resolver errors with a generic one,
+  /// so internal details like file paths or SQL errors don't reach the webview.
+  ///
+  /// A redacted error only keeps its `path`, `locations` and `extensions.code`,
+  /// along with an `extensions.correlationId` to find the original in the logs
+  /// of [`Self::on_error`].
+  /// The errors about the request itself, like validation errors, are left alone.
+  ///
+  /// Default: enabled in release builds
+  #[must_use]
+  pub fn redact_errors(mut self, redact: bool) -> Self {
+    self.redaction.enabled = redact;
+    self
+  }
+  /// The message of the redacted errors.
+  ///
+  /// Default: `"Internal server error"`
+  #[must_use]
+  pub fn redacted_error_message(mut self, message: impl Into<String>) -> Self {
+    self.redaction.message = message.into();
+    self
+  }
+  /// Register a callback receiving each resolver error, before its redaction,
+  /// with its correlation id.
+  ///
+  /// The correlation id is also added to the error sent to the webview when it isn't redacted.
+  ///
+  /// Default: the redacted errors are logged with [`log::error!`]
+  #[must_use]
+  pub fn on_error<F>(mut self, on_error: F) -> Self
+  where
+    F: Fn(&ServerError, &str) + Send + Sync + 'static,
+  {
+    self.redaction.on_error = Some(Box::new(on_error));
+    self
+  }
+  /// Register an async-graphql extension on the schema.
+  ///
+  /// Only a plugin made with [`Self::with_schema_builder`] can take one,
+  /// [`Self::try_build`] fails otherwise.
+  #[must_use]
+  pub fn extension(mut self, extension: impl ExtensionFactory) -> Self {
+    self.schema = match self.schema {
+      PluginSchema::Pending(schema) => {
+        PluginSchema::Pending(Box::new((*schema).extension(extension)))
+      }
+      schema => {
+        self.requires_schema_builder = true;
+        schema
+      }
+    };
+    self
+  }
+  /// Limit the depth of the queries, like [`SchemaBuilder::limit_depth`].
+  ///
+  /// Overridden by the `limitDepth` of the plugin config.
+  /// Only a plugin made with [`Self::with_schema_builder`] can take it,
+  /// [`Self::try_build`] fails otherwise.
+  #[must_use]
+  pub fn limit_depth(mut self, depth: usize) -> Self {
+    self.schema_limits.limit_depth = Some(depth);
+    self
+  }
+  /// Limit the complexity of the queries, like [`SchemaBuilder::limit_complexity`].
+  ///
+  /// Overridden by the `limitComplexity` of the plugin config.
+  /// Only a plugin made with [`Self::with_schema_builder`] can take it,
+  /// [`Self::try_build`] fails otherwise.
+  #[must_use]
+  pub fn limit_complexity(mut self, complexity: usize) -> Self {
+    self.schema_limits.limit_complexity = Some(complexity);
+    self
+  }
+  /// Limit the operations of each webview, depending on its label and current URL.
+  ///
+  /// The budgets of a webview are kept across its page loads, and forgotten with its window.
+  ///
+  /// ```rust,ignore
+  /// builder.webview_limits(|label, url| match (label, url.scheme()) {
+  ///   ("main", "tauri") => WebviewLimits::new(),
+  ///   _ => WebviewLimits::new().max_depth(5).complexity_per_minute(500),
+  /// })
+  /// ```
+  ///
+  /// Default: the limits of the schema only
+  #[must_use]
+  pub fn webview_limits<F>(mut self, policy: F) -> Self
+  where
+    F: Fn(&str, &Url) -> WebviewLimits + Send + Sync + 'static,
+  {
+    self.webview_limits = Some(Box::new(policy));
+    self
+  }
+  /// Limit the size of the requests, checked as soon as they are decoded.
+  ///
+  /// ```rust,ignore
+  /// builder.request_limits(RequestLimits::new().max_batch_size(10).max_aliases(30))
+  /// ```
+  ///
+  /// Default: no limits
+  #[must_use]
+  pub fn request_limits(mut self, limits: RequestLimits) -> Self {
+    self.request_limits = limits;
+    self
+  }
+  /// Register a callback when a webview opens a [`crate::protocol`] connection.
+  ///
+  /// It receives the `connection_init` payload and returns the `connection_ack` payload.
+  /// Returning an error refuses the connection.
+  #[must_use]
+  pub fn on_connection_init<F>(mut self, on_connection_init: F) -> Self
+  where
+    F: Fn(&Webview<R>, Option<JsonValue>) -> Result<Option<JsonValue>, String>
+      + Send
+      + Sync
+      + 'static,
+  {
+    self.on_connection_init = Box::new(on_connection_init);
+    self
+  }
   /// Build the [`crate::MizukiPlugin`]
   ///
-  /// It returns an error if your plugin name is reserved.
+  /// It returns an error if your plugin name is reserved,
+  /// or if the schema was built before taking extensions or limits.
   ///
   /// ## List of reserved names
   ///
@@ -208,10 +593,15 @@ where
     if let Some(&reserved) = RESERVED_PLUGIN_NAMES.iter().find(|&r| r == &self.name) {
       return Err(BuilderError::ReservedName(reserved.into()));
     }
+    let requires_schema_builder = self.requires_schema_builder || !self.schema_limits.is_empty();
+    if requires_schema_builder && matches!(self.schema, PluginSchema::Built(_)) {
+      return Err(BuilderError::SchemaBuilt);
+    }
     Ok(MizukiPlugin {
       name: self.name,
       app: None,
       schema: self.schema,
+      schema_limits: self.schema_limits,
       setup: self.setup,
       js_init_script: self.js_init_script,
       on_page_load: self.on_page_load,
@@ -223,7 +613,27 @@ where
       on_navigation: self.on_navigation,
       on_window_ready: self.on_window_ready,
       auto_cancel: self.auto_cancel,
-      sub_end_event_label: self.sub_event_label,
+      cancel_dispatcher: Arc::new(CancelDispatcher::new(self.sub_event_label)),
+      subscription_delivery: self.subscription_delivery,
+      on_connection_init: Arc::new(self.on_connection_init),
+      connections: Default::default(),
+      multipart_options: self.multipart_options,
+      response_mode: self.response_mode,
+      preflight: Arc::new(Preflight {
+        persisted_queries: PersistedQueries::new(self.persisted_query_store),
+        trusted_documents: self.trusted_documents,
+        access: self.access,
+        webview_limiter: WebviewLimiter::new(self.webview_limits),
+      }),
+      timeouts: Arc::new(self.timeouts),
+      delivery_policies: Arc::new(self.delivery_policies),
+      registry: Default::default(),
+      metrics: Default::default(),
+      multicast: Arc::new(Multicast::new(self.multicast_key)),
+      redaction: Arc::new(self.redaction),
+      request_limits: self.request_limits,
+      field_permissions: self.field_permissions,
+      roles: self.roles,
     })
   }
   /// Build the [`crate::MizukiPlugin`]
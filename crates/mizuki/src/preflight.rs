@@ -0,0 +1,146 @@
+use async_graphql::{BatchRequest, BatchResponse, Request, Response, ServerError};
+
+use tauri::{Runtime, Webview};
+
+use crate::{
+  access::AccessControl, apq::PersistedQueries, incremental, metrics::Metrics,
+  permissions::FieldPermissions, trusted_documents::TrustedDocuments,
+  webview_limits::WebviewLimiter,
+};
+
+/// The requests of a batch that were rejected before their execution.
+pub(crate) struct Rejected {
+  single: bool,
+  errors: Vec<(usize, ServerError)>,
+}
+
+impl Rejected {
+  /// Put the errors of the rejected requests back among the responses of the others.
+  pub(crate) fn merge(self, response: Option<BatchResponse>) -> BatchResponse {
+    if self.single {
+      return match (response, self.errors.into_iter().next()) {
+        (_, Some((_, error))) => BatchResponse::Single(Response::from_errors(vec![error])),
+        (Some(response), None) => response,
+        (None, None) => unreachable!("a single request is either executed or rejected"),
+      };
+    }
+    let mut responses = match response {
+      Some(BatchResponse::Batch(responses)) => responses,
+      Some(BatchResponse::Single(response)) => vec![response],
+      None => Vec::new(),
+    };
+    for (index, error) in self.errors {
+      responses.insert(index, Response::from_errors(vec![error]));
+    }
+    BatchResponse::Batch(responses)
+  }
+}
+
+/// Where the requests rejected by the [`Preflight`] are recorded.
+#[derive(Clone, Copy)]
+pub(crate) struct Rejections<'a> {
+  pub(crate) metrics: &'a Metrics,
+  pub(crate) plugin: &'static str,
+}
+
+/// The checks a request goes through before it is executed.
+pub(crate) struct Preflight {
+  pub(crate) persisted_queries: PersistedQueries,
+  pub(crate) trusted_documents: Option<TrustedDocuments>,
+  pub(crate) access: AccessControl,
+  pub(crate) webview_limiter: WebviewLimiter,
+}
+
+impl Preflight {
+  /// Resolve the persisted query of a request, then check its directives, and check it
+  /// against the current page, the permissions and the limits of `webview`.
+  ///
+  /// Only the `deferrable` requests, delivered incrementally, may use `@defer`,
+  /// and a new persisted query is only stored once its request passed.
+  ///
+  /// The rejected requests are recorded in the metrics, except the persisted
+  /// queries the webview has to send again.
+  pub(crate) fn check<R: Runtime>(
+    &self,
+    request: Request,
+    webview: &Webview<R>,
+    permissions: &FieldPermissions,
+    rejections: Rejections<'_>,
+    deferrable: bool,
+  ) -> Result<Request, ServerError> {
+    let (mut request, unregistered) = self.persisted_queries.resolve(request)?;
+    match self.check_resolved(&mut request, webview, permissions, deferrable) {
+      Ok(()) => {
+        if let Some(unregistered) = unregistered {
+          self.persisted_queries.register(unregistered, &mut request);
+        }
+        Ok(request)
+      }
+      Err(error) => {
+        rejections.metrics.rejected(rejections.plugin, &mut request);
+        Err(error)
+      }
+    }
+  }
+
+  fn check_resolved<R: Runtime>(
+    &self,
+    request: &mut Request,
+    webview: &Webview<R>,
+    permissions: &FieldPermissions,
+    deferrable: bool,
+  ) -> Result<(), ServerError> {
+    incremental::check(request, deferrable)?;
+    self.access.check(request, webview)?;
+    permissions.check(request)?;
+    if let Some(trusted_documents) = &self.trusted_documents {
+      trusted_documents.check(request)?;
+    }
+    // Last, so the budget isn't spent on the requests rejected anyway.
+    self.webview_limiter.check(request, webview)
+  }
+
+  /// Check the requests of a batch, none of them deferrable.
+  ///
+  /// Returns the requests left to execute, if any, and the rejected ones.
+  pub(crate) fn check_batch<R: Runtime>(
+    &self,
+    batch: BatchRequest,
+    webview: &Webview<R>,
+    permissions: &FieldPermissions,
+    rejections: Rejections<'_>,
+  ) -> (Option<BatchRequest>, Rejected) {
+    match batch {
+      BatchRequest::Single(request) => {
+        let (request, errors) = match self.check(request, webview, permissions, rejections, false) {
+          Ok(request) => (Some(BatchRequest::Single(request)), Vec::new()),
+          Err(error) => (None, vec![(0, error)]),
+        };
+        (
+          request,
+          Rejected {
+            single: true,
+            errors,
+          },
+        )
+      }
+      BatchRequest::Batch(requests) => {
+        let mut checked = Vec::new();
+        let mut errors = Vec::new();
+        for (index, request) in requests.into_iter().enumerate() {
+          match self.check(request, webview, permissions, rejections, false) {
+            Ok(request) => checked.push(request),
+            Err(error) => errors.push((index, error)),
+          }
+        }
+        (
+          (!checked.is_empty()).then_some(BatchRequest::Batch(checked)),
+          Rejected {
+            single: false,
+            errors,
+          },
+        )
+      }
+    }
+  }
+}
@@ -0,0 +1,167 @@
+//! The [`graphql-transport-ws`] protocol, carried over Tauri's IPC.
+//!
+//! Each webview can open one connection to the plugin, on which any number of
+//! operations are multiplexed.
+//! The client sends its [`ClientMessage`]s through the `transport` command
+//! and receives the [`ServerMessage`]s on the [`tauri::ipc::Channel`]
+//! given alongside the `connection_init` message:
+//!
+//! ```js
+//! import { Channel, invoke } from '@tauri-apps/api/core'
+//!
+//! const channel = new Channel()
+//! channel.onmessage = message => console.log(message)
+//!
+//! await invoke('plugin:todo-plugin|transport', { type: 'connection_init', channel })
+//! await invoke('plugin:todo-plugin|transport', {
+//!   type: 'subscribe',
+//!   id: '1',
+//!   payload: { query: 'subscription { helloWorld }' }
+//! })
+//! ```
+//!
+//! There is no socket to close on this transport.
+//! Instead, when the client violates the protocol, the plugin drops the
+//! connection and rejects the offending invoke with a [`CloseReason`].
+//! A client then has to send a new `connection_init` message to reconnect.
+//! Connections are also dropped when the webview reloads or its window is
+//! destroyed.
+//!
+//! [`graphql-transport-ws`]: https://github.com/enisdenjo/graphql-ws/blob/master/PROTOCOL.md
+
+use async_graphql::{Request, Response, ServerError};
+use serde::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
+
+/// A message sent by the webview.
+///
+/// ```
+/// use mizuki::protocol::ClientMessage;
+///
+/// let message: ClientMessage = serde_json::from_str(r#"{"type":"complete","id":"1"}"#).unwrap();
+/// assert!(matches!(message, ClientMessage::Complete { id } if id == "1"));
+/// ```
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+#[allow(clippy::large_enum_variant)] // Request is at fault
+pub enum ClientMessage {
+  /// Opens the connection of the webview.
+  ///
+  /// The plugin answers with [`ServerMessage::ConnectionAck`].
+  ConnectionInit {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    payload: Option<JsonValue>,
+  },
+  /// Starts an operation.
+  ///
+  /// `id` must be unique among the running operations of the connection.
+  Subscribe { id: String, payload: Request },
+  /// Stops a running operation.
+  Complete { id: String },
+  /// Checks that the connection is still alive.
+  ///
+  /// The plugin answers with [`ServerMessage::Pong`].
+  Ping {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    payload: Option<JsonValue>,
+  },
+  /// The answer to a [`ServerMessage::Ping`].
+  Pong {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    payload: Option<JsonValue>,
+  },
+}
+
+/// A message sent to the webview.
+///
+/// ```
+/// use mizuki::protocol::ServerMessage;
+///
+/// let message = ServerMessage::Complete { id: "1".into() };
+/// assert_eq!(
+///   serde_json::to_string(&message).unwrap(),
+///   r#"{"type":"complete","id":"1"}"#
+/// );
+/// ```
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage {
+  /// The connection has been accepted.
+  ConnectionAck {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    payload: Option<JsonValue>,
+  },
+  /// A result of the operation `id`.
+  Next { id: String, payload: Response },
+  /// The operation `id` failed before producing any result,
+  /// usually because it didn't pass validation.
+  ///
+  /// No [`ServerMessage::Complete`] follows this message.
+  Error {
+    id: String,
+    payload: Vec<ServerError>,
+  },
+  /// The operation `id` has no more results.
+  ///
+  /// It isn't sent for the operations stopped with [`ClientMessage::Complete`].
+  Complete { id: String },
+  /// Checks that the webview is still listening.
+  ///
+  /// The plugin never sends it on its own, it is part of the protocol for completeness.
+  Ping {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    payload: Option<JsonValue>,
+  },
+  /// The answer to a [`ClientMessage::Ping`].
+  Pong {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    payload: Option<JsonValue>,
+  },
+}
+
+/// Why the plugin dropped a connection.
+///
+/// The codes are the WebSocket close codes of the protocol.
+///
+/// ```
+/// use mizuki::protocol::CloseReason;
+///
+/// assert_eq!(
+///   serde_json::to_string(&CloseReason::unauthorized()).unwrap(),
+///   r#"{"code":4401,"reason":"Unauthorized"}"#
+/// );
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct CloseReason {
+  pub code: u16,
+  pub reason: String,
+}
+
+impl CloseReason {
+  pub fn new(code: u16, reason: impl Into<String>) -> Self {
+    Self {
+      code,
+      reason: reason.into(),
+    }
+  }
+  /// The client sent a message that doesn't follow the protocol.
+  pub fn bad_request(reason: impl Into<String>) -> Self {
+    Self::new(4400, reason)
+  }
+  /// The client started an operation before the connection was acknowledged.
+  pub fn unauthorized() -> Self {
+    Self::new(4401, "Unauthorized")
+  }
+  /// The connection was refused by [`crate::Builder::on_connection_init`].
+  pub fn forbidden(reason: impl Into<String>) -> Self {
+    Self::new(4403, reason)
+  }
+  /// The client started an operation with the `id` of a running one.
+  pub fn subscriber_already_exists(id: &str) -> Self {
+    Self::new(4409, format!("Subscriber for {} already exists", id))
+  }
+  /// The client sent `connection_init` on an open connection.
+  pub fn too_many_initialisation_requests() -> Self {
+    Self::new(4429, "Too many initialisation requests")
+  }
+}
@@ -0,0 +1,247 @@
+use std::{
+  collections::hash_map::RandomState,
+  hash::{BuildHasher, Hasher},
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+  },
+};
+
+use async_graphql::{
+  futures_util::{stream::BoxStream, StreamExt},
+  BatchResponse, ErrorExtensionValues, PathSegment, Response, ServerError, Value,
+};
+
+use crate::{incremental::IncrementalResponse, subscription::SubscriptionItem};
+
+pub(crate) type OnError = dyn Fn(&ServerError, &str) + Send + Sync;
+
+/// What the webviews get to see of the errors of the resolvers.
+///
+/// Only the errors with a `path` come from resolvers, the others are about the
+/// request itself and are left alone.
+pub(crate) struct Redaction {
+  pub(crate) enabled: bool,
+  pub(crate) message: String,
+  pub(crate) on_error: Option<Box<OnError>>,
+}
+
+impl Default for Redaction {
+  fn default() -> Self {
+    Self {
+      enabled: !cfg!(debug_assertions),
+      message: "Internal server error".into(),
+      on_error: None,
+    }
+  }
+}
+
+impl Redaction {
+  /// Give each resolver error a correlation id, report it, and redact it if enabled.
+  pub(crate) fn errors(&self, errors: &mut [ServerError]) {
+    if !self.enabled && self.on_error.is_none() {
+      return;
+    }
+    for error in errors.iter_mut().filter(|error| !error.path.is_empty()) {
+      let correlation_id = correlation_id();
+      match &self.on_error {
+        Some(on_error) => on_error(error, &correlation_id),
+        // Without a callback, the original of a redacted error is only in the logs.
+        None => log::error!(
+          "resolver error at {}: {} (correlation id {})",
+          path(error),
+          error.message,
+          correlation_id
+        ),
+      }
+      if self.enabled {
+        *error = self.redacted(error, &correlation_id);
+      } else {
+        error
+          .extensions
+          .get_or_insert_with(Default::default)
+          .set("correlationId", correlation_id);
+      }
+    }
+  }
+
+  /// A generic error in place of `error`, keeping where it happened and its code.
+  fn redacted(&self, error: &ServerError, correlation_id: &str) -> ServerError {
+    let mut redacted = ServerError::new(self.message.clone(), None);
+    redacted.locations = error.locations.clone();
+    redacted.path = error.path.clone();
+    let code = error
+      .extensions
+      .as_ref()
+      .and_then(|extensions| extensions.get("code"))
+      .cloned()
+      .unwrap_or_else(|| Value::from("INTERNAL_SERVER_ERROR"));
+    let mut extensions = ErrorExtensionValues::default();
+    extensions.set("code", code);
+    extensions.set("correlationId", correlation_id);
+    redacted.extensions = Some(extensions);
+    redacted
+  }
+
+  pub(crate) fn response(&self, response: &mut Response) {
+    self.errors(&mut response.errors);
+  }
+
+  pub(crate) fn batch_response(&self, response: &mut BatchResponse) {
+    match response {
+      BatchResponse::Single(response) => self.response(response),
+      BatchResponse::Batch(responses) => responses
+        .iter_mut()
+        .for_each(|response| self.response(response)),
+    }
+  }
+
+  pub(crate) fn item(&self, item: &mut SubscriptionItem) {
+    match item {
+      SubscriptionItem::Response(response) => self.response(response),
+      SubscriptionItem::Incremental(response) => self.incremental(response),
+    }
+  }
+
+  fn incremental(&self, response: &mut IncrementalResponse) {
+    match response {
+      IncrementalResponse::Initial { response, .. } => self.response(response),
+      IncrementalResponse::Subsequent { incremental, .. } => incremental
+        .iter_mut()
+        .for_each(|payload| self.errors(payload.errors_mut())),
+    }
+  }
+
+  /// Redact the results of a stream.
+  pub(crate) fn stream<T: Send + 'static>(
+    self: &Arc<Self>,
+    stream: BoxStream<'static, T>,
+    redact: fn(&Self, &mut T),
+  ) -> BoxStream<'static, T> {
+    if !self.enabled && self.on_error.is_none() {
+      return stream;
+    }
+    let redaction = self.clone();
+    stream
+      .map(move |mut item| {
+        redact(&redaction, &mut item);
+        item
+      })
+      .boxed()
+  }
+}
+
+/// The path of an error, like `user.friends.0.name`.
+fn path(error: &ServerError) -> String {
+  let segments: Vec<_> = error
+    .path
+    .iter()
+    .map(|segment| match segment {
+      PathSegment::Field(name) => name.clone(),
+      PathSegment::Index(index) => index.to_string(),
+    })
+    .collect();
+  segments.join(".")
+}
+
+/// An id to find the details of a redacted error in the logs with.
+fn correlation_id() -> String {
+  static NEXT: AtomicU64 = AtomicU64::new(0);
+  let mut hasher = RandomState::new().build_hasher();
+  hasher.write_u64(NEXT.fetch_add(1, Ordering::Relaxed));
+  format!("{:016x}", hasher.finish())
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::Mutex;
+
+  use super::*;
+
+  /// A resolver error at `user.0.name`, with a code.
+  fn resolver_error() -> ServerError {
+    let mut error = ServerError::new("connection refused at /var/db.sock", None);
+    error.path = vec![
+      PathSegment::Field("user".into()),
+      PathSegment::Index(0),
+      PathSegment::Field("name".into()),
+    ];
+    let mut extensions = ErrorExtensionValues::default();
+    extensions.set("code", "DB_DOWN");
+    error.extensions = Some(extensions);
+    error
+  }
+
+  fn extension(error: &ServerError, key: &str) -> Option<Value> {
+    error.extensions.as_ref()?.get(key).cloned()
+  }
+
+  #[test]
+  fn resolver_errors_are_redacted() {
+    let redaction = Redaction {
+      enabled: true,
+      ..Default::default()
+    };
+    let mut errors = vec![resolver_error(), ServerError::new("Unknown field", None)];
+    redaction.errors(&mut errors);
+    assert_eq!(errors[0].message, "Internal server error");
+    assert_eq!(errors[0].path, resolver_error().path);
+    assert_eq!(extension(&errors[0], "code"), Some(Value::from("DB_DOWN")));
+    assert!(extension(&errors[0], "correlationId").is_some());
+    // The errors about the request itself are left alone.
+    assert_eq!(errors[1].message, "Unknown field");
+    assert!(errors[1].extensions.is_none());
+  }
+
+  #[test]
+  fn on_error_gets_the_original_with_its_correlation_id() {
+    let reported = Arc::new(Mutex::new(Vec::new()));
+    let on_error = reported.clone();
+    let redaction = Redaction {
+      enabled: true,
+      message: "Oops".into(),
+      on_error: Some(Box::new(move |error, id| {
+        on_error
+          .lock()
+          .unwrap()
+          .push((error.message.clone(), id.to_string()))
+      })),
+    };
+    let mut response = Response::from_errors(vec![resolver_error()]);
+    redaction.response(&mut response);
+    let reported = reported.lock().unwrap();
+    assert_eq!(reported[0].0, "connection refused at /var/db.sock");
+    assert_eq!(response.errors[0].message, "Oops");
+    assert_eq!(
+      extension(&response.errors[0], "correlationId"),
+      Some(Value::from(reported[0].1.clone()))
+    );
+  }
+
+  #[test]
+  fn unredacted_errors_get_a_correlation_id_with_on_error() {
+    let redaction = Redaction {
+      enabled: false,
+      on_error: Some(Box::new(|_, _| {})),
+      ..Default::default()
+    };
+    let mut errors = vec![resolver_error()];
+    redaction.errors(&mut errors);
+    assert_eq!(errors[0].message, "connection refused at /var/db.sock");
+    assert!(extension(&errors[0], "correlationId").is_some());
+
+    let mut errors = vec![resolver_error()];
+    Redaction {
+      enabled: false,
+      ..Default::default()
+    }
+    .errors(&mut errors);
+    assert_eq!(errors[0], resolver_error());
+  }
+
+  #[test]
+  fn paths_are_printed_with_dots() {
+    assert_eq!(path(&resolver_error()), "user.0.name");
+    assert_ne!(correlation_id(), correlation_id());
+  }
+}
@@ -0,0 +1,268 @@
+use std::{
+  collections::BTreeMap,
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+  },
+  time::SystemTime,
+};
+
+use async_graphql::{
+  futures_util::{stream::BoxStream, StreamExt},
+  Request,
+};
+use serde::Serialize;
+use tokio_util::sync::CancellationToken;
+
+use crate::operation::operation_name;
+
+/// An active subscription, as listed by [`SubscriptionRegistry::list`].
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SubscriptionInfo {
+  /// The id the subscription is cancelled by.
+  pub id: u64,
+  /// The name of the plugin running it.
+  pub plugin: &'static str,
+  /// The label of the webview it was started by.
+  pub webview: String,
+  /// The id the webview gave to it.
+  pub client_id: String,
+  pub operation_name: Option<String>,
+  pub started_at: SystemTime,
+  /// How many results were sent to the webview.
+  pub items: u64,
+  /// The size of the results sent to the webview.
+  pub bytes: u64,
+}
+
+struct Entry {
+  info: SubscriptionInfo,
+  items: Arc<AtomicU64>,
+  bytes: Arc<AtomicU64>,
+  /// Stops forwarding the results.
+  stop: CancellationToken,
+  /// The token of the operation, cancelled for the resolvers.
+  cancel_token: CancellationToken,
+}
+
+#[derive(Default)]
+struct Inner {
+  entries: Mutex<BTreeMap<u64, Entry>>,
+  next_id: AtomicU64,
+}
+
+/// The active subscriptions of the mizuki plugins of an app.
+///
+/// It is managed by the app, so it can be reached from Rust:
+///
+/// ```rust,no_run
+/// use mizuki::SubscriptionRegistry;
+/// use tauri::Manager;
+///
+/// fn logout<R: tauri::Runtime>(app: &tauri::AppHandle<R>) {
+///   let registry = app.state::<SubscriptionRegistry>();
+///   for subscription in registry.list() {
+///     println!("{:?}", subscription);
+///   }
+///   registry.cancel_webview("main");
+/// }
+/// ```
+///
+/// Both the subscriptions of the `subscriptions` command and the ones of the
+/// [`crate::protocol`] connections are recorded.
+#[derive(Clone, Default)]
+pub struct SubscriptionRegistry {
+  inner: Arc<Inner>,
+}
+
+impl SubscriptionRegistry {
+  /// The active subscriptions, in the order they started.
+  pub fn list(&self) -> Vec<SubscriptionInfo> {
+    let entries = self.inner.entries.lock().unwrap();
+    entries
+      .values()
+      .map(|entry| SubscriptionInfo {
+        items: entry.items.load(Ordering::Relaxed),
+        bytes: entry.bytes.load(Ordering::Relaxed),
+        ..entry.info.clone()
+      })
+      .collect()
+  }
+
+  /// Cancel a subscription.
+  ///
+  /// Returns `false` if there is no active subscription with this id.
+  pub fn cancel(&self, id: u64) -> bool {
+    let entries = self.inner.entries.lock().unwrap();
+    let Some(entry) = entries.get(&id) else {
+      return false;
+    };
+    entry.cancel();
+    true
+  }
+
+  /// Cancel all the subscriptions of a webview.
+  ///
+  /// Returns how many subscriptions were cancelled.
+  pub fn cancel_webview(&self, label: &str) -> usize {
+    let entries = self.inner.entries.lock().unwrap();
+    entries
+      .values()
+      .filter(|entry| entry.info.webview == label)
+      .inspect(|entry| entry.cancel())
+      .count()
+  }
+
+  /// Record a subscription until the returned [`Registration`] is dropped.
+  pub(crate) fn register(
+    &self,
+    plugin: &'static str,
+    webview: &str,
+    client_id: &str,
+    request: &mut Request,
+    cancel_token: &CancellationToken,
+  ) -> Registration {
+    let id = self.inner.next_id.fetch_add(1, Ordering::Relaxed);
+    let items = Arc::new(AtomicU64::new(0));
+    let bytes = Arc::new(AtomicU64::new(0));
+    let stop = CancellationToken::new();
+    let entry = Entry {
+      info: SubscriptionInfo {
+        id,
+        plugin,
+        webview: webview.into(),
+        client_id: client_id.into(),
+        operation_name: operation_name(request),
+        started_at: SystemTime::now(),
+        items: 0,
+        bytes: 0,
+      },
+      items: items.clone(),
+      bytes: bytes.clone(),
+      stop: stop.clone(),
+      cancel_token: cancel_token.clone(),
+    };
+    self.inner.entries.lock().unwrap().insert(id, entry);
+    Registration {
+      registry: self.clone(),
+      id,
+      items,
+      bytes,
+      stop,
+    }
+  }
+}
+
+impl Entry {
+  fn cancel(&self) {
+    self.stop.cancel();
+    self.cancel_token.cancel();
+  }
+}
+
+/// Keeps a subscription in the [`SubscriptionRegistry`].
+pub(crate) struct Registration {
+  registry: SubscriptionRegistry,
+  id: u64,
+  items: Arc<AtomicU64>,
+  bytes: Arc<AtomicU64>,
+  stop: CancellationToken,
+}
+
+impl Registration {
+  /// Record the size of a result sent to the webview.
+  pub(crate) fn sent(&self, bytes: usize) {
+    self.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+  }
+
+  /// Count the results of the subscription, and end them when it is cancelled.
+  ///
+  /// The webview is then told the subscription is complete, as if it ended on its own.
+  pub(crate) fn track<T: Send + 'static>(
+    &self,
+    stream: BoxStream<'static, T>,
+  ) -> BoxStream<'static, T> {
+    let items = self.items.clone();
+    stream
+      .take_until(self.stop.clone().cancelled_owned())
+      .inspect(move |_| {
+        items.fetch_add(1, Ordering::Relaxed);
+      })
+      .boxed()
+  }
+}
+
+impl Drop for Registration {
+  fn drop(&mut self) {
+    self.registry.inner.entries.lock().unwrap().remove(&self.id);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use async_graphql::futures_util::stream;
+
+  use super::*;
+
+  fn register(
+    registry: &SubscriptionRegistry,
+    webview: &str,
+    token: &CancellationToken,
+  ) -> Registration {
+    registry.register(
+      "test",
+      webview,
+      "1",
+      &mut Request::new("subscription Ticks { ticks }"),
+      token,
+    )
+  }
+
+  #[tokio::test]
+  async fn subscriptions_are_listed_while_registered() {
+    let registry = SubscriptionRegistry::default();
+    let registration = register(&registry, "main", &CancellationToken::new());
+    let items: Vec<_> = registration
+      .track(stream::iter([1, 2, 3]).boxed())
+      .collect()
+      .await;
+    assert_eq!(items, [1, 2, 3]);
+    registration.sent(10);
+
+    let list = registry.list();
+    assert_eq!(list.len(), 1);
+    assert_eq!(list[0].operation_name.as_deref(), Some("Ticks"));
+    assert_eq!((list[0].items, list[0].bytes), (3, 10));
+    drop(registration);
+    assert!(registry.list().is_empty());
+  }
+
+  #[tokio::test]
+  async fn cancelled_subscriptions_end() {
+    let registry = SubscriptionRegistry::default();
+    let token = CancellationToken::new();
+    let registration = register(&registry, "main", &token);
+    let mut stream = registration.track(stream::pending::<()>().boxed());
+    let id = registry.list()[0].id;
+    assert!(registry.cancel(id));
+    assert!(token.is_cancelled());
+    assert_eq!(stream.next().await, None);
+    drop(registration);
+    assert!(!registry.cancel(id));
+  }
+
+  #[test]
+  fn webviews_are_cancelled_as_a_whole() {
+    let registry = SubscriptionRegistry::default();
+    let (main, other) = (CancellationToken::new(), CancellationToken::new());
+    let _registrations = [
+      register(&registry, "main", &main),
+      register(&registry, "main", &main),
+      register(&registry, "other", &other),
+    ];
+    assert_eq!(registry.cancel_webview("main"), 2);
+    assert!(main.is_cancelled());
+    assert!(!other.is_cancelled());
+  }
+}
@@ -0,0 +1,300 @@
+use async_graphql::{BatchRequest, Request};
+use tauri::ipc::InvokeBody;
+
+use crate::{metrics::json_len, Error};
+
+/// Hard limits on the requests, checked once decoded, before any parsing.
+///
+/// They bound the work a webview can ask for with a single invoke,
+/// along with the size of the invoke payload, checked before it is even decoded:
+///
+/// ```
+/// use mizuki::RequestLimits;
+///
+/// let limits = RequestLimits::new()
+///   .max_body_size(128 * 1024)
+///   .max_batch_size(10)
+///   .max_query_size(16 * 1024)
+///   .max_variables_size(64 * 1024)
+///   .max_aliases(30);
+/// ```
+///
+/// Requests going over them are rejected with a `LIMIT_EXCEEDED` [`Error`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct RequestLimits {
+  max_body_size: Option<usize>,
+  max_batch_size: Option<usize>,
+  max_query_size: Option<usize>,
+  max_variables_size: Option<usize>,
+  max_aliases: Option<usize>,
+}
+
+impl RequestLimits {
+  /// No limits.
+  pub fn new() -> Self {
+    Self::default()
+  }
+  /// Limit the size of the invoke payload, in bytes, or in bytes of JSON when it isn't raw.
+  ///
+  /// Unlike the other limits, it is checked before the payload is decoded.
+  #[must_use]
+  pub fn max_body_size(mut self, size: usize) -> Self {
+    self.max_body_size = Some(size);
+    self
+  }
+  /// Limit how many requests a batch holds.
+  #[must_use]
+  pub fn max_batch_size(mut self, size: usize) -> Self {
+    self.max_batch_size = Some(size);
+    self
+  }
+  /// Limit the size of the query text, in bytes.
+  #[must_use]
+  pub fn max_query_size(mut self, size: usize) -> Self {
+    self.max_query_size = Some(size);
+    self
+  }
+  /// Limit the size of the variables, in bytes of JSON.
+  #[must_use]
+  pub fn max_variables_size(mut self, size: usize) -> Self {
+    self.max_variables_size = Some(size);
+    self
+  }
+  /// Limit how many aliases the query text holds.
+  #[must_use]
+  pub fn max_aliases(mut self, aliases: usize) -> Self {
+    self.max_aliases = Some(aliases);
+    self
+  }
+
+  pub(crate) fn check_body(&self, body: &InvokeBody) -> Result<(), Error> {
+    let Some(max) = self.max_body_size else {
+      return Ok(());
+    };
+    let size = match body {
+      InvokeBody::Raw(bytes) => bytes.len(),
+      InvokeBody::Json(value) => json_len(value),
+    };
+    if size > max {
+      return Err(Error::LimitExceeded {
+        limit: "bodySize",
+        max,
+      });
+    }
+    Ok(())
+  }
+
+  pub(crate) fn check_batch(&self, batch: &BatchRequest) -> Result<(), Error> {
+    match batch {
+      BatchRequest::Single(request) => self.check(request),
+      BatchRequest::Batch(requests) => {
+        if let Some(max) = self.max_batch_size.filter(|&max| requests.len() > max) {
+          return Err(Error::LimitExceeded {
+            limit: "batchSize",
+            max,
+          });
+        }
+        requests.iter().try_for_each(|request| self.check(request))
+      }
+    }
+  }
+
+  pub(crate) fn check(&self, request: &Request) -> Result<(), Error> {
+    let exceeded = |limit, max| Err(Error::LimitExceeded { limit, max });
+    if let Some(max) = self.max_query_size.filter(|&max| request.query.len() > max) {
+      return exceeded("querySize", max);
+    }
+    if let Some(max) = self
+      .max_variables_size
+      .filter(|&max| json_len(&request.variables) > max)
+    {
+      return exceeded("variablesSize", max);
+    }
+    if let Some(max) = self
+      .max_aliases
+      .filter(|&max| count_aliases(&request.query) > max)
+    {
+      return exceeded("aliases", max);
+    }
+    Ok(())
+  }
+}
+
+/// Count the aliases of a query text without parsing it.
+///
+/// An alias is a name followed by a colon outside of parentheses, the other
+/// colons being the ones of arguments and variable definitions.
+fn count_aliases(query: &str) -> usize {
+  let bytes = query.as_bytes();
+  let mut aliases = 0;
+  let mut parentheses = 0usize;
+  let mut after_name = false;
+  let mut i = 0;
+  while i < bytes.len() {
+    match bytes[i] {
+      b'#' => {
+        while i < bytes.len() && bytes[i] != b'\n' {
+          i += 1;
+        }
+        continue;
+      }
+      b'"' => {
+        i = skip_string(bytes, i);
+        after_name = false;
+        continue;
+      }
+      b'(' => {
+        parentheses += 1;
+        after_name = false;
+      }
+      b')' => {
+        parentheses = parentheses.saturating_sub(1);
+        after_name = false;
+      }
+      b':' => {
+        if after_name && parentheses == 0 {
+          aliases += 1;
+        }
+        after_name = false;
+      }
+      b'_' | b'a'..=b'z' | b'A'..=b'Z' => {
+        while i < bytes.len() && (bytes[i] == b'_' || bytes[i].is_ascii_alphanumeric()) {
+          i += 1;
+        }
+        after_name = true;
+        continue;
+      }
+      b' ' | b'\t' | b'\n' | b'\r' | b',' => {}
+      _ => after_name = false,
+    }
+    i += 1;
+  }
+  aliases
+}
+
+/// The index after the string starting at `start`, a block string or not.
+fn skip_string(bytes: &[u8], start: usize) -> usize {
+  if bytes[start..].starts_with(b"\"\"\"") {
+    let mut i = start + 3;
+    while i < bytes.len() {
+      if bytes[i..].starts_with(b"\\\"\"\"") {
+        i += 4;
+      } else if bytes[i..].starts_with(b"\"\"\"") {
+        return i + 3;
+      } else {
+        i += 1;
+      }
+    }
+    return i;
+  }
+  let mut i = start + 1;
+  while i < bytes.len() {
+    match bytes[i] {
+      b'\\' => i += 2,
+      b'"' => return i + 1,
+      _ => i += 1,
+    }
+  }
+  i
+}
+
+#[cfg(test)]
+mod tests {
+  use async_graphql::Variables;
+  use serde_json::json;
+
+  use super::*;
+
+  #[test]
+  fn aliases_are_counted() {
+    assert_eq!(count_aliases("{ a: user { b: name, email } c: user }"), 3);
+    assert_eq!(count_aliases("{ user { name } }"), 0);
+  }
+
+  #[test]
+  fn arguments_and_variables_are_not_aliases() {
+    let query = r#"query Q($id: ID! = "1", $input: In = { a: 1 }) {
+      user(id: $id, input: { nested: { b: 2 } }) @include(if: true) { name }
+    }"#;
+    assert_eq!(count_aliases(query), 0);
+  }
+
+  #[test]
+  fn strings_and_comments_are_skipped() {
+    let query = r#"
+      # a: b, c: d
+      {
+        x: f(text: "e: f \" g: h")
+        y: f(text: """
+          i: j \""" k: l
+        """)
+      }
+    "#;
+    assert_eq!(count_aliases(query), 2);
+    assert_eq!(count_aliases(r#"{ f(a: "unterminated: x) }"#), 0);
+  }
+
+  #[test]
+  fn fragments_count_their_aliases() {
+    let query = "
+      query { ...F ... on Query { a: user } ... @skip(if: false) { b: user } }
+      fragment F on Query { c: user { d: name } }
+    ";
+    assert_eq!(count_aliases(query), 4);
+  }
+
+  #[test]
+  fn requests_over_the_limits_are_rejected() {
+    let limits = RequestLimits::new()
+      .max_query_size(20)
+      .max_variables_size(10)
+      .max_aliases(1);
+    let exceeded = |request: &Request| match limits.check(request) {
+      Err(Error::LimitExceeded { limit, .. }) => Some(limit),
+      _ => None,
+    };
+    assert_eq!(exceeded(&Request::new("{ a: b }")), None);
+    assert_eq!(
+      exceeded(&Request::new("{ a: b, c: d, e: f, g: h }")),
+      Some("querySize")
+    );
+    assert_eq!(exceeded(&Request::new("{ a: b c: d }")), Some("aliases"));
+    let variables = Variables::from_json(json!({ "long": "0123456789" }));
+    assert_eq!(
+      exceeded(&Request::new("{ a }").variables(variables)),
+      Some("variablesSize")
+    );
+    assert!(RequestLimits::new()
+      .check(&Request::new("{ a: b c: d }"))
+      .is_ok());
+  }
+
+  #[test]
+  fn batches_over_the_limit_are_rejected() {
+    let limits = RequestLimits::new().max_batch_size(2);
+    let batch = |size| BatchRequest::Batch((0..size).map(|_| Request::new("{ a }")).collect());
+    assert!(limits.check_batch(&batch(2)).is_ok());
+    assert!(matches!(
+      limits.check_batch(&batch(3)),
+      Err(Error::LimitExceeded {
+        limit: "batchSize",
+        max: 2
+      })
+    ));
+  }
+
+  #[test]
+  fn bodies_over_the_limit_are_rejected() {
+    let limits = RequestLimits::new().max_body_size(16);
+    assert!(limits.check_body(&InvokeBody::Raw(vec![0; 16])).is_ok());
+    assert!(limits.check_body(&InvokeBody::Raw(vec![0; 17])).is_err());
+    let json = InvokeBody::Json(json!({ "query": "{ a b c }" }));
+    assert!(matches!(
+      limits.check_body(&json),
+      Err(Error::LimitExceeded {
+        limit: "bodySize",
+        ..
+      })
+    ));
+  }
+}
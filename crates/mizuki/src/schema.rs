@@ -0,0 +1,166 @@
+use async_graphql::{ObjectType, Schema, SchemaBuilder, SubscriptionType};
+use serde::Deserialize;
+
+use crate::BuilderError;
+
+/// The limits of the schema, set by the [`crate::Builder`] or the plugin config.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct SchemaLimits {
+  pub(crate) limit_depth: Option<usize>,
+  pub(crate) limit_complexity: Option<usize>,
+}
+
+impl SchemaLimits {
+  pub(crate) fn is_empty(&self) -> bool {
+    self.limit_depth.is_none() && self.limit_complexity.is_none()
+  }
+
+  /// The limits of `self`, overridden by the ones `overrides` sets.
+  pub(crate) fn overridden_by(self, overrides: Self) -> Self {
+    Self {
+      limit_depth: overrides.limit_depth.or(self.limit_depth),
+      limit_complexity: overrides.limit_complexity.or(self.limit_complexity),
+    }
+  }
+}
+
+/// The plugin config, read from the `plugins.<plugin name>` section of `tauri.conf.json`.
+///
+/// Only the limits can be set there: the extensions are Rust values,
+/// registered with [`crate::Builder::extension`].
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Config {
+  #[serde(flatten)]
+  pub(crate) limits: SchemaLimits,
+}
+
+/// The schema of a plugin, finished once the plugin config is known.
+pub(crate) enum PluginSchema<Q, M, S>
+where
+  Q: ObjectType + 'static,
+  M: ObjectType + 'static,
+  S: SubscriptionType + 'static,
+{
+  Built(Schema<Q, M, S>),
+  Pending(Box<SchemaBuilder<Q, M, S>>),
+  /// While it is being finished.
+  Finishing,
+}
+
+impl<Q, M, S> PluginSchema<Q, M, S>
+where
+  Q: ObjectType + 'static,
+  M: ObjectType + 'static,
+  S: SubscriptionType + 'static,
+{
+  /// Finish the schema of the plugin with `limits`.
+  ///
+  /// A schema that was built before being given to the plugin can't have any,
+  /// like [`crate::Builder::try_build`] it fails with [`BuilderError::SchemaBuilt`].
+  pub(crate) fn finish(&mut self, limits: SchemaLimits) -> Result<&Schema<Q, M, S>, BuilderError> {
+    let schema = match std::mem::replace(self, Self::Finishing) {
+      Self::Built(schema) => {
+        let limited = !limits.is_empty();
+        *self = Self::Built(schema);
+        if limited {
+          return Err(BuilderError::SchemaBuilt);
+        }
+        return Ok(self.get().expect("the schema is built"));
+      }
+      Self::Pending(builder) => {
+        let mut builder = *builder;
+        if let Some(depth) = limits.limit_depth {
+          builder = builder.limit_depth(depth);
+        }
+        if let Some(complexity) = limits.limit_complexity {
+          builder = builder.limit_complexity(complexity);
+        }
+        builder.finish()
+      }
+      Self::Finishing => unreachable!("the schema is finished once"),
+    };
+    *self = Self::Built(schema);
+    Ok(self.get().expect("the schema was just built"))
+  }
+
+  /// The schema, once finished.
+  pub(crate) fn get(&self) -> Option<&Schema<Q, M, S>> {
+    match self {
+      Self::Built(schema) => Some(schema),
+      _ => None,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use async_graphql::{EmptyMutation, EmptySubscription, Object};
+
+  use super::*;
+
+  struct Query;
+
+  #[Object]
+  impl Query {
+    async fn value(&self) -> Query {
+      Query
+    }
+    async fn leaf(&self) -> bool {
+      true
+    }
+  }
+
+  type TestSchema = PluginSchema<Query, EmptyMutation, EmptySubscription>;
+
+  fn limits(limit_depth: Option<usize>) -> SchemaLimits {
+    SchemaLimits {
+      limit_depth,
+      limit_complexity: None,
+    }
+  }
+
+  async fn errors(schema: &Schema<Query, EmptyMutation, EmptySubscription>) -> usize {
+    schema
+      .execute("{ value { value { value { leaf } } } }")
+      .await
+      .errors
+      .len()
+  }
+
+  #[tokio::test]
+  async fn a_pending_schema_takes_the_limits() {
+    let mut schema = TestSchema::Pending(Box::new(Schema::build(
+      Query,
+      EmptyMutation,
+      EmptySubscription,
+    )));
+    let schema = schema.finish(limits(Some(2))).unwrap();
+    assert_eq!(errors(schema).await, 1);
+  }
+
+  #[tokio::test]
+  async fn a_built_schema_fails_with_limits() {
+    let mut schema = TestSchema::Built(Schema::new(Query, EmptyMutation, EmptySubscription));
+    assert!(matches!(
+      schema.finish(limits(Some(2))),
+      Err(BuilderError::SchemaBuilt)
+    ));
+    let schema = schema.finish(limits(None)).unwrap();
+    assert_eq!(errors(schema).await, 0);
+  }
+
+  #[test]
+  fn the_config_overrides_the_builder() {
+    let builder = SchemaLimits {
+      limit_depth: Some(5),
+      limit_complexity: Some(100),
+    };
+    let config: Config = serde_json::from_str(r#"{ "limitDepth": 10 }"#).unwrap();
+    let limits = builder.overridden_by(config.limits);
+    assert_eq!(limits.limit_depth, Some(10));
+    assert_eq!(limits.limit_complexity, Some(100));
+    assert!(SchemaLimits::default().is_empty());
+  }
+}
@@ -1,10 +1,264 @@
-use async_graphql::Request;
-use serde::Deserialize;
+use async_graphql::{
+  futures_util::{stream::BoxStream, StreamExt},
+  Request, Response,
+};
+use serde::{Deserialize, Serialize};
+use tokio_util::sync::CancellationToken;
 
-#[derive(Debug, Deserialize)]
+use crate::{codec::Codec, incremental::IncrementalResponse, trace, Error};
+use tauri::{
+  ipc::{Channel, InvokeResponseBody, JavaScriptChannelId},
+  Emitter, EventTarget, Runtime, Webview,
+};
+
+#[derive(Deserialize)]
 pub struct SubscriptionRequest {
   #[serde(flatten)]
   pub inner: Request,
-  pub id: u32,
+  /// Suffix of the `graphql://{id}` event used by the event delivery.
+  #[serde(default)]
+  pub id: Option<u32>,
   pub sub_id: String,
+  /// The [`tauri::ipc::Channel`] the results should be streamed over.
+  #[serde(default)]
+  pub channel: Option<JavaScriptChannelId>,
+}
+
+/// How the subscription results are delivered to the webview.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum SubscriptionDelivery {
+  /// Stream the results over the [`tauri::ipc::Channel`] sent with the request,
+  /// and fall back to `graphql://{id}` events if the client didn't send one.
+  #[default]
+  Auto,
+  /// Always emit the results as `graphql://{id}` events.
+  ///
+  /// Requires the `core:event:default` permission.
+  Event,
+  /// Always stream the results over a [`tauri::ipc::Channel`].
+  ///
+  /// Requests without a channel are rejected.
+  Channel,
+}
+
+/// A result sent to the webview.
+#[derive(Serialize)]
+#[serde(untagged)]
+pub(crate) enum SubscriptionItem {
+  Response(Response),
+  /// A result of a query using `@defer`.
+  Incremental(IncrementalResponse),
+}
+
+impl SubscriptionItem {
+  pub(crate) fn has_errors(&self) -> bool {
+    match self {
+      Self::Response(response) => response.is_err(),
+      Self::Incremental(response) => response.has_errors(),
+    }
+  }
+}
+
+/// Where the results of a single subscription go.
+pub(crate) enum SubscriptionSink<R: Runtime> {
+  Event { webview: Webview<R>, event: String },
+  Channel { channel: Channel, codec: Codec },
+}
+
+impl<R: Runtime> SubscriptionSink<R> {
+  pub(crate) fn new(
+    delivery: SubscriptionDelivery,
+    codec: Option<Codec>,
+    webview: &Webview<R>,
+    req: &SubscriptionRequest,
+  ) -> Result<Self, Error> {
+    let channel = match delivery {
+      SubscriptionDelivery::Event => None,
+      SubscriptionDelivery::Auto => req.channel.as_ref(),
+      SubscriptionDelivery::Channel => Some(req.channel.as_ref().ok_or(Error::MissingChannel)?),
+    };
+    if let Some(channel) = channel {
+      return Ok(Self::Channel {
+        channel: channel.channel_on(webview.clone()),
+        codec: codec.unwrap_or(Codec::Json),
+      });
+    }
+    let id = req.id.ok_or(Error::MissingEventId)?;
+    Ok(Self::Event {
+      webview: webview.clone(),
+      event: format!("graphql://{}", id),
+    })
+  }
+
+  /// Send a subscription result, returning its size.
+  pub(crate) fn send(&self, response: &impl Serialize) -> Result<usize, Error> {
+    match self {
+      Self::Event { webview, event } => {
+        let payload =
+          serde_json::to_string(response).map_err(|error| Error::EncodeFailed(error.into()))?;
+        let len = payload.len();
+        webview.emit_to(
+          EventTarget::Webview {
+            label: webview.label().into(),
+          },
+          event,
+          payload,
+        )?;
+        Ok(len)
+      }
+      Self::Channel { channel, codec } => {
+        let body = codec.encode_body(response).map_err(Error::EncodeFailed)?;
+        let len = match &body {
+          InvokeResponseBody::Json(json) => json.len(),
+          InvokeResponseBody::Raw(bytes) => bytes.len(),
+        };
+        channel.send(body)?;
+        Ok(len)
+      }
+    }
+  }
+
+  /// Send the results of a stream until it ends, or until `cancel_token` is cancelled.
+  ///
+  /// `sent` is called with each result and its size.
+  pub(crate) async fn forward(
+    &self,
+    mut stream: BoxStream<'static, SubscriptionItem>,
+    cancel_token: &CancellationToken,
+    mut sent: impl FnMut(&SubscriptionItem, usize),
+  ) -> Result<(), Error> {
+    loop {
+      tokio::select! {
+        _ = cancel_token.cancelled() => break,
+        res = stream.next() => match res {
+          Some(result) => {
+            let len = self.send(&result)?;
+            sent(&result, len);
+            trace::item_emitted();
+          }
+          None => break,
+        },
+      }
+    }
+    Ok(())
+  }
+
+  /// Notify the webview that the subscription has ended.
+  pub(crate) fn end(&self) -> Result<(), Error> {
+    match self {
+      Self::Event { webview, event } => webview.emit_to(
+        EventTarget::Webview {
+          label: webview.label().into(),
+        },
+        event,
+        Option::<()>::None,
+      )?,
+      Self::Channel { channel, codec } => {
+        channel.send(codec.encode_body(&()).map_err(Error::EncodeFailed)?)?
+      }
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::{Arc, Mutex};
+
+  use async_graphql::{futures_util::stream, Value};
+  use serde_json::json;
+  use tauri::{
+    test::{mock_app, MockRuntime},
+    WebviewUrl, WebviewWindowBuilder,
+  };
+
+  use super::*;
+
+  fn request(channel: bool) -> SubscriptionRequest {
+    let mut request = json!({ "query": "subscription { a }", "sub_id": "1", "id": 7 });
+    if channel {
+      request["channel"] = json!("__CHANNEL__:1");
+    }
+    serde_json::from_value(request).unwrap()
+  }
+
+  fn webview() -> Webview<MockRuntime> {
+    let app = mock_app();
+    let window = WebviewWindowBuilder::new(&app, "main", WebviewUrl::default())
+      .build()
+      .unwrap();
+    window.as_ref().clone()
+  }
+
+  /// A channel sink keeping the bodies sent on it.
+  fn channel_sink() -> (SubscriptionSink<MockRuntime>, Arc<Mutex<Vec<String>>>) {
+    let sent = Arc::new(Mutex::new(Vec::new()));
+    let bodies = sent.clone();
+    let channel = Channel::new(move |body| {
+      let InvokeResponseBody::Json(json) = body else {
+        panic!("expected a json body");
+      };
+      bodies.lock().unwrap().push(json);
+      Ok(())
+    });
+    let sink = SubscriptionSink::Channel {
+      channel,
+      codec: Codec::Json,
+    };
+    (sink, sent)
+  }
+
+  #[test]
+  fn the_delivery_picks_the_sink() {
+    let webview = webview();
+    let sink =
+      |delivery, channel| SubscriptionSink::new(delivery, None, &webview, &request(channel));
+    assert!(matches!(
+      sink(SubscriptionDelivery::Auto, true),
+      Ok(SubscriptionSink::Channel { .. })
+    ));
+    assert!(matches!(
+      sink(SubscriptionDelivery::Auto, false),
+      Ok(SubscriptionSink::Event { event, .. }) if event == "graphql://7"
+    ));
+    assert!(matches!(
+      sink(SubscriptionDelivery::Event, true),
+      Ok(SubscriptionSink::Event { .. })
+    ));
+    assert!(matches!(
+      sink(SubscriptionDelivery::Channel, false),
+      Err(Error::MissingChannel)
+    ));
+  }
+
+  #[tokio::test]
+  async fn results_are_forwarded_until_the_end() {
+    let (sink, sent) = channel_sink();
+    let items =
+      stream::iter([1, 2].map(|i| SubscriptionItem::Response(Response::new(Value::from(i)))));
+    let mut sizes = Vec::new();
+    sink
+      .forward(items.boxed(), &CancellationToken::new(), |_, len| {
+        sizes.push(len)
+      })
+      .await
+      .unwrap();
+    sink.end().unwrap();
+    let sent = sent.lock().unwrap();
+    assert_eq!(*sent, [r#"{"data":1}"#, r#"{"data":2}"#, "null"]);
+    assert_eq!(sizes, [10, 10]);
+  }
+
+  #[tokio::test]
+  async fn cancellation_stops_the_forwarding() {
+    let (sink, sent) = channel_sink();
+    let token = CancellationToken::new();
+    token.cancel();
+    sink
+      .forward(stream::pending().boxed(), &token, |_, _| {})
+      .await
+      .unwrap();
+    assert!(sent.lock().unwrap().is_empty());
+  }
 }
@@ -0,0 +1,213 @@
+use std::{collections::HashMap, future::Future, time::Duration};
+
+use async_graphql::{
+  futures_util::{stream::BoxStream, StreamExt},
+  parser::types::OperationType,
+  Request, Response, ServerError,
+};
+
+use crate::{
+  operation::{operation, root_fields},
+  trace, Error,
+};
+
+/// The timeouts of an operation type.
+#[derive(Default)]
+pub(crate) struct OperationTimeouts {
+  pub(crate) timeout: Option<Duration>,
+  /// Keyed by root field name.
+  pub(crate) fields: HashMap<String, Duration>,
+}
+
+/// How long the operations are allowed to run.
+#[derive(Default)]
+pub(crate) struct Timeouts {
+  /// Applies to the queries and mutations without a more specific timeout.
+  pub(crate) default: Option<Duration>,
+  pub(crate) query: OperationTimeouts,
+  pub(crate) mutation: OperationTimeouts,
+  pub(crate) subscription: OperationTimeouts,
+}
+
+impl Timeouts {
+  pub(crate) fn operation_mut(&mut self, ty: OperationType) -> &mut OperationTimeouts {
+    match ty {
+      OperationType::Query => &mut self.query,
+      OperationType::Mutation => &mut self.mutation,
+      OperationType::Subscription => &mut self.subscription,
+    }
+  }
+
+  /// The timeout of a request.
+  ///
+  /// Each root field is allowed its own timeout, or the one of its operation type.
+  /// The root fields of a query or subscription run concurrently, so the request
+  /// gets the longest of their timeouts, while the ones of a mutation run one after
+  /// the other, so it gets their sum.
+  /// Invalid requests get none, they fail before running anything.
+  pub(crate) fn of(&self, request: &mut Request) -> Option<Duration> {
+    let (document, operation_definition) = operation(request)?;
+    let (operation, default) = match operation_definition.ty {
+      OperationType::Query => (&self.query, self.default),
+      OperationType::Mutation => (&self.mutation, self.default),
+      // Subscriptions are meant to last, only their own timeouts apply.
+      OperationType::Subscription => (&self.subscription, None),
+    };
+    let default = operation.timeout.or(default);
+
+    let fields = root_fields(document, operation_definition);
+    if fields.is_empty() {
+      return default;
+    }
+    let serial = operation_definition.ty == OperationType::Mutation;
+    fields
+      .into_iter()
+      .map(|field| operation.fields.get(field).copied().or(default))
+      .try_fold(Duration::ZERO, |total, timeout| {
+        let timeout = timeout?;
+        Some(if serial {
+          total.saturating_add(timeout)
+        } else {
+          total.max(timeout)
+        })
+      })
+  }
+}
+
+/// The error of an operation that ran out of time.
+pub(crate) fn timed_out(timeout: Duration) -> ServerError {
+  Error::Timeout(timeout).into()
+}
+
+/// Run an execution, answering with a [`timed_out`] error past `timeout`.
+pub(crate) async fn execute(
+  timeout: Option<Duration>,
+  execution: impl Future<Output = Response>,
+) -> Response {
+  let Some(timeout) = timeout else {
+    return execution.await;
+  };
+  tokio::time::timeout(timeout, execution)
+    .await
+    .unwrap_or_else(|_| {
+      trace::timed_out(timeout);
+      Response::from_errors(vec![timed_out(timeout)])
+    })
+}
+
+/// End a stream past `timeout`, with the item made by `timed_out`.
+pub(crate) fn stream<T: Send + 'static>(
+  stream: BoxStream<'static, T>,
+  timeout: Option<Duration>,
+  timed_out: impl FnOnce(Duration) -> T + Send + 'static,
+) -> BoxStream<'static, T> {
+  let Some(timeout) = timeout else {
+    return stream;
+  };
+  let deadline = Box::pin(tokio::time::sleep(timeout));
+  async_graphql::futures_util::stream::unfold(
+    Some((stream, deadline, timed_out)),
+    move |state| async move {
+      let (mut stream, mut deadline, timed_out) = state?;
+      tokio::select! {
+        item = stream.next() => item.map(|item| (item, Some((stream, deadline, timed_out)))),
+        _ = &mut deadline => {
+          trace::timed_out(timeout);
+          Some((timed_out(timeout), None))
+        }
+      }
+    },
+  )
+  .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+  use async_graphql::{futures_util::stream, Value};
+
+  use super::*;
+
+  const SECOND: Duration = Duration::from_secs(1);
+
+  fn timeouts() -> Timeouts {
+    let mut timeouts = Timeouts {
+      default: Some(SECOND),
+      ..Default::default()
+    };
+    let query = timeouts.operation_mut(OperationType::Query);
+    query.fields.insert("slow".into(), 10 * SECOND);
+    let subscription = timeouts.operation_mut(OperationType::Subscription);
+    subscription.fields.insert("ticks".into(), 60 * SECOND);
+    timeouts.mutation.timeout = Some(5 * SECOND);
+    timeouts
+  }
+
+  fn of(query: &str) -> Option<Duration> {
+    timeouts().of(&mut Request::new(query))
+  }
+
+  #[test]
+  fn the_longest_root_field_timeout_applies() {
+    assert_eq!(of("{ fast }"), Some(SECOND));
+    assert_eq!(of("{ slow }"), Some(10 * SECOND));
+    assert_eq!(of("{ fast slow }"), Some(10 * SECOND));
+    assert_eq!(
+      of("{ ...F } fragment F on Query { slow }"),
+      Some(10 * SECOND)
+    );
+  }
+
+  #[test]
+  fn mutations_get_the_sum_of_their_timeouts() {
+    let mut timeouts = timeouts();
+    timeouts
+      .mutation
+      .fields
+      .insert("upload".into(), 30 * SECOND);
+    let of = |query: &str| timeouts.of(&mut Request::new(query));
+    assert_eq!(of("mutation { upload }"), Some(30 * SECOND));
+    // The root fields of a mutation run serially.
+    assert_eq!(of("mutation { send upload }"), Some(35 * SECOND));
+    assert_eq!(of("mutation { a: send b: send }"), Some(10 * SECOND));
+  }
+
+  #[test]
+  fn operation_types_have_their_own_timeouts() {
+    assert_eq!(of("mutation { send }"), Some(5 * SECOND));
+    assert_eq!(of("subscription { ticks }"), Some(60 * SECOND));
+    // The default only applies to queries and mutations.
+    assert_eq!(of("subscription { other }"), None);
+    assert_eq!(of("subscription { ticks other }"), None);
+    assert_eq!(of("{"), None);
+    assert_eq!(Timeouts::default().of(&mut Request::new("{ a }")), None);
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn executions_time_out() {
+    let slow = async {
+      tokio::time::sleep(2 * SECOND).await;
+      Response::new(Value::Null)
+    };
+    let response = execute(Some(SECOND), slow).await;
+    let code = response.errors[0].extensions.as_ref().unwrap().get("code");
+    assert_eq!(code, Some(&Value::from("TIMEOUT")));
+
+    let fast = async { Response::new(Value::Null) };
+    assert!(execute(Some(SECOND), fast).await.errors.is_empty());
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn streams_end_with_the_timeout() {
+    let ticks = stream::unfold(0, |i| async move {
+      tokio::time::sleep(Duration::from_millis(400)).await;
+      Some((Some(i), i + 1))
+    })
+    .boxed();
+    let items: Vec<_> = stream(ticks, Some(SECOND), |_| None).collect().await;
+    assert_eq!(items, [Some(0), Some(1), None]);
+
+    let short = stream::iter([Some(0)]).boxed();
+    let items: Vec<_> = stream(short, Some(SECOND), |_| None).collect().await;
+    assert_eq!(items, [Some(0)]);
+  }
+}
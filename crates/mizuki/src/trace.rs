@@ -0,0 +1,202 @@
+//! Diagnostics of the commands, emitted through [`tracing`] when the `tracing` feature is enabled.
+//!
+//! Without it, everything here compiles down to nothing.
+
+use std::future::Future;
+#[cfg(feature = "tracing")]
+use std::time::Instant;
+
+use async_graphql::Request;
+#[cfg(feature = "tracing")]
+use tracing::field::Empty;
+
+#[cfg(feature = "tracing")]
+use crate::operation::{operation, operation_name};
+
+/// A span, recording its `duration_ms` once dropped.
+pub(crate) struct Span {
+  #[cfg(feature = "tracing")]
+  inner: tracing::Span,
+  #[cfg(feature = "tracing")]
+  start: Instant,
+}
+
+impl Span {
+  #[cfg(feature = "tracing")]
+  fn new(inner: tracing::Span) -> Self {
+    Self {
+      inner,
+      start: Instant::now(),
+    }
+  }
+
+  /// The span of a `graphql` invocation.
+  pub(crate) fn graphql(plugin: &'static str, webview: &str) -> Self {
+    #[cfg(feature = "tracing")]
+    return Self::new(tracing::info_span!(
+      "graphql",
+      plugin,
+      webview,
+      duration_ms = Empty
+    ));
+    #[cfg(not(feature = "tracing"))]
+    {
+      let _ = (plugin, webview);
+      Self {}
+    }
+  }
+
+  /// The span of a `subscriptions` invocation, see [`record_subscription`].
+  pub(crate) fn subscription(plugin: &'static str, webview: &str) -> Self {
+    #[cfg(feature = "tracing")]
+    return Self::new(tracing::info_span!(
+      "subscription",
+      plugin,
+      webview,
+      id = Empty,
+      operation.name = Empty,
+      "operation.type" = Empty,
+      duration_ms = Empty
+    ));
+    #[cfg(not(feature = "tracing"))]
+    {
+      let _ = (plugin, webview);
+      Self {}
+    }
+  }
+
+  /// The span of a `transport` invocation, handling a single message of the protocol.
+  ///
+  /// The subscriptions it starts run in [`Self::subscription`] spans of their own.
+  pub(crate) fn transport(plugin: &'static str, webview: &str) -> Self {
+    #[cfg(feature = "tracing")]
+    return Self::new(tracing::info_span!(
+      "transport",
+      plugin,
+      webview,
+      duration_ms = Empty
+    ));
+    #[cfg(not(feature = "tracing"))]
+    {
+      let _ = (plugin, webview);
+      Self {}
+    }
+  }
+
+  /// The span of an operation of a `graphql` invocation, entered by the execution of `request`.
+  pub(crate) fn operation(request: &mut Request) -> Self {
+    #[cfg(feature = "tracing")]
+    {
+      let span = Self::new(tracing::info_span!(
+        "operation",
+        operation.name = Empty,
+        "operation.type" = Empty,
+        duration_ms = Empty
+      ));
+      record_operation(&span.inner, request);
+      span
+    }
+    #[cfg(not(feature = "tracing"))]
+    {
+      let _ = request;
+      Self {}
+    }
+  }
+
+  /// Run `f` in the span.
+  pub(crate) fn in_scope<T>(&self, f: impl FnOnce() -> T) -> T {
+    #[cfg(feature = "tracing")]
+    return self.inner.in_scope(f);
+    #[cfg(not(feature = "tracing"))]
+    f()
+  }
+
+  /// Run `future` in the span, which ends with it.
+  pub(crate) fn instrument<F: Future>(self, future: F) -> impl Future<Output = F::Output> {
+    #[cfg(feature = "tracing")]
+    {
+      let inner = self.inner.clone();
+      tracing::Instrument::instrument(
+        async move {
+          let _span = self;
+          future.await
+        },
+        inner,
+      )
+    }
+    #[cfg(not(feature = "tracing"))]
+    future
+  }
+}
+
+#[cfg(feature = "tracing")]
+fn record_operation(span: &tracing::Span, request: &mut Request) {
+  if let Some(name) = operation_name(request) {
+    span.record("operation.name", name);
+  }
+  if let Some((_, operation)) = operation(request) {
+    span.record("operation.type", operation.ty.to_string());
+  }
+}
+
+/// Record the id and the operation of the subscription of the current span.
+pub(crate) fn record_subscription(id: &str, request: &mut Request) {
+  #[cfg(feature = "tracing")]
+  {
+    let span = tracing::Span::current();
+    span.record("id", id);
+    record_operation(&span, request);
+  }
+  #[cfg(not(feature = "tracing"))]
+  let _ = (id, request);
+}
+
+#[cfg(feature = "tracing")]
+impl Drop for Span {
+  fn drop(&mut self) {
+    self
+      .inner
+      .record("duration_ms", self.start.elapsed().as_millis() as u64);
+  }
+}
+
+/// Why a subscription stopped before its end.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Cancellation {
+  /// The webview cancelled it, or it was cancelled from the [`crate::SubscriptionRegistry`].
+  Cancelled,
+  /// The webview left the page that started it.
+  PageLeft,
+}
+
+pub(crate) fn subscription_started() {
+  #[cfg(feature = "tracing")]
+  tracing::debug!("subscription started");
+}
+
+pub(crate) fn item_emitted() {
+  #[cfg(feature = "tracing")]
+  tracing::trace!("subscription item emitted");
+}
+
+pub(crate) fn subscription_cancelled(reason: Cancellation) {
+  #[cfg(feature = "tracing")]
+  tracing::debug!(?reason, "subscription cancelled");
+  #[cfg(not(feature = "tracing"))]
+  let _ = reason;
+}
+
+pub(crate) fn subscription_completed() {
+  #[cfg(feature = "tracing")]
+  tracing::debug!("subscription completed");
+}
+
+pub(crate) fn timed_out(timeout: std::time::Duration) {
+  #[cfg(feature = "tracing")]
+  tracing::debug!(
+    timeout_ms = timeout.as_millis() as u64,
+    "operation timed out"
+  );
+  #[cfg(not(feature = "tracing"))]
+  let _ = timeout;
+}
@@ -0,0 +1,615 @@
+use std::{
+  collections::HashMap,
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+  },
+};
+
+use async_graphql::{futures_util::StreamExt, ObjectType, Schema, SubscriptionType};
+use serde::Deserialize;
+use tauri::{
+  ipc::{Channel, JavaScriptChannelId},
+  Manager, Runtime, Webview,
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+  delivery::{self, DeliveryPolicies},
+  guard::Capabilities,
+  metrics::{json_len, Metrics},
+  multicast::Multicast,
+  permissions::FieldPermissions,
+  plugin::{OnConnectionInit, OnSubRequst},
+  preflight::{Preflight, Rejections},
+  protocol::{ClientMessage, CloseReason, ServerMessage},
+  redaction::Redaction,
+  registry::SubscriptionRegistry,
+  request_limits::RequestLimits,
+  timeout::{self, Timeouts},
+  trace::{self, Cancellation, Span},
+};
+
+/// Payload of the `transport` command.
+#[derive(Deserialize)]
+pub struct TransportRequest {
+  #[serde(flatten)]
+  pub message: ClientMessage,
+  /// The channel the server messages are sent on.
+  ///
+  /// Only read with the `connection_init` message.
+  #[serde(default)]
+  pub channel: Option<JavaScriptChannelId>,
+}
+
+struct Operation {
+  key: u64,
+  cancel_token: CancellationToken,
+}
+
+struct Connection {
+  window: String,
+  channel: Channel<ServerMessage>,
+  operations: HashMap<String, Operation>,
+}
+
+impl Drop for Connection {
+  fn drop(&mut self) {
+    for operation in self.operations.values() {
+      operation.cancel_token.cancel();
+    }
+  }
+}
+
+/// The open graphql-transport-ws connections, keyed by webview label.
+#[derive(Default)]
+pub(crate) struct Connections {
+  inner: Mutex<HashMap<String, Connection>>,
+  next_key: AtomicU64,
+}
+
+impl Connections {
+  /// Drop the connection of a webview, stopping all of its operations.
+  pub(crate) fn close(&self, webview: &str) {
+    self.inner.lock().unwrap().remove(webview);
+  }
+
+  /// Drop the connections of all the webviews of a window.
+  pub(crate) fn close_window(&self, window: &str) {
+    self
+      .inner
+      .lock()
+      .unwrap()
+      .retain(|_, connection| connection.window != window);
+  }
+
+  fn is_open(&self, webview: &str) -> bool {
+    self.inner.lock().unwrap().contains_key(webview)
+  }
+
+  /// The channel of the connection of a webview, for a new operation.
+  fn channel(&self, webview: &str, id: &str) -> Result<Channel<ServerMessage>, CloseReason> {
+    let inner = self.inner.lock().unwrap();
+    let connection = inner.get(webview).ok_or_else(CloseReason::unauthorized)?;
+    if connection.operations.contains_key(id) {
+      return Err(CloseReason::subscriber_already_exists(id));
+    }
+    Ok(connection.channel.clone())
+  }
+
+  /// Returns the key of the new operation.
+  ///
+  /// The connection may have been closed, or the id taken, since [`Self::channel`].
+  fn insert_operation(
+    &self,
+    webview: &str,
+    id: &str,
+    cancel_token: &CancellationToken,
+  ) -> Result<u64, CloseReason> {
+    let mut inner = self.inner.lock().unwrap();
+    let connection = inner
+      .get_mut(webview)
+      .ok_or_else(CloseReason::unauthorized)?;
+    if connection.operations.contains_key(id) {
+      return Err(CloseReason::subscriber_already_exists(id));
+    }
+    let key = self.next_key.fetch_add(1, Ordering::Relaxed);
+    connection.operations.insert(
+      id.into(),
+      Operation {
+        key,
+        cancel_token: cancel_token.clone(),
+      },
+    );
+    Ok(key)
+  }
+
+  /// Returns `false` if the operation was already removed, by the webview or with its connection.
+  fn remove_operation(&self, webview: &str, id: &str, key: u64) -> bool {
+    let mut inner = self.inner.lock().unwrap();
+    let Some(connection) = inner.get_mut(webview) else {
+      return false;
+    };
+    if connection.operations.get(id).map(|o| o.key) != Some(key) {
+      return false;
+    }
+    connection.operations.remove(id);
+    true
+  }
+}
+
+pub(crate) struct Transport<R, Q, M, S>
+where
+  R: Runtime,
+  Q: ObjectType + 'static,
+  M: ObjectType + 'static,
+  S: SubscriptionType + 'static,
+{
+  pub(crate) schema: Schema<Q, M, S>,
+  pub(crate) connections: Arc<Connections>,
+  pub(crate) on_connection_init: Arc<Box<OnConnectionInit<R>>>,
+  pub(crate) on_sub_request: Arc<Box<OnSubRequst>>,
+  pub(crate) preflight: Arc<Preflight>,
+  pub(crate) timeouts: Arc<Timeouts>,
+  pub(crate) delivery_policies: Arc<DeliveryPolicies>,
+  pub(crate) registry: SubscriptionRegistry,
+  pub(crate) metrics: Metrics,
+  pub(crate) plugin_name: &'static str,
+  pub(crate) multicast: Arc<Multicast<R>>,
+  pub(crate) redaction: Arc<Redaction>,
+  pub(crate) request_limits: RequestLimits,
+  pub(crate) permissions: FieldPermissions,
+  pub(crate) capabilities: Capabilities,
+}
+
+impl<R, Q, M, S> Transport<R, Q, M, S>
+where
+  R: Runtime,
+  Q: ObjectType + 'static,
+  M: ObjectType + 'static,
+  S: SubscriptionType + 'static,
+{
+  /// Handle a client message.
+  ///
+  /// The connection of the webview is dropped if the message violates the protocol.
+  pub(crate) fn handle(
+    &self,
+    webview: &Webview<R>,
+    req: TransportRequest,
+  ) -> Result<(), CloseReason> {
+    let channel = req
+      .channel
+      .map(|channel| channel.channel_on(webview.clone()));
+    self.handle_message(webview, req.message, channel)
+  }
+
+  fn handle_message(
+    &self,
+    webview: &Webview<R>,
+    message: ClientMessage,
+    channel: Option<Channel<ServerMessage>>,
+  ) -> Result<(), CloseReason> {
+    let res = self.dispatch(webview, message, channel);
+    if res.is_err() {
+      self.connections.close(webview.label());
+    }
+    res
+  }
+
+  /// The connections are never locked while calling out, to the app or to the webview.
+  fn dispatch(
+    &self,
+    webview: &Webview<R>,
+    message: ClientMessage,
+    channel: Option<Channel<ServerMessage>>,
+  ) -> Result<(), CloseReason> {
+    match message {
+      ClientMessage::ConnectionInit { payload } => {
+        let channel =
+          channel.ok_or_else(|| CloseReason::bad_request("Missing the connection channel"))?;
+        if self.connections.is_open(webview.label()) {
+          return Err(CloseReason::too_many_initialisation_requests());
+        }
+        let payload =
+          (self.on_connection_init)(webview, payload).map_err(CloseReason::forbidden)?;
+        let mut inner = self.connections.inner.lock().unwrap();
+        // Another `connection_init` may have been acknowledged in the meantime.
+        if inner.contains_key(webview.label()) {
+          return Err(CloseReason::too_many_initialisation_requests());
+        }
+        inner.insert(
+          webview.label().into(),
+          Connection {
+            window: webview.window().label().into(),
+            channel: channel.clone(),
+            operations: HashMap::new(),
+          },
+        );
+        drop(inner);
+        channel
+          .send(ServerMessage::ConnectionAck { payload })
+          .map_err(|e| CloseReason::bad_request(e.to_string()))?;
+      }
+      ClientMessage::Subscribe { id, payload } => {
+        let channel = self.connections.channel(webview.label(), &id)?;
+        if let Err(error) = self.request_limits.check(&payload) {
+          let _ = channel.send(ServerMessage::Error {
+            id,
+            payload: vec![error.into()],
+          });
+          return Ok(());
+        }
+        let mut payload = match self.preflight.check(
+          payload,
+          webview,
+          &self.permissions,
+          Rejections {
+            metrics: &self.metrics,
+            plugin: self.plugin_name,
+          },
+          false,
+        ) {
+          Ok(payload) => payload,
+          Err(error) => {
+            let _ = channel.send(ServerMessage::Error {
+              id,
+              payload: vec![error],
+            });
+            return Ok(());
+          }
+        };
+        // A child of the span of the invocation, outliving it.
+        let span = Span::subscription(self.plugin_name, webview.label());
+        span.in_scope(|| trace::record_subscription(&id, &mut payload));
+        let cancel_token = CancellationToken::new();
+        let key = self
+          .connections
+          .insert_operation(webview.label(), &id, &cancel_token)?;
+        let timeout = self.timeouts.of(&mut payload);
+        let delivery_policy = self.delivery_policies.of(&mut payload);
+        let mut subscription_metrics = self.metrics.subscription(self.plugin_name, &mut payload);
+        let registration = self.registry.register(
+          self.plugin_name,
+          webview.label(),
+          &id,
+          &mut payload,
+          &cancel_token,
+        );
+        let prepare = |request: async_graphql::Request, cancel_token: CancellationToken| {
+          (self.on_sub_request)(
+            request
+              .data(webview.app_handle().clone())
+              .data(webview.clone())
+              .data(webview.window())
+              .data(cancel_token)
+              .data(self.capabilities.clone()),
+          )
+        };
+        let stream = match self
+          .multicast
+          .key(&mut payload, webview, &self.capabilities)
+        {
+          Some(key) => self.multicast.subscribe(key, |upstream_token| {
+            self.redaction.stream(
+              self.schema.execute_stream(prepare(payload, upstream_token)),
+              Redaction::response,
+            )
+          }),
+          None => self.redaction.stream(
+            self
+              .schema
+              .execute_stream(prepare(payload, cancel_token.clone())),
+            Redaction::response,
+          ),
+        };
+        let stream = delivery::pace(stream, delivery_policy);
+        let stream = timeout::stream(stream, timeout, |timeout| {
+          async_graphql::Response::from_errors(vec![timeout::timed_out(timeout)])
+        });
+        let mut stream = registration.track(stream);
+        let connections = self.connections.clone();
+        let label = webview.label().to_string();
+        tauri::async_runtime::spawn(span.instrument(async move {
+          trace::subscription_started();
+          let mut cancelled = false;
+          loop {
+            tokio::select! {
+              _ = cancel_token.cancelled() => {
+                cancelled = true;
+                break;
+              },
+              res = stream.next() => {
+                let message = match res {
+                  Some(response) => {
+                    trace::item_emitted();
+                    let failed = response.is_err();
+                    let message = ServerMessage::Next { id: id.clone(), payload: response };
+                    let len = json_len(&message);
+                    registration.sent(len);
+                    subscription_metrics.emitted(len, failed);
+                    message
+                  },
+                  None => {
+                    let _ = channel.send(ServerMessage::Complete { id: id.clone() });
+                    break;
+                  }
+                };
+                if channel.send(message).is_err() {
+                  break;
+                }
+              }
+            }
+          }
+          if cancelled {
+            subscription_metrics.cancelled();
+            trace::subscription_cancelled(Cancellation::Cancelled);
+          } else {
+            trace::subscription_completed();
+          }
+          // Operations cancelled from Rust are still known to the webview.
+          if connections.remove_operation(&label, &id, key) && cancelled {
+            let _ = channel.send(ServerMessage::Complete { id });
+          }
+        }));
+      }
+      ClientMessage::Complete { id } => {
+        let mut inner = self.connections.inner.lock().unwrap();
+        if let Some(operation) = inner
+          .get_mut(webview.label())
+          .and_then(|connection| connection.operations.remove(&id))
+        {
+          operation.cancel_token.cancel();
+        }
+      }
+      ClientMessage::Ping { payload } => {
+        let channel = self
+          .connections
+          .inner
+          .lock()
+          .unwrap()
+          .get(webview.label())
+          .map(|connection| connection.channel.clone())
+          .ok_or_else(CloseReason::unauthorized)?;
+        let _ = channel.send(ServerMessage::Pong { payload });
+      }
+      ClientMessage::Pong { .. } => {}
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{sync::mpsc, time::Duration};
+
+  use async_graphql::{futures_util::Stream, EmptyMutation, Object, Subscription};
+  use serde_json::{json, Value as JsonValue};
+  use tauri::{
+    ipc::InvokeResponseBody,
+    test::{mock_app, MockRuntime},
+    App, WebviewUrl, WebviewWindowBuilder,
+  };
+
+  use super::*;
+  use crate::{apq::PersistedQueries, webview_limits::WebviewLimiter};
+
+  struct Query;
+
+  #[Object]
+  impl Query {
+    async fn ping(&self) -> bool {
+      true
+    }
+  }
+
+  struct Subscription;
+
+  #[Subscription]
+  impl Subscription {
+    async fn count(&self, to: i32) -> impl Stream<Item = i32> {
+      async_graphql::futures_util::stream::iter(0..to)
+    }
+  }
+
+  type TestTransport = Transport<MockRuntime, Query, EmptyMutation, Subscription>;
+
+  fn transport() -> TestTransport {
+    Transport {
+      schema: Schema::new(Query, EmptyMutation, Subscription),
+      connections: Default::default(),
+      on_connection_init: Arc::new(Box::new(|_, payload| Ok(payload))),
+      on_sub_request: Arc::new(Box::new(|request| request)),
+      preflight: Arc::new(Preflight {
+        persisted_queries: PersistedQueries::new(None),
+        trusted_documents: None,
+        access: Default::default(),
+        webview_limiter: WebviewLimiter::new(None),
+      }),
+      timeouts: Default::default(),
+      delivery_policies: Default::default(),
+      registry: Default::default(),
+      metrics: Default::default(),
+      plugin_name: "test",
+      multicast: Arc::new(Multicast::new(None)),
+      redaction: Default::default(),
+      request_limits: Default::default(),
+      permissions: Default::default(),
+      capabilities: Default::default(),
+    }
+  }
+
+  fn webview(app: &App<MockRuntime>) -> Webview<MockRuntime> {
+    let window = WebviewWindowBuilder::new(app, "main", WebviewUrl::default())
+      .build()
+      .unwrap();
+    window.as_ref().clone()
+  }
+
+  /// A channel forwarding the messages sent on it.
+  fn channel() -> (Channel<ServerMessage>, mpsc::Receiver<JsonValue>) {
+    let (sender, messages) = mpsc::channel();
+    let channel = Channel::new(move |body| {
+      let InvokeResponseBody::Json(json) = body else {
+        panic!("expected a json message");
+      };
+      let _ = sender.send(serde_json::from_str(&json).unwrap());
+      Ok(())
+    });
+    (channel, messages)
+  }
+
+  fn message(json: JsonValue) -> ClientMessage {
+    serde_json::from_value(json).unwrap()
+  }
+
+  /// Wait for the first `count` messages, sent by the spawned operations.
+  fn received(messages: &mpsc::Receiver<JsonValue>, count: usize) -> Vec<JsonValue> {
+    (0..count)
+      .map(|_| {
+        messages
+          .recv_timeout(Duration::from_secs(10))
+          .expect("a message")
+      })
+      .collect()
+  }
+
+  fn connect(
+    transport: &TestTransport,
+    webview: &Webview<MockRuntime>,
+  ) -> mpsc::Receiver<JsonValue> {
+    let (channel, messages) = channel();
+    let init = message(json!({ "type": "connection_init", "payload": { "token": 1 } }));
+    transport.dispatch(webview, init, Some(channel)).unwrap();
+    messages
+  }
+
+  #[test]
+  fn connection_init_is_acknowledged_once() {
+    let app = mock_app();
+    let webview = webview(&app);
+    let transport = transport();
+    let messages = connect(&transport, &webview);
+    assert_eq!(
+      received(&messages, 1),
+      [json!({ "type": "connection_ack", "payload": { "token": 1 } })]
+    );
+
+    let (channel, _) = channel();
+    let init = message(json!({ "type": "connection_init" }));
+    let res = transport.handle_message(&webview, init, Some(channel));
+    assert_eq!(res, Err(CloseReason::too_many_initialisation_requests()));
+    assert!(!transport.connections.is_open(webview.label()));
+  }
+
+  #[test]
+  fn connection_init_requires_a_channel() {
+    let app = mock_app();
+    let webview = webview(&app);
+    let init = message(json!({ "type": "connection_init" }));
+    let res = transport().dispatch(&webview, init, None);
+    assert_eq!(res.unwrap_err().code, 4400);
+  }
+
+  #[test]
+  fn rejected_connection_init_is_forbidden() {
+    let app = mock_app();
+    let webview = webview(&app);
+    let mut transport = transport();
+    transport.on_connection_init = Arc::new(Box::new(|_, _| Err("no token".into())));
+    let (channel, messages) = channel();
+    let init = message(json!({ "type": "connection_init" }));
+    let res = transport.dispatch(&webview, init, Some(channel));
+    assert_eq!(res, Err(CloseReason::forbidden("no token")));
+    assert!(messages.try_recv().is_err());
+  }
+
+  #[test]
+  fn subscribe_sends_next_then_complete() {
+    let app = mock_app();
+    let webview = webview(&app);
+    let transport = transport();
+    let messages = connect(&transport, &webview);
+    let subscribe = message(json!({
+      "type": "subscribe",
+      "id": "1",
+      "payload": { "query": "subscription { count(to: 2) }" }
+    }));
+    transport.dispatch(&webview, subscribe, None).unwrap();
+    assert_eq!(
+      received(&messages, 4)[1..],
+      [
+        json!({ "type": "next", "id": "1", "payload": { "data": { "count": 0 } } }),
+        json!({ "type": "next", "id": "1", "payload": { "data": { "count": 1 } } }),
+        json!({ "type": "complete", "id": "1" }),
+      ]
+    );
+  }
+
+  #[test]
+  fn execution_errors_are_sent_as_next() {
+    let app = mock_app();
+    let webview = webview(&app);
+    let transport = transport();
+    let messages = connect(&transport, &webview);
+    let subscribe = message(json!({
+      "type": "subscribe",
+      "id": "1",
+      "payload": { "query": "subscription { unknown }" }
+    }));
+    transport.dispatch(&webview, subscribe, None).unwrap();
+    let messages = received(&messages, 3);
+    assert_eq!(messages[1]["type"], "next");
+    assert!(messages[1]["payload"]["errors"].is_array());
+    assert_eq!(messages[2], json!({ "type": "complete", "id": "1" }));
+  }
+
+  #[test]
+  fn subscribe_requires_a_connection() {
+    let app = mock_app();
+    let webview = webview(&app);
+    let subscribe = message(json!({
+      "type": "subscribe",
+      "id": "1",
+      "payload": { "query": "{ ping }" }
+    }));
+    let res = transport().dispatch(&webview, subscribe, None);
+    assert_eq!(res, Err(CloseReason::unauthorized()));
+  }
+
+  #[test]
+  fn duplicate_ids_close_the_connection() {
+    let app = mock_app();
+    let webview = webview(&app);
+    let transport = transport();
+    let _messages = connect(&transport, &webview);
+    let subscribe = || {
+      message(json!({
+        "type": "subscribe",
+        "id": "1",
+        "payload": { "query": "subscription { count(to: 1000000) }" }
+      }))
+    };
+    transport.dispatch(&webview, subscribe(), None).unwrap();
+    let res = transport.handle_message(&webview, subscribe(), None);
+    assert_eq!(res, Err(CloseReason::subscriber_already_exists("1")));
+    assert!(!transport.connections.is_open(webview.label()));
+  }
+
+  #[test]
+  fn ping_is_answered_with_pong() {
+    let app = mock_app();
+    let webview = webview(&app);
+    let transport = transport();
+    let ping = || message(json!({ "type": "ping", "payload": { "at": 1 } }));
+    assert_eq!(
+      transport.dispatch(&webview, ping(), None),
+      Err(CloseReason::unauthorized())
+    );
+    let messages = connect(&transport, &webview);
+    transport.dispatch(&webview, ping(), None).unwrap();
+    assert_eq!(
+      received(&messages, 2)[1],
+      json!({ "type": "pong", "payload": { "at": 1 } })
+    );
+  }
+}
@@ -0,0 +1,151 @@
+//! Trusted documents.
+//!
+//! A plugin can refuse any operation its own frontend doesn't send.
+//! The build script of the plugin scans the frontend for its operations and
+//! writes their hashes in a manifest, with `mizuki_build::TrustedDocuments`:
+//!
+//! ```rust,ignore
+//! // build.rs
+//! fn main() {
+//!   mizuki_build::build();
+//!   mizuki_build::TrustedDocuments::new()
+//!     .path("../src")
+//!     .generate()
+//!     .expect("Failed to generate the trusted documents");
+//! }
+//! ```
+//!
+//! The plugin then embeds the manifest, and rejects the other operations with
+//! an `UNTRUSTED_DOCUMENT` error:
+//!
+//! ```rust,ignore
+//! let plugin = mizuki::Builder::new("todo-plugin", schema)
+//!   .trusted_documents(mizuki::include_trusted_documents!())
+//!   .build();
+//! ```
+//!
+//! The operations are compared once printed in a canonical form, so
+//! formatting and the `__typename` fields added by the GraphQL clients don't
+//! matter.
+//! Tools like GraphiQL send their own introspection queries, which aren't
+//! trusted either.
+
+use std::collections::HashSet;
+
+use async_graphql::{Request, ServerError};
+#[doc(hidden)]
+pub use mizuki_documents::manifest_file_name as __manifest_file_name;
+pub use mizuki_documents::{Manifest, MANIFEST_FILE_NAME};
+
+use crate::Error;
+
+/// The hashes of the operations a plugin accepts.
+#[derive(Debug, Clone, Default)]
+pub struct TrustedDocuments {
+  hashes: HashSet<String>,
+}
+
+impl TrustedDocuments {
+  /// Read a manifest written by `mizuki_build::TrustedDocuments::generate`.
+  pub fn from_manifest(json: &str) -> serde_json::Result<Self> {
+    Ok(serde_json::from_str::<Manifest>(json)?.into())
+  }
+
+  /// Whether the operation of this hash is trusted.
+  pub fn contains(&self, hash: &str) -> bool {
+    self.hashes.contains(hash)
+  }
+
+  pub(crate) fn check(&self, request: &mut Request) -> Result<(), ServerError> {
+    let operation_name = request.operation_name.clone();
+    let trusted = request.parsed_query().ok().is_some_and(|document| {
+      mizuki_documents::operation_hash(document, operation_name.as_deref())
+        .is_some_and(|hash| self.contains(&hash))
+    });
+    if trusted {
+      Ok(())
+    } else {
+      Err(Error::UntrustedDocument.into())
+    }
+  }
+}
+
+impl From<Manifest> for TrustedDocuments {
+  fn from(manifest: Manifest) -> Self {
+    Self {
+      hashes: manifest.operations.into_keys().collect(),
+    }
+  }
+}
+
+/// Embed the trusted documents manifest generated by the build script.
+///
+/// # Panics
+///
+/// If the manifest is malformed.
+#[macro_export]
+macro_rules! include_trusted_documents {
+  () => {
+    $crate::trusted_documents::TrustedDocuments::from_manifest(include_str!(concat!(
+      env!("OUT_DIR"),
+      "/",
+      $crate::trusted_documents::__manifest_file_name!()
+    )))
+    .expect("Failed to read the trusted documents manifest")
+  };
+}
+
+#[cfg(test)]
+mod tests {
+  use async_graphql::parser::parse_query;
+
+  use super::*;
+
+  fn trusted(queries: &[&str]) -> TrustedDocuments {
+    let operations = queries
+      .iter()
+      .map(|query| {
+        let document = parse_query(query).unwrap();
+        let hash = mizuki_documents::operation_hash(&document, None).unwrap();
+        (hash, None)
+      })
+      .collect();
+    Manifest { operations }.into()
+  }
+
+  #[test]
+  fn only_trusted_operations_pass() {
+    let documents = trusted(&["query Q { user { name } }"]);
+    // Formatting and the `__typename` added by the clients don't matter.
+    let reformatted = "query Q {\n  user {\n    name\n    __typename\n  }\n}";
+    assert!(documents.check(&mut Request::new(reformatted)).is_ok());
+    let error = documents
+      .check(&mut Request::new("query Q { user { email } }"))
+      .unwrap_err();
+    let code = error.extensions.unwrap().get("code").cloned();
+    assert_eq!(code, Some(async_graphql::Value::from("UNTRUSTED_DOCUMENT")));
+    assert!(documents.check(&mut Request::new("{")).is_err());
+  }
+
+  #[test]
+  fn operations_are_picked_by_name() {
+    let documents = trusted(&["query A { a }"]);
+    let query = "query A { a } query B { b }";
+    assert!(documents
+      .check(&mut Request::new(query).operation_name("A"))
+      .is_ok());
+    assert!(documents
+      .check(&mut Request::new(query).operation_name("B"))
+      .is_err());
+  }
+
+  #[test]
+  fn manifests_are_read_from_json() {
+    let documents =
+      TrustedDocuments::from_manifest(r#"{ "operations": { "abc": "Q", "def": null } }"#).unwrap();
+    assert!(documents.contains("abc"));
+    assert!(documents.contains("def"));
+    assert!(!documents.contains("ghi"));
+    assert!(TrustedDocuments::from_manifest("{}").is_err());
+  }
+}
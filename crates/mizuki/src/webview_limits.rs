@@ -0,0 +1,311 @@
+use std::{
+  collections::HashMap,
+  sync::Mutex,
+  time::{Duration, Instant},
+};
+
+use async_graphql::{
+  parser::types::{ExecutableDocument, Selection, SelectionSet},
+  Name, Request, ServerError,
+};
+use tauri::{Runtime, Url, Webview};
+
+use crate::{access::webview_url, operation::operation, Error};
+
+/// How long the complexity budget of a webview lasts before being renewed.
+const BUDGET_WINDOW: Duration = Duration::from_secs(60);
+
+/// The limits of the operations of a webview.
+///
+/// Unlike the limits of the schema, they depend on the webview sending the operation:
+///
+/// ```
+/// use mizuki::WebviewLimits;
+///
+/// let remote = WebviewLimits::new()
+///   .max_depth(5)
+///   .max_complexity(50)
+///   .complexity_per_minute(500);
+/// ```
+///
+/// They are checked before the operation is executed, on its document: each field
+/// counts for one in its complexity, the complexities set in the schema don't apply.
+/// Operations going over them are rejected with a `LIMIT_EXCEEDED` error.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct WebviewLimits {
+  max_depth: Option<usize>,
+  max_complexity: Option<usize>,
+  complexity_per_minute: Option<usize>,
+}
+
+impl WebviewLimits {
+  /// No limits.
+  pub fn new() -> Self {
+    Self::default()
+  }
+  /// Limit the depth of each operation.
+  #[must_use]
+  pub fn max_depth(mut self, depth: usize) -> Self {
+    self.max_depth = Some(depth);
+    self
+  }
+  /// Limit the complexity of each operation.
+  #[must_use]
+  pub fn max_complexity(mut self, complexity: usize) -> Self {
+    self.max_complexity = Some(complexity);
+    self
+  }
+  /// Limit the total complexity of the operations of the webview in a minute.
+  #[must_use]
+  pub fn complexity_per_minute(mut self, budget: usize) -> Self {
+    self.complexity_per_minute = Some(budget);
+    self
+  }
+}
+
+pub(crate) type WebviewLimitsPolicy = dyn Fn(&str, &Url) -> WebviewLimits + Send + Sync;
+
+/// The complexity a webview spent in the current window.
+struct Budget {
+  /// The label of the window of the webview.
+  window: String,
+  since: Instant,
+  spent: usize,
+}
+
+/// Checks the operations against the limits of their webview, and keeps track of the budgets.
+pub(crate) struct WebviewLimiter {
+  policy: Option<Box<WebviewLimitsPolicy>>,
+  budgets: Mutex<HashMap<String, Budget>>,
+}
+
+impl WebviewLimiter {
+  pub(crate) fn new(policy: Option<Box<WebviewLimitsPolicy>>) -> Self {
+    Self {
+      policy,
+      budgets: Default::default(),
+    }
+  }
+
+  /// Check that `request` is within the limits of `webview`, spending its budget.
+  ///
+  /// Invalid requests pass, they fail before running anything.
+  pub(crate) fn check<R: Runtime>(
+    &self,
+    request: &mut Request,
+    webview: &Webview<R>,
+  ) -> Result<(), ServerError> {
+    let Some(policy) = &self.policy else {
+      return Ok(());
+    };
+    let limits = policy(webview.label(), &webview_url(webview));
+    if limits == WebviewLimits::default() {
+      return Ok(());
+    }
+    let Some((document, operation)) = operation(request) else {
+      return Ok(());
+    };
+    let (depth, complexity) = Measure::new(document).selection_set(&operation.selection_set.node);
+    let exceeded = |limit, max| Err(Error::LimitExceeded { limit, max }.into());
+    if let Some(max) = limits.max_depth.filter(|&max| depth > max) {
+      return exceeded("depth", max);
+    }
+    if let Some(max) = limits.max_complexity.filter(|&max| complexity > max) {
+      return exceeded("complexity", max);
+    }
+    if let Some(budget) = limits.complexity_per_minute {
+      if !self.spend(webview, complexity, budget) {
+        return exceeded("complexityPerMinute", budget);
+      }
+    }
+    Ok(())
+  }
+
+  /// Spend `complexity` from the budget of `webview`, if it has enough left.
+  fn spend<R: Runtime>(&self, webview: &Webview<R>, complexity: usize, budget: usize) -> bool {
+    let mut budgets = self.budgets.lock().unwrap();
+    let now = Instant::now();
+    let current = budgets
+      .entry(webview.label().into())
+      .or_insert_with(|| Budget {
+        window: webview.window().label().into(),
+        since: now,
+        spent: 0,
+      });
+    if now.duration_since(current.since) >= BUDGET_WINDOW {
+      current.since = now;
+      current.spent = 0;
+    }
+    if current.spent.saturating_add(complexity) > budget {
+      return false;
+    }
+    current.spent += complexity;
+    true
+  }
+
+  /// Forget the budgets of the webviews of a window.
+  ///
+  /// The budget of a webview outlives its pages, a reload must not renew it.
+  pub(crate) fn close_window(&self, window: &str) {
+    self
+      .budgets
+      .lock()
+      .unwrap()
+      .retain(|_, budget| budget.window != window);
+  }
+}
+
+/// Measures the depth and complexity of the selection sets of a document.
+///
+/// Each fragment is measured once, so that spreading them over and over doesn't
+/// cost more to measure than to parse.
+struct Measure<'a> {
+  document: &'a ExecutableDocument,
+  /// `None` while the fragment is being measured.
+  fragments: HashMap<&'a Name, Option<(usize, usize)>>,
+}
+
+impl<'a> Measure<'a> {
+  fn new(document: &'a ExecutableDocument) -> Self {
+    Self {
+      document,
+      fragments: HashMap::new(),
+    }
+  }
+
+  fn selection_set(&mut self, selection_set: &'a SelectionSet) -> (usize, usize) {
+    let mut depth = 0;
+    let mut complexity = 0usize;
+    for selection in &selection_set.items {
+      let (d, c) = match &selection.node {
+        Selection::Field(field) => {
+          let (d, c) = self.selection_set(&field.node.selection_set.node);
+          (d + 1, c.saturating_add(1))
+        }
+        Selection::InlineFragment(fragment) => {
+          self.selection_set(&fragment.node.selection_set.node)
+        }
+        Selection::FragmentSpread(spread) => self.fragment(&spread.node.fragment_name.node),
+      };
+      depth = depth.max(d);
+      complexity = complexity.saturating_add(c);
+    }
+    (depth, complexity)
+  }
+
+  /// Unknown and cyclic fragments measure nothing, they fail validation.
+  fn fragment(&mut self, name: &'a Name) -> (usize, usize) {
+    if let Some(measured) = self.fragments.get(name) {
+      return measured.unwrap_or_default();
+    }
+    let Some(fragment) = self.document.fragments.get(name) else {
+      return (0, 0);
+    };
+    self.fragments.insert(name, None);
+    let measured = self.selection_set(&fragment.node.selection_set.node);
+    self.fragments.insert(name, Some(measured));
+    measured
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use tauri::{
+    test::{mock_app, MockRuntime},
+    App, WebviewUrl, WebviewWindowBuilder,
+  };
+
+  use super::*;
+
+  fn measure(query: &str) -> (usize, usize) {
+    let mut request = Request::new(query);
+    let (document, operation) = operation(&mut request).unwrap();
+    Measure::new(document).selection_set(&operation.selection_set.node)
+  }
+
+  #[test]
+  fn fields_are_measured() {
+    assert_eq!(measure("{ a }"), (1, 1));
+    assert_eq!(measure("{ a { b c { d } } e }"), (3, 5));
+    assert_eq!(measure("{ a { ... on T { b { c } } } }"), (3, 3));
+  }
+
+  #[test]
+  fn fragments_are_measured_where_spread() {
+    let query = "{ a { ...F } b { ...F } } fragment F on T { c { d } }";
+    assert_eq!(measure(query), (3, 6));
+    // Unknown and cyclic fragments are left to the validation.
+    assert_eq!(measure("{ a { ...Unknown } }"), (1, 1));
+    assert_eq!(
+      measure("{ a { ...F } } fragment F on T { b { ...G } } fragment G on T { ...F }"),
+      (2, 2)
+    );
+  }
+
+  #[test]
+  fn repeated_spreads_are_measured_once() {
+    let mut query = String::from("{ ...F0 }");
+    for i in 0..40 {
+      query.push_str(&format!(
+        " fragment F{} on T {{ ...F{} ...F{} }}",
+        i,
+        i + 1,
+        i + 1
+      ));
+    }
+    query.push_str(" fragment F40 on T { a }");
+    assert_eq!(measure(&query), (1, 1 << 40));
+  }
+
+  fn webview(app: &App<MockRuntime>, label: &str) -> Webview<MockRuntime> {
+    let window = WebviewWindowBuilder::new(app, label, WebviewUrl::default())
+      .build()
+      .unwrap();
+    window.as_ref().clone()
+  }
+
+  fn limited(limits: WebviewLimits) -> WebviewLimiter {
+    WebviewLimiter::new(Some(Box::new(move |label, _| match label {
+      "main" => WebviewLimits::new(),
+      _ => limits,
+    })))
+  }
+
+  fn limit(error: ServerError) -> Option<async_graphql::Value> {
+    error.extensions.unwrap().get("limit").cloned()
+  }
+
+  #[test]
+  fn limits_depend_on_the_webview() {
+    let app = mock_app();
+    let (main, remote) = (webview(&app, "main"), webview(&app, "remote"));
+    let limiter = limited(WebviewLimits::new().max_depth(2).max_complexity(3));
+    let check = |query: &str, webview| limiter.check(&mut Request::new(query), webview);
+    assert!(check("{ a { b { c } } }", &main).is_ok());
+    assert!(check("{ a { b } }", &remote).is_ok());
+    assert_eq!(
+      limit(check("{ a { b { c } } }", &remote).unwrap_err()),
+      Some("depth".into())
+    );
+    assert_eq!(
+      limit(check("{ a b c d }", &remote).unwrap_err()),
+      Some("complexity".into())
+    );
+  }
+
+  #[test]
+  fn budgets_are_spent_and_forgotten_with_the_window() {
+    let app = mock_app();
+    let remote = webview(&app, "remote");
+    let limiter = limited(WebviewLimits::new().complexity_per_minute(5));
+    let check = || limiter.check(&mut Request::new("{ a b }"), &remote);
+    assert!(check().is_ok());
+    assert!(check().is_ok());
+    assert_eq!(
+      limit(check().unwrap_err()),
+      Some("complexityPerMinute".into())
+    );
+    limiter.close_window("remote");
+    assert!(limiter.budgets.lock().unwrap().is_empty());
+  }
+}
@@ -1,19 +1,43 @@
 import {ApolloLink, Observable} from '@apollo/client/core'
-import {from as fromPromise} from 'rxjs'
 import {GraphQLError, print} from 'graphql'
-import {invoke} from '@tauri-apps/api/core'
+import {Channel, invoke} from '@tauri-apps/api/core'
 import {getCurrentWebview} from '@tauri-apps/api/webview'
-import {Event} from '@tauri-apps/api/event'
-import {getMainDefinition} from '@apollo/client/utilities'
+import {getMainDefinition, hasDirectives} from '@apollo/client/utilities'
 
-type Response = [body: string, isOk: boolean]
+type Response = [body: string, isOk: boolean] | ApolloLink.Result
+
+/**
+ * The GraphQL error of a failed invoke.
+ *
+ * The plugin rejects with a structured error, whose `code` and details end up
+ * in the `extensions`.
+ */
+function invokeError(err: unknown): GraphQLError {
+  if (typeof err === 'object' && err !== null && 'code' in err) {
+    const {message, ...extensions} = err as {message: string; code: string}
+    return new GraphQLError(message, {extensions})
+  }
+  return new GraphQLError(String(err))
+}
+
+function toArgs(operation: ApolloLink.Operation) {
+  // the persisted query link leaves the query out until the plugin asks for it
+  const includeQuery = operation.getContext().http?.includeQuery !== false
+  return {
+    query: includeQuery ? print(operation.query) : undefined,
+    variables: operation.variables || undefined,
+    extensions: operation.extensions
+  }
+}
 
 export class InvokeLink extends ApolloLink {
   private pluginName: string
+  private cancelEventLabel: string
 
-  constructor(pluginName: string) {
+  constructor(pluginName: string, cancelEventLabel: string = 'sub_end') {
     super()
     this.pluginName = pluginName
+    this.cancelEventLabel = cancelEventLabel
   }
 
   public request(
@@ -21,26 +45,49 @@ export class InvokeLink extends ApolloLink {
     forward: ApolloLink.ForwardFunction
   ): Observable<ApolloLink.Result> {
     const command = `plugin:${this.pluginName}|graphql`
-    const args = {
-      query: print(operation.query),
-      variables: operation.variables || undefined,
-      extensions: operation.extensions
-    }
-    return fromPromise(
-      invoke<Response>(command, args)
+    const args = toArgs(operation)
+    return new Observable(subscriber => {
+      const requestId = `${Math.floor(Math.random() * 10000000)}`
+      let ended = false
+
+      invoke<Response>(command, args, {
+        headers: {
+          'Mizuki-Accept': 'application/json',
+          'Mizuki-Request-Id': requestId
+        }
+      })
         .then(response => {
           console.debug(response)
-          const [body] = response!
-          const payload: ApolloLink.Result = JSON.parse(body)
+          // plugins older than the `Mizuki-Accept` header answer with a tuple
+          const payload: ApolloLink.Result = Array.isArray(response)
+            ? JSON.parse(response[0])
+            : response
           return payload
         })
         .catch(err => {
           return {
-            errors: [new GraphQLError(String(err))],
+            errors: [invokeError(err)],
             context: operation.getContext()
           }
         })
-    )
+        .then(result => {
+          ended = true
+          subscriber.next(result)
+          subscriber.complete()
+        })
+
+      return () => {
+        // let the plugin stop the request if it is still running
+        if (!ended) {
+          const webview = getCurrentWebview()
+          webview.emitTo(
+            {kind: 'Webview', label: webview.label},
+            this.cancelEventLabel,
+            requestId
+          )
+        }
+      }
+    })
   }
 }
 
@@ -58,16 +105,11 @@ export class SubscriptionsLink extends ApolloLink {
     operation: ApolloLink.Operation,
     forward: ApolloLink.ForwardFunction
   ): Observable<ApolloLink.Result> {
-    const args = {
-      query: print(operation.query),
-      variables: operation.variables || undefined,
-      extensions: operation.extensions
-    }
+    const args = toArgs(operation)
     const appWebview = getCurrentWebview()
     const command = `plugin:${this.pluginName}|subscriptions`
 
     return new Observable(subscriber => {
-      const id = Math.floor(Math.random() * 10000000)
       const subId = `${Math.floor(Math.random() * 10000000)}`
       let unlistens: (() => void)[] = [
         () => {
@@ -86,26 +128,19 @@ export class SubscriptionsLink extends ApolloLink {
       window.addEventListener('beforeunload', unlisten)
       unlistens.push(() => window.removeEventListener('beforeunload', unlisten))
 
-      appWebview
-        .listen(`graphql://${id}`, (event: Event<string | null>) => {
-          if (event.payload === null) return subscriber.complete()
-          const res: ApolloLink.Result = JSON.parse(event.payload)
-          // console.debug(res)
-          subscriber.next(res)
-        })
-        .then(_unlisten => unlistens.push(_unlisten))
-        .then(() =>
-          invoke(command, {
-            ...args,
-            id,
-            sub_id: subId
-          }).catch(e => {
-            throw new Error(`Tauri Invoke Error ${String(e)}`)
-          })
-        )
-        .catch(err => {
-          subscriber.error(err)
-        })
+      const channel = new Channel<ApolloLink.Result | null>()
+      channel.onmessage = res => {
+        if (res === null) return subscriber.complete()
+        subscriber.next(res)
+      }
+
+      invoke(command, {
+        ...args,
+        sub_id: subId,
+        channel
+      }).catch(e => {
+        subscriber.error(invokeError(e))
+      })
       return unlisten
     })
   }
@@ -121,12 +156,14 @@ export class MizukiLink extends ApolloLink {
         const definition = getMainDefinition(query)
 
         return (
-          definition.kind === 'OperationDefinition' &&
-          definition.operation === 'subscription'
+          (definition.kind === 'OperationDefinition' &&
+            definition.operation === 'subscription') ||
+          // incremental results are streamed like subscription ones
+          hasDirectives(['defer'], query)
         )
       },
       new SubscriptionsLink(pluginName, subEndEventLabel),
-      new InvokeLink(pluginName)
+      new InvokeLink(pluginName, subEndEventLabel)
     )
   }
 
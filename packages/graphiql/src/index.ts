@@ -1,23 +1,40 @@
 import {Fetcher, Observable, Unsubscribable} from '@graphiql/toolkit'
 import {ExecutionResult, GraphQLError, parse} from 'graphql'
-import {invoke} from '@tauri-apps/api/core'
-import {Event} from '@tauri-apps/api/event'
+import {Channel, invoke} from '@tauri-apps/api/core'
 import {getCurrentWebview} from '@tauri-apps/api/webview'
 
-type Response = [body: string, isOk: boolean]
+type Response = [body: string, isOk: boolean] | ExecutionResult
+
+/**
+ * The GraphQL error of a failed invoke.
+ *
+ * The plugin rejects with a structured error, whose `code` and details end up
+ * in the `extensions`.
+ */
+function invokeError(err: unknown): GraphQLError {
+  if (typeof err === 'object' && err !== null && 'code' in err) {
+    const {message, ...extensions} = err as {message: string; code: string}
+    return new GraphQLError(message, {extensions})
+  }
+  return new GraphQLError(String(err))
+}
 
 export function getInvokeFetcher(pluginName: string) {
   const command = `plugin:${pluginName}|graphql`
   const fetcher: Fetcher = async function (params) {
-    const r = await invoke<Response>(command, params)
+    const r = await invoke<Response>(command, params, {
+      headers: {'Mizuki-Accept': 'application/json'}
+    })
       .then(response => {
-        const [body] = response!
-        const payload: ExecutionResult = JSON.parse(body)
+        // plugins older than the `Mizuki-Accept` header answer with a tuple
+        const payload: ExecutionResult = Array.isArray(response)
+          ? JSON.parse(response[0])
+          : response
         return payload
       })
       .catch(err => {
         return {
-          errors: [new GraphQLError(String(err))]
+          errors: [invokeError(err)]
         } satisfies ExecutionResult
       })
     return r
@@ -114,7 +131,6 @@ export function getSubscriptionFetcher(
   const command = `plugin:${pluginName}|subscriptions`
   const fetcher: Fetcher = async function (params) {
     // console.log('fetching')
-    const id = Math.floor(Math.random() * 10000000)
     const subId = `${Math.floor(Math.random() * 10000000)}`
     let unlistens: (() => void)[] = [
       () => {
@@ -142,26 +158,18 @@ export function getSubscriptionFetcher(
       complete: () => void
     ) {
       // console.log('sub')
-      appWebview
-        .listen(`graphql://${id}`, (event: Event<string | null>) => {
-          if (event.payload === null) return complete()
-          const res: ExecutionResult = JSON.parse(event.payload)
-          // console.debug(res)
-          next(res)
-        })
-        .then(_unlisten => unlistens.push(_unlisten))
-        .then(() =>
-          invoke(command, {
-            ...params,
-            id,
-            sub_id: subId
-          }).catch(e => {
-            throw new Error(`Tauri Invoke Error ${String(e)}`)
-          })
-        )
-        .catch(err => {
-          error(err)
-        })
+      const channel = new Channel<ExecutionResult | null>()
+      channel.onmessage = res => {
+        if (res === null) return complete()
+        next(res)
+      }
+      invoke(command, {
+        ...params,
+        sub_id: subId,
+        channel
+      }).catch(e => {
+        error(invokeError(e))
+      })
     }
     // console.log('returned subObs')
     return new SubObs(sub, unlisten)
@@ -1,5 +1,4 @@
-import {invoke} from '@tauri-apps/api/core'
-import {Event} from '@tauri-apps/api/event'
+import {Channel, invoke} from '@tauri-apps/api/core'
 import {getCurrentWebview} from '@tauri-apps/api/webview'
 import {
   Exchange,
@@ -10,7 +9,7 @@ import {
   OperationResult,
   subscriptionExchange as subEx
 } from '@urql/core'
-import {print} from 'graphql'
+import {DocumentNode, GraphQLError, print, visit, BREAK} from 'graphql'
 import {
   filter,
   make,
@@ -38,15 +37,23 @@ import {
  * })
  * ```
  * @param name Your plugin name
+ * @param [cancelEventLabel='sub_end'] the event cancelling a request on teardown
  */
-export const invokeExchange: (name: string) => Exchange =
-  name =>
+export const invokeExchange: (
+  name: string,
+  cancelEventLabel?: string
+) => Exchange =
+  (name, cancelEventLabel = 'sub_end') =>
   ({forward}) => {
     return ops$ => {
       const sharedOps$ = share(ops$)
       const fetchResults$ = pipe(
         sharedOps$,
-        filter(op => op.kind === 'query' || op.kind === 'mutation'),
+        filter(
+          op =>
+            (op.kind === 'query' || op.kind === 'mutation') &&
+            !isIncremental(op.query)
+        ),
         mergeMap(operation => {
           const {key} = operation
           const teardown$ = pipe(
@@ -55,7 +62,7 @@ export const invokeExchange: (name: string) => Exchange =
           )
 
           const args = {
-            query: print(operation.query),
+            query: includeQuery(operation) ? print(operation.query) : undefined,
             variables: operation.variables || undefined,
             extensions: operation.extensions
           }
@@ -73,7 +80,7 @@ export const invokeExchange: (name: string) => Exchange =
           })
 
           return pipe(
-            makeInvokeSource(operation, command, args),
+            makeInvokeSource(operation, command, args, cancelEventLabel),
             takeUntil(teardown$),
             onPush(result => {
               const error = !result.data ? result.error : undefined
@@ -95,7 +102,11 @@ export const invokeExchange: (name: string) => Exchange =
 
       const forward$ = pipe(
         sharedOps$,
-        filter(op => op.kind !== 'query' && op.kind !== 'mutation'),
+        filter(
+          op =>
+            (op.kind !== 'query' && op.kind !== 'mutation') ||
+            isIncremental(op.query)
+        ),
         forward
       )
 
@@ -103,25 +114,77 @@ export const invokeExchange: (name: string) => Exchange =
     }
   }
 
-type Response = [body: string, isOk: boolean]
+type Response = [body: string, isOk: boolean] | ExecutionResult
+
+/**
+ * Whether the query text should be sent, which the `persistedExchange`
+ * only wants after the plugin missed its hash.
+ */
+function includeQuery(operation: Operation): boolean {
+  const persistedQuery = operation.extensions?.persistedQuery
+  return !persistedQuery || !!persistedQuery.miss
+}
+
+/**
+ * Whether a query uses `@defer`,
+ * in which case its results are streamed by the `subscriptionExchange`.
+ */
+function isIncremental(query: DocumentNode): boolean {
+  let incremental = false
+  visit(query, {
+    Directive(node) {
+      if (node.name.value === 'defer') {
+        incremental = true
+        return BREAK
+      }
+    }
+  })
+  return incremental
+}
+
+/**
+ * The GraphQL error of a failed invoke.
+ *
+ * The plugin rejects with a structured error, whose `code` and details end up
+ * in the `extensions`.
+ */
+function invokeError(err: unknown): GraphQLError {
+  if (typeof err === 'object' && err !== null && 'code' in err) {
+    const {message, ...extensions} = err as {message: string; code: string}
+    return new GraphQLError(message, {extensions})
+  }
+  return new GraphQLError(String(err))
+}
 
 function makeInvokeSource(
   operation: Operation,
   command: string,
-  invokeArgs: Record<string, any>
+  invokeArgs: Record<string, any>,
+  cancelEventLabel: string
 ): Source<OperationResult> {
   return make(({next, complete}) => {
+    const requestId = `${Math.floor(Math.random() * 10000000)}`
+    let started = false
     let ended = false
 
     Promise.resolve()
       .then(() => {
         if (ended) return
 
-        return invoke<Response>(command, invokeArgs)
+        started = true
+        return invoke<Response>(command, invokeArgs, {
+          headers: {
+            'Mizuki-Accept': 'application/json',
+            'Mizuki-Request-Id': requestId
+          }
+        })
       })
       .then(response => {
-        const [body] = response!
-        const payload: ExecutionResult = JSON.parse(body)
+        ended = true
+        // plugins older than the `Mizuki-Accept` header answer with a tuple
+        const payload: ExecutionResult = Array.isArray(response)
+          ? JSON.parse(response[0])
+          : response!
 
         console.debug(response)
 
@@ -129,13 +192,23 @@ function makeInvokeSource(
       })
       .then(complete)
       .catch(err => {
-        const result = makeErrorResult(operation, err, null)
+        ended = true
+        const result = makeErrorResult(operation, invokeError(err), null)
 
         next(result)
         complete()
       })
 
     return () => {
+      // let the plugin stop the request if it is still running
+      if (started && !ended) {
+        const webview = getCurrentWebview()
+        webview.emitTo(
+          {kind: 'Webview', label: webview.label},
+          cancelEventLabel,
+          requestId
+        )
+      }
       ended = true
     }
   })
@@ -172,9 +245,11 @@ export function subscriptionExchange(
 ) {
   const appWebview = getCurrentWebview()
   return subEx({
+    isSubscriptionOperation: operation =>
+      operation.kind === 'subscription' ||
+      (operation.kind === 'query' && isIncremental(operation.query)),
     forwardSubscription: operation => ({
       subscribe: sink => {
-        const id = Math.floor(Math.random() * 10000000)
         const subId = `${Math.floor(Math.random() * 10000000)}`
         let unlistens: (() => void)[] = [
           () => {
@@ -197,27 +272,22 @@ export function subscriptionExchange(
         unlistens.push(() =>
           window.removeEventListener('beforeunload', unlisten)
         )
+        const channel = new Channel<ExecutionResult | null>()
+        channel.onmessage = payload => {
+          if (payload === null) return sink.complete()
+          sink.next(payload)
+        }
         Promise.resolve()
-          .then(async () =>
-            appWebview.listen(
-              `graphql://${id}`,
-              (event: Event<string | null>) => {
-                if (event.payload === null) return sink.complete()
-                sink.next(JSON.parse(event.payload))
-              }
-            )
-          )
-          .then(_unlisten => unlistens.push(_unlisten))
           .then(() =>
             invoke(`plugin:${name}|subscriptions`, {
               ...operation,
-              id,
-              sub_id: subId
+              sub_id: subId,
+              channel
             })
           )
           // .then(() => sink.complete())
           .catch(err => {
-            sink.error(err)
+            sink.error(invokeError(err))
           })
         return {
           unsubscribe: unlisten
@@ -245,9 +315,12 @@ export function subscriptionExchange(
  * ```
  *
  * @param name Your plugin name
- * @param subEndEventLabel the subscription end event label
+ * @param subEndEventLabel the label of the event ending a subscription or cancelling a request
  * @returns
  */
 export function getExchanges(name: string, subEndEventLabel?: string) {
-  return [invokeExchange(name), subscriptionExchange(name, subEndEventLabel)]
+  return [
+    invokeExchange(name, subEndEventLabel),
+    subscriptionExchange(name, subEndEventLabel)
+  ]
 }